@@ -5,3 +5,39 @@ create table if not exists brc20_accounts (
     primary key(address)
 );
 
+create table if not exists brc20_trades (
+    id serial,
+    action varchar(16) not null,
+    token varchar(32) not null,
+    address varchar(64) not null,
+    amount bigint not null,
+    price bigint not null,
+    txid varchar(128) not null,
+    create_time bigint not null,
+    -- buried under MIN_CONFIRMATIONS blocks; pending until then
+    confirmed boolean not null default false,
+    primary key(id)
+);
+
+alter table brc20_trades add column if not exists confirmed boolean not null default false;
+
+create table if not exists brc20_cursors (
+    name varchar(64) not null,
+    value bigint not null,
+    primary key(name)
+);
+
+create table if not exists brc20_actions (
+    key varchar(64) not null,
+    txid varchar(128) not null,
+    create_time bigint not null,
+    primary key(key)
+);
+
+create table if not exists brc20_queue (
+    id bigserial,
+    token varchar(32) not null,
+    action text not null,
+    create_time bigint not null,
+    primary key(id)
+);
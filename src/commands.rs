@@ -0,0 +1,811 @@
+use crate::config::Config;
+use crate::db::StorageBackend;
+use crate::types::{FraAccount, ListItem, Rpc, Trade, TradeAction};
+use crate::utils::{gen_accounts, now_secs};
+use crate::{CURSOR_BOUGHT, CURSOR_MINTED};
+use anyhow::{Error, Result};
+use chrono::{DateTime, NaiveDate};
+use clap::ValueEnum;
+use futures::StreamExt;
+use globutils::wallet::public_key_from_bech32;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::time::Instant;
+
+const BOOK_PAGE_SIZE: i32 = 50;
+
+/// How subcommands report: human lines, or one JSON document on stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    /// A progress line; dropped in JSON mode so stdout stays parseable.
+    pub fn line(&self, line: impl Display) {
+        if *self == Output::Text {
+            println!("{}", line);
+        }
+    }
+
+    /// The command's result: `text` for humans, `value` as JSON otherwise.
+    pub fn result<T: Serialize>(&self, value: &T, text: impl Display) -> Result<()> {
+        match self {
+            Output::Text => println!("{}", text),
+            Output::Json => println!("{}", serde_json::to_string(value)?),
+        }
+        Ok(())
+    }
+}
+
+/// Sends `amount` FRA from `treasury` to every account whose balance is below
+/// `threshold`, in a single transaction.
+pub async fn fund(
+    rpc: &Rpc,
+    treasury: &FraAccount,
+    roles: &[(&str, &Vec<FraAccount>)],
+    amount: u64,
+    threshold: u64,
+    output: Output,
+) -> Result<()> {
+    let mut outputs = vec![];
+    let mut recipients = vec![];
+    let mut failures = 0;
+    for (role, accounts) in roles {
+        for account in accounts.iter() {
+            let balance = match rpc.get_balance(account).await {
+                Ok(balance) => balance,
+                Err(e) => {
+                    output.line(format_args!(
+                        "[fund] {} {}: balance query failed: {}",
+                        role, account.address, e
+                    ));
+                    failures += 1;
+                    continue;
+                }
+            };
+            if balance >= threshold {
+                continue;
+            }
+            match public_key_from_bech32(&account.address) {
+                Ok(pk) => {
+                    outputs.push((pk, amount));
+                    recipients.push((role, account, balance));
+                }
+                Err(e) => {
+                    output.line(format_args!(
+                        "[fund] {} {}: bad address: {:?}",
+                        role, account.address, e
+                    ));
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    if outputs.is_empty() {
+        return output.result(
+            &json!({ "funded": [], "failures": failures }),
+            format_args!("[fund] no accounts below {}, {} failures", threshold, failures),
+        );
+    }
+
+    let txid = rpc
+        .transfer_many(treasury, &outputs)
+        .await
+        .map_err(|e| Error::msg(format!("[fund] transfer from {} failed: {}", treasury.address, e)))?;
+    let mut funded = vec![];
+    for (role, account, balance) in recipients.iter() {
+        output.line(format_args!(
+            "[fund] {} {}: {} -> +{}",
+            role, account.address, balance, amount
+        ));
+        funded.push(json!({ "role": role, "address": account.address, "balance": balance }));
+    }
+    output.result(
+        &json!({ "funded": funded, "amount": amount, "txid": txid, "failures": failures }),
+        format_args!(
+            "[fund] sent {} to {} accounts in {}, {} failures",
+            amount * outputs.len() as u64,
+            outputs.len(),
+            txid,
+            failures
+        ),
+    )
+}
+
+/// Differences between the account files and the accounts table.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AccountDiff {
+    /// In a file but not in the DB, or stored under another type.
+    pub missing: Vec<(i32, String)>,
+    /// In the DB but in no file.
+    pub orphaned: Vec<(i32, String)>,
+}
+
+/// Compares each `(ty, role, accounts)` file against the DB, reporting both
+/// directions; with `fix`, missing accounts are inserted. Orphans are only
+/// reported since the DB can't recover their keys.
+pub async fn verify_accounts(
+    storage: &dyn StorageBackend,
+    roles: &[(i32, &str, &Vec<FraAccount>)],
+    fix: bool,
+    output: Output,
+) -> Result<AccountDiff> {
+    let mut stored: HashMap<String, i32> = storage.list_accounts().await?.into_iter().collect();
+    let mut diff = AccountDiff::default();
+
+    for (ty, role, accounts) in roles {
+        let mut missing = vec![];
+        for account in accounts.iter() {
+            match stored.remove(&account.address) {
+                Some(stored_ty) if stored_ty == *ty => {}
+                Some(stored_ty) => {
+                    output.line(format_args!(
+                        "[verify] {} {}: stored as type {}",
+                        role, account.address, stored_ty
+                    ));
+                    missing.push(account.clone());
+                }
+                None => {
+                    output.line(format_args!("[verify] {} {}: not in DB", role, account.address));
+                    missing.push(account.clone());
+                }
+            }
+        }
+        if fix && !missing.is_empty() {
+            storage.insert_accounts(*ty, &missing).await?;
+            output.line(format_args!("[verify] {}: inserted {} accounts", role, missing.len()));
+        }
+        diff.missing
+            .extend(missing.into_iter().map(|account| (*ty, account.address)));
+    }
+
+    for (address, ty) in stored {
+        output.line(format_args!("[verify] type {} {}: not in any file", ty, address));
+        diff.orphaned.push((ty, address));
+    }
+    diff.orphaned.sort();
+
+    output.result(
+        &diff,
+        format_args!(
+            "[verify] {} missing{}, {} orphaned",
+            diff.missing.len(),
+            if fix { " (fixed)" } else { "" },
+            diff.orphaned.len()
+        ),
+    )?;
+    Ok(diff)
+}
+
+/// Cancels the listings of `tokens` the bot recorded placing; anything else
+/// on the book, a listing placed by hand from one of our accounts included,
+/// is left alone. The exchange doesn't return listing ids, so a listing is
+/// told apart by its seller, amount and price matching a recorded list trade
+/// not yet cancelled. Asks for confirmation first unless `force`.
+pub async fn cancel_all(
+    rpc: &Rpc,
+    storage: &dyn StorageBackend,
+    tokens: &[String],
+    accounts: &[FraAccount],
+    force: bool,
+    output: Output,
+) -> Result<()> {
+    let stored: HashMap<String, i32> = storage.list_accounts().await?.into_iter().collect();
+    let keys: HashMap<&str, &FraAccount> = accounts
+        .iter()
+        .filter(|account| stored.contains_key(&account.address))
+        .map(|account| (account.address.as_str(), account))
+        .collect();
+
+    let mut placed = placed_listings(storage).await?;
+    let mut owned = vec![];
+    for token in tokens {
+        for item in fetch_book(rpc, token).await? {
+            let account = match keys.get(item.from.as_str()) {
+                Some(account) => *account,
+                None => continue,
+            };
+            let (amount, price) = match (item.amount_value(), item.price_value()) {
+                (Ok(amount), Ok(price)) => (amount, price),
+                _ => continue,
+            };
+            let key = (token.clone(), item.from.clone(), amount, price);
+            match placed.get_mut(&key) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => continue,
+            }
+            owned.push((token, item, account));
+        }
+    }
+    if owned.is_empty() {
+        return output.result(
+            &json!({ "cancelled": [], "failed": [] }),
+            "[cancel-all] no bot-owned listings",
+        );
+    }
+    if !force && !confirm(&format!("Cancel {} listings? [y/N] ", owned.len()))? {
+        return output.result(&json!({ "aborted": true }), "[cancel-all] aborted");
+    }
+
+    let mut cancelled = vec![];
+    let mut failed = vec![];
+    for (token, item, account) in owned.iter() {
+        match rpc.cancel_listing(account, item.id).await {
+            Ok(()) => {
+                output.line(format_args!(
+                    "[cancel-all] {} {} listing {} cancelled",
+                    token, account.address, item.id
+                ));
+                cancelled.push(item.id);
+                storage
+                    .record_trade(&Trade {
+                        action: TradeAction::Cancel,
+                        token: token.to_string(),
+                        address: account.address.clone(),
+                        amount: item.amount.parse()?,
+                        price: item.price.parse()?,
+                        txid: item.id.to_string(),
+                        create_time: now_secs(),
+                    })
+                    .await?;
+            }
+            Err(e) => {
+                output.line(format_args!(
+                    "[cancel-all] {} listing {} failed: {}",
+                    token, item.id, e
+                ));
+                failed.push(item.id);
+            }
+        }
+    }
+    output.result(
+        &json!({ "cancelled": cancelled, "failed": failed }),
+        format_args!(
+            "[cancel-all] cancelled {}, {} failures",
+            cancelled.len(),
+            failed.len()
+        ),
+    )
+}
+
+/// Listings the bot placed and hasn't cancelled, by `(token, seller,
+/// amount, price)`, counted from the recorded trades.
+async fn placed_listings(
+    storage: &dyn StorageBackend,
+) -> Result<HashMap<(String, String, u64, u64), usize>> {
+    let mut placed: HashMap<(String, String, u64, u64), usize> = HashMap::new();
+    let mut trades = storage.recent_trades(0);
+    while let Some(trade) = trades.next().await {
+        let trade = trade?;
+        let key = (trade.token, trade.address, trade.amount, trade.price);
+        match trade.action {
+            TradeAction::List => *placed.entry(key).or_default() += 1,
+            TradeAction::Cancel => {
+                if let Some(count) = placed.get_mut(&key) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(placed)
+}
+
+async fn fetch_book(rpc: &Rpc, token: &str) -> Result<Vec<ListItem>> {
+    let first = rpc.get_token_list(token, 1, BOOK_PAGE_SIZE).await?;
+    let pages = (first.total + BOOK_PAGE_SIZE - 1) / BOOK_PAGE_SIZE;
+    let mut items = first.data.unwrap_or_default();
+    for page in 2..=pages {
+        let res = rpc.get_token_list(token, page, BOOK_PAGE_SIZE).await?;
+        items.extend(res.data.unwrap_or_default());
+    }
+    Ok(items)
+}
+
+/// Prompts on stderr so JSON output on stdout stays clean.
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Loads the configuration and reports every problem with it, failing when
+/// there is any. Nothing is connected to.
+pub fn validate_config(config: Result<Config>, floor_prices: &[u64], output: Output) -> Result<()> {
+    let problems = match config {
+        Ok(config) => {
+            let mut problems = config.problems();
+            if floor_prices.is_empty() || floor_prices.contains(&0) {
+                problems.push(format!("floor prices {:?} must be positive", floor_prices));
+            }
+            problems
+        }
+        Err(e) => vec![format!("{:#}", e)],
+    };
+    let text = if problems.is_empty() {
+        "[validate-config] ok".to_string()
+    } else {
+        problems
+            .iter()
+            .map(|problem| format!("[validate-config] {}", problem))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    output.result(&json!({ "ok": problems.is_empty(), "problems": problems }), text)?;
+    if !problems.is_empty() {
+        return Err(Error::msg(format!("{} config problems", problems.len())));
+    }
+    Ok(())
+}
+
+/// Crate version, commit and build time of this binary.
+pub fn version_line() -> String {
+    let built = DateTime::from_timestamp(env!("BUILD_TIMESTAMP").parse().unwrap_or(0), 0)
+        .map_or("unknown".to_string(), |t| t.to_rfc3339());
+    format!(
+        "brc20-trading-bot {} (commit {}, built {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_COMMIT"),
+        built
+    )
+}
+
+pub fn version(output: Output) -> Result<()> {
+    output.result(
+        &json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "commit": env!("GIT_COMMIT"),
+            "build_timestamp": env!("BUILD_TIMESTAMP"),
+        }),
+        version_line(),
+    )
+}
+
+/// Generates and drops `count` accounts, reporting how long it took. Nothing
+/// is written to disk or the database.
+pub fn bench_gen(count: i32, output: Output) -> Result<()> {
+    let start = Instant::now();
+    let accounts = gen_accounts(count)?;
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = accounts.len() as f64 / elapsed.max(f64::EPSILON);
+    drop(accounts);
+
+    output.result(
+        &json!({ "count": count, "elapsed_secs": elapsed, "accounts_per_sec": rate }),
+        format!("[bench-gen] {} accounts in {:.3}s ({:.1}/s)", count, elapsed, rate),
+    )
+}
+
+/// Streams trades recorded on or after `since` (a `YYYY-MM-DD` UTC date) into
+/// a CSV file at `out`.
+pub async fn export_trades(
+    storage: &dyn StorageBackend,
+    out: &str,
+    since: &str,
+    output: Output,
+) -> Result<()> {
+    let since = day_start(since)?;
+
+    let mut w = BufWriter::new(File::create(out)?);
+    writeln!(w, "action,token,address,amount,price,txid,timestamp")?;
+
+    let mut rows = 0;
+    let mut trades = storage.report_trades(since);
+    while let Some(trade) = trades.next().await {
+        write_trade_csv(&mut w, &trade?)?;
+        rows += 1;
+    }
+    w.flush()?;
+
+    output.result(
+        &json!({ "rows": rows, "out": out }),
+        format_args!("[export] wrote {} trades to {}", rows, out),
+    )
+}
+
+/// Writes the trades recorded since `since` (YYYY-MM-DD, UTC) to `out` as
+/// timestamped OpenMetrics, for `promtool tsdb create-blocks-from openmetrics`
+/// to backfill dashboards. Only storage is read.
+pub async fn replay_metrics(
+    storage: &dyn StorageBackend,
+    out: &str,
+    since: &str,
+    output: Output,
+) -> Result<()> {
+    let since = day_start(since)?;
+    let mut trades = vec![];
+    let mut stream = storage.report_trades(since);
+    while let Some(trade) = stream.next().await {
+        trades.push(trade?);
+    }
+    let mut w = BufWriter::new(File::create(out)?);
+    w.write_all(trade_history(&trades).as_bytes())?;
+    w.flush()?;
+
+    output.result(
+        &json!({ "trades": trades.len(), "out": out }),
+        format_args!("[replay-metrics] wrote {} trades to {}", trades.len(), out),
+    )
+}
+
+/// `trades`, oldest first, as `trades_total` and `trade_price` samples at
+/// the time of each trade, one series after another as OpenMetrics wants.
+fn trade_history(trades: &[Trade]) -> String {
+    let mut series: BTreeMap<String, (u64, String, String)> = BTreeMap::new();
+    for trade in trades.iter() {
+        let labels = format!(
+            "{{action=\"{}\",token=\"{}\"}}",
+            trade.action.as_str(),
+            trade.token
+        );
+        let (count, totals, prices) = series.entry(labels.clone()).or_default();
+        *count += 1;
+        totals.push_str(&format!("trades_total{} {} {}\n", labels, count, trade.create_time));
+        if trade.action != TradeAction::Cancel {
+            let price = format!("trade_price{} {} {}\n", labels, trade.price, trade.create_time);
+            prices.push_str(&price);
+        }
+    }
+    let mut out = "# TYPE trades counter\n".to_string();
+    series.values().for_each(|(_, totals, _)| out.push_str(totals));
+    out.push_str("# TYPE trade_price gauge\n");
+    series.values().for_each(|(_, _, prices)| out.push_str(prices));
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Unix seconds of midnight UTC on `date`, YYYY-MM-DD.
+fn day_start(date: &str) -> Result<i64> {
+    Ok(NaiveDate::parse_from_str(date, "%Y-%m-%d")?
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp())
+}
+
+/// Time-to-target for one token and action, at the rate seen in the window.
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    pub token: String,
+    pub action: &'static str,
+    pub target: u64,
+    pub done: u64,
+    /// Tokens per hour over the window.
+    pub rate_per_hour: f64,
+    /// `None` when nothing happened in the window, so there's no rate.
+    pub eta_secs: Option<u64>,
+}
+
+/// Estimates when `MINT_TARGET_TOTAL` and `BUY_TARGET_AMOUNT` will be met if
+/// the trades of the last `window_secs` keep their pace. Progress is read
+/// from the same cursors the cycles save, and nothing is written.
+pub async fn eta(
+    storage: &dyn StorageBackend,
+    config: &Config,
+    window_secs: u64,
+    output: Output,
+) -> Result<Vec<Estimate>> {
+    let now = now_secs();
+    let window = window_secs.max(1);
+    let mut done_in_window: HashMap<(String, &str), u64> = HashMap::new();
+    let mut trades = storage.report_trades(now - window as i64);
+    while let Some(trade) = trades.next().await {
+        let trade = trade?;
+        *done_in_window.entry((trade.token, trade.action.as_str())).or_default() += trade.amount;
+    }
+
+    let mut estimates = vec![];
+    for token in config.tokens.iter() {
+        let targets = [
+            (TradeAction::Mint, CURSOR_MINTED, config.mint_target_total),
+            (TradeAction::Buy, CURSOR_BOUGHT, config.buy_target_amount),
+        ];
+        for (action, cursor, target) in targets {
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
+            let cursor = format!("{}:{}", cursor, token);
+            let done = storage.load_cursor(&cursor).await?.unwrap_or(0).max(0) as u64;
+            let key = (token.clone(), action.as_str());
+            let recent = done_in_window.get(&key).copied().unwrap_or(0);
+            let rate_per_hour = recent as f64 * 3600.0 / window as f64;
+            let left = target.saturating_sub(done);
+            let eta_secs = if left == 0 {
+                Some(0)
+            } else if recent == 0 {
+                None
+            } else {
+                Some((left as u128 * window as u128).div_ceil(recent as u128) as u64)
+            };
+            estimates.push(Estimate {
+                token: token.clone(),
+                action: action.as_str(),
+                target,
+                done,
+                rate_per_hour,
+                eta_secs,
+            });
+        }
+    }
+
+    let mut text = vec![format!(
+        "[eta] assumes the pace of the last {}s holds, with the current accounts and intervals",
+        window
+    )];
+    if estimates.is_empty() {
+        text.push("[eta] neither MINT_TARGET_TOTAL nor BUY_TARGET_AMOUNT is set".to_string());
+    }
+    for e in estimates.iter() {
+        let eta = match e.eta_secs {
+            Some(0) => "target met".to_string(),
+            Some(secs) => format!("about {:.1}h", secs as f64 / 3600.0),
+            None => "unknown, no trades in the window".to_string(),
+        };
+        text.push(format!(
+            "[eta] {} {}: {}/{} at {:.1}/h, {}",
+            e.token, e.action, e.done, e.target, e.rate_per_hour, eta
+        ));
+    }
+    output.result(&estimates, text.join("\n"))?;
+    Ok(estimates)
+}
+
+/// Node and exchange chain heights, as `diagnose` saw them.
+#[derive(Debug, Serialize)]
+pub struct Heights {
+    pub node: u64,
+    pub exchange: Option<u64>,
+    pub gap: Option<u64>,
+}
+
+/// Compares the node's chain height with the exchange's, warning past
+/// HEIGHT_GAP_WARN and failing past HEIGHT_GAP_MAX.
+pub async fn diagnose(rpc: &Rpc, config: &Config, output: Output) -> Result<Heights> {
+    let node = rpc.latest_height().await?;
+    let exchange = rpc.get_exchange_height().await?;
+    let gap = exchange.map(|exchange| node.abs_diff(exchange));
+    let mut text = vec![format!("[diagnose] node height {}", node)];
+    match (exchange, gap) {
+        (Some(exchange), Some(gap)) => {
+            text.push(format!("[diagnose] exchange height {}, {} blocks apart", exchange, gap));
+            if gap > config.height_gap_max {
+                let max = config.height_gap_max;
+                text.push(format!("[diagnose] gap is past HEIGHT_GAP_MAX {}", max));
+            } else if gap > config.height_gap_warn {
+                text.push(format!(
+                    "[diagnose] warning: gap is past HEIGHT_GAP_WARN {}; the exchange is lagging",
+                    config.height_gap_warn
+                ));
+            }
+        }
+        _ => text.push("[diagnose] the exchange doesn't report its height".to_string()),
+    }
+    let heights = Heights { node, exchange, gap };
+    output.result(&heights, text.join("\n"))?;
+    if let Some(gap) = gap.filter(|&gap| gap > config.height_gap_max) {
+        return Err(Error::msg(format!("node and exchange are {} blocks apart", gap)));
+    }
+    Ok(heights)
+}
+
+fn write_trade_csv<W: Write>(w: &mut W, trade: &Trade) -> Result<()> {
+    let timestamp = DateTime::from_timestamp(trade.create_time, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    writeln!(
+        w,
+        "{},{},{},{},{},{},{}",
+        trade.action.as_str(),
+        csv_field(&trade.token),
+        csv_field(&trade.address),
+        trade.amount,
+        trade.price,
+        csv_field(&trade.txid),
+        timestamp
+    )?;
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{
+        cancel_all, diagnose, eta, trade_history, verify_accounts, write_trade_csv, Output,
+    };
+    use crate::config::Config;
+    use crate::db::mem::MemStorage;
+    use crate::db::StorageBackend;
+    use crate::types::mock::{json_response, mock_server};
+    use crate::types::{FraAccount, ListItem, ListResponse, Rpc, Trade, TradeAction};
+    use crate::utils::{gen_accounts, now_secs};
+    use anyhow::Result;
+
+    #[test]
+    fn test_write_trade_csv() -> Result<()> {
+        let trade = Trade {
+            action: TradeAction::Buy,
+            token: "bo,nk".to_string(),
+            address: "fra1abc".to_string(),
+            amount: 10,
+            price: 5,
+            txid: "deadbeef".to_string(),
+            create_time: 86400,
+        };
+        let mut out = vec![];
+        write_trade_csv(&mut out, &trade)?;
+
+        assert_eq!(
+            String::from_utf8(out)?,
+            "buy,\"bo,nk\",fra1abc,10,5,deadbeef,1970-01-02T00:00:00+00:00\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_accounts_fix() -> Result<()> {
+        let storage = MemStorage::default();
+        let accounts: Vec<FraAccount> = gen_accounts(2)?;
+        storage.insert_accounts(1, &accounts[..1]).await?;
+        storage.insert_accounts(1, &gen_accounts(1)?).await?;
+
+        let roles = [(1, "mint", &accounts)];
+        let diff = verify_accounts(&storage, &roles, false, Output::Json).await?;
+        assert_eq!(diff.missing, vec![(1, accounts[1].address.clone())]);
+        assert_eq!(diff.orphaned.len(), 1);
+
+        verify_accounts(&storage, &roles, true, Output::Text).await?;
+        let diff = verify_accounts(&storage, &roles, false, Output::Text).await?;
+        assert!(diff.missing.is_empty());
+        assert_eq!(diff.orphaned.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_skips_foreign_listings() -> Result<()> {
+        let accounts = gen_accounts(1)?;
+        let mut ours = ListItem::sample(1, 100, 5);
+        ours.from = accounts[0].address.clone();
+        // placed by hand from the same account
+        let mut manual = ListItem::sample(3, 70, 5);
+        manual.from = accounts[0].address.clone();
+        let book = ListResponse::from_items(vec![ours, ListItem::sample(2, 100, 5), manual]);
+        let url = mock_server(vec![
+            json_response(&serde_json::to_string(&book)?),
+            json_response("{}"),
+        ])
+        .await;
+        let rpc = Rpc::new(&url, &url, &url)?;
+        let storage = MemStorage::default();
+        storage.insert_accounts(1, &accounts).await?;
+        storage
+            .record_trade(&Trade {
+                action: TradeAction::List,
+                token: "bonk".to_string(),
+                address: accounts[0].address.clone(),
+                amount: 100,
+                price: 5,
+                txid: "aa".to_string(),
+                create_time: now_secs(),
+            })
+            .await?;
+
+        let tokens = ["bonk".to_string()];
+        cancel_all(&rpc, &storage, &tokens, &accounts, true, Output::Text).await?;
+
+        let trades = storage.trades.lock().unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[1].action, TradeAction::Cancel);
+        assert_eq!(trades[1].txid, "1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_eta_from_recent_rate() -> Result<()> {
+        let storage = MemStorage::default();
+        let now = now_secs();
+        for (action, amount, age) in [
+            (TradeAction::Mint, 300, 600),
+            (TradeAction::Mint, 300, 1800),
+            (TradeAction::Mint, 5000, 7200),
+            (TradeAction::List, 900, 600),
+        ] {
+            storage
+                .record_trade(&Trade {
+                    action,
+                    token: "bonk".to_string(),
+                    address: "fra1abc".to_string(),
+                    amount,
+                    price: 1,
+                    txid: format!("{}", age),
+                    create_time: now - age,
+                })
+                .await?;
+        }
+        storage.save_cursor("minted:bonk", 400).await?;
+        let mut config = Config::for_tests(&["bonk"]);
+        config.mint_target_total = Some(1000);
+        config.buy_target_amount = Some(50);
+
+        let estimates = eta(&storage, &config, 3600, Output::Text).await?;
+
+        assert_eq!(estimates.len(), 2);
+        // 600 minted in the last hour, 600 left
+        assert_eq!(estimates[0].action, "mint");
+        assert_eq!(estimates[0].rate_per_hour, 600.0);
+        assert_eq!(estimates[0].eta_secs, Some(3600));
+        assert_eq!(estimates[1].action, "buy");
+        assert_eq!(estimates[1].eta_secs, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_fails_past_height_gap_max() -> Result<()> {
+        let status = |height: u64| {
+            json_response(&format!(
+                r#"{{"result":{{"sync_info":{{"latest_block_height":"{}"}}}}}}"#,
+                height
+            ))
+        };
+        let url = mock_server(vec![
+            status(110),
+            json_response(r#"{"data":{"height":100}}"#),
+            status(150),
+            json_response(r#"{"data":{"height":100}}"#),
+        ])
+        .await;
+        let rpc = Rpc::new(&url, &url, &url)?;
+        let config = Config::for_tests(&["bonk"]);
+
+        let heights = diagnose(&rpc, &config, Output::Text).await?;
+        assert_eq!(heights.gap, Some(10));
+        assert!(diagnose(&rpc, &config, Output::Text).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trade_history_is_timestamped_openmetrics() {
+        let trade = |action, price, create_time| Trade {
+            action,
+            token: "bonk".to_string(),
+            address: "fra1abc".to_string(),
+            amount: 10,
+            price,
+            txid: create_time.to_string(),
+            create_time,
+        };
+        let trades = [
+            trade(TradeAction::Buy, 5, 100),
+            trade(TradeAction::Cancel, 7, 150),
+            trade(TradeAction::Buy, 6, 200),
+        ];
+
+        assert_eq!(
+            trade_history(&trades),
+            "# TYPE trades counter\n\
+             trades_total{action=\"buy\",token=\"bonk\"} 1 100\n\
+             trades_total{action=\"buy\",token=\"bonk\"} 2 200\n\
+             trades_total{action=\"cancel\",token=\"bonk\"} 1 150\n\
+             # TYPE trade_price gauge\n\
+             trade_price{action=\"buy\",token=\"bonk\"} 5 100\n\
+             trade_price{action=\"buy\",token=\"bonk\"} 6 200\n\
+             # EOF\n"
+        );
+    }
+}
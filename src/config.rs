@@ -0,0 +1,870 @@
+use crate::exchange::ExchangeKind;
+use crate::strategy::TieBreak;
+use crate::utils::{ActiveWindow, DbFailurePolicy, FloorMode, PartialFailure, SplitStrategy};
+use anyhow::{Context, Error, Result};
+use globutils::wallet::public_key_from_bech32;
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+/// Decimal places of FRA; FRA amounts given with a decimal point are in
+/// whole FRA.
+const FRA_DECIMALS: u32 = 6;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    /// Read replica for reporting queries; the primary serves them when unset.
+    pub database_replica_url: Option<String>,
+    /// Token this config drives; the first of `tokens` until `for_token`.
+    pub token: String,
+    /// Every token in the comma-separated TOKEN list.
+    pub tokens: Vec<String>,
+    /// Per-token BUY_BUDGET_<TOKEN> overrides of `buy_budget`.
+    pub token_budgets: HashMap<String, u64>,
+    /// Decimal places of the tokens, for token amounts written like "12.3".
+    pub token_decimals: u32,
+    pub ex_rpc_url: String,
+    pub node_rpc_url: String,
+    pub node_api_port: String,
+    pub node_td_port: String,
+    /// Exchange escrow account new listings are sent to.
+    pub center_account: String,
+    /// List when the book's summed amount drops below this.
+    pub list_sum_min: Option<u64>,
+    /// Cancel own listings when the book's summed amount exceeds this.
+    pub list_sum_max: Option<u64>,
+    /// Extra fetches of the first page when the book comes back empty.
+    pub empty_list_retries: u32,
+    /// Address for the Prometheus endpoint; metrics are not served when unset.
+    pub metrics_addr: Option<String>,
+    pub balance_refresh_secs: u64,
+    /// Whether the exchange accepts buying part of a listing.
+    pub partial_fills: bool,
+    /// Order of listings at the same price; cheaper listings always go first.
+    pub buy_tie_break: TieBreak,
+    /// Total FRA the buy side may spend; unlimited when unset.
+    pub buy_budget: Option<u64>,
+    /// Share of the budget, in percent, past which each tick places fewer
+    /// buys the less budget is left; no tapering when unset.
+    pub buy_soft_cap_pct: Option<u64>,
+    /// Total tokens the buy side should acquire; unlimited when unset.
+    pub buy_target_amount: Option<u64>,
+    /// List and buy ticks, combined, that only observe before acting. Minting
+    /// and repricing wait for them too but don't count toward them.
+    pub warmup_ticks: u64,
+    /// Chance per tick that a qualifying listing is bought; 1.0 buys all.
+    pub buy_probability: f64,
+    /// Upper bound of the random delay before each buy.
+    pub buy_jitter_ms: u64,
+    /// Seeds the jitter rng for reproducible runs.
+    pub buy_jitter_seed: Option<u64>,
+    /// Ask the exchange for cheapest-first listings at or below the floor.
+    pub sorted_scan: bool,
+    /// UTXOs worth less than this are left out of input selection.
+    pub min_utxo_value: u64,
+    /// Re-list all bought inventory at market when the floor collapses.
+    pub enable_panic_sell: bool,
+    /// Floor drop, in percent of the window's high, that triggers panic sell.
+    pub panic_drop_pct: f64,
+    /// How far back the floor high is tracked.
+    pub panic_window_secs: u64,
+    /// Mint each listing's amount to the listing account before listing it.
+    pub mint_before_list: bool,
+    /// Extra wait after a mint commits, for the exchange indexer to see it.
+    pub mint_to_list_delay_secs: u64,
+    /// How long to wait for a mint to commit before giving up on the listing.
+    pub mint_confirm_timeout_secs: u64,
+    /// Skip minting; the cycles still poll and log.
+    pub no_mint: bool,
+    /// Skip buying; the cycles still poll and log.
+    pub no_buy: bool,
+    /// Skip listing and cancelling; the cycles still poll and log.
+    pub no_list: bool,
+    /// Daily hours the cycles run in; always active when unset.
+    pub active_window: Option<ActiveWindow>,
+    /// Price new listings this many basis points over the book's median
+    /// instead of the fixed floor prices.
+    pub list_spread_bps: Option<u32>,
+    /// Pages a buy scan may fetch per tick; the listed-supply sum is exempt.
+    pub max_pages_per_scan: Option<u32>,
+    /// Seconds an account rests after minting before it mints again.
+    pub min_mint_interval_secs: u64,
+    /// Undercut the best foreign ask by this much when creating listings;
+    /// prices are left alone when unset.
+    pub list_undercut_tick: Option<u64>,
+    /// Lowest price a created listing may have.
+    pub sell_floor: Option<u64>,
+    /// DB pool size; derived by `pool_sizes` when unset.
+    pub db_max_connections: Option<u32>,
+    /// Idle RPC connections kept per host; derived by `pool_sizes` when unset.
+    pub rpc_pool_size: Option<usize>,
+    /// Idle pooled connections are closed after this many seconds.
+    pub db_idle_timeout_secs: u64,
+    /// Mint, list and buy submissions in flight at once, over all accounts
+    /// and tokens.
+    pub max_inflight_actions: usize,
+    /// FRA every account keeps unspent so it can pay for later transactions.
+    pub fee_reserve: u64,
+    /// Most accounts `--accounts` may ask to generate for one file.
+    pub max_gen_accounts: i32,
+    /// Directory holding the account files and their checksums.
+    pub accounts_dir: String,
+    /// Refuse to start, rather than warn, when an accounts file no longer
+    /// matches its checksum or has none.
+    pub accounts_checksum_strict: bool,
+    /// Books with fewer listings than this are too thin to buy into.
+    pub min_listings_to_buy: usize,
+    /// Retries one list or buy cycle may make over all its calls before it
+    /// gives up; unlimited when unset.
+    pub cycle_retry_budget: Option<u32>,
+    /// Largest gap between the node's and the exchange's balance for an
+    /// account that still lists; any gap is accepted when unset.
+    pub balance_tolerance: Option<u64>,
+    /// Smallest listing worth its fee; smaller deficits are listed at this size.
+    pub min_list_amount: u64,
+    /// A listed sum above this is treated as a corrupt book and not acted on.
+    pub max_listed_sum: Option<u64>,
+    /// Buy under the average cheapest ask of this many seconds instead of
+    /// the rotating floor.
+    pub ma_window_secs: Option<u64>,
+    /// Fail the cycle on listing ids repeated across pages instead of
+    /// dropping the repeats.
+    pub strict_pagination: bool,
+    /// Most mints, lists and buys one cycle submits in total; the rest wait
+    /// for the next cycle. Unlimited when unset.
+    pub max_actions_per_tick: Option<u32>,
+    /// Rotate each role's accounts in a shuffled order instead of file order.
+    pub shuffle_accounts: bool,
+    /// Seed of that order; a stored or fresh one is used when unset.
+    pub shuffle_seed: Option<u64>,
+    /// Wait before the first list and buy cycles, so the startup banner can
+    /// be checked and the bot stopped before it acts. Later cycles keep
+    /// their intervals from then on.
+    pub startup_grace_secs: u64,
+    /// Runs the list and buy cycles as their own tasks, so a slow scan in
+    /// one doesn't delay the other. Accounts are then locked per spend.
+    pub concurrent_cycles: bool,
+    /// Total to mint per token over the bot's life, regardless of the book;
+    /// the mint cycle is off when unset.
+    pub mint_target_total: Option<u64>,
+    /// Largest single mint of the mint cycle.
+    pub mint_amount: u64,
+    /// Relist own listings priced further than this from the current list
+    /// price, in basis points; the reprice cycle is off when unset.
+    pub reprice_drift_bps: Option<u64>,
+    /// Listings younger than this are never repriced, so a relisted one
+    /// isn't churned again at once.
+    pub reprice_min_interval_secs: u64,
+    /// Proxy for every RPC request, credentials allowed in the URL; without
+    /// it HTTP_PROXY/HTTPS_PROXY apply.
+    pub rpc_proxy: Option<String>,
+    /// Empty scans in a row after which the list and buy cycles start
+    /// polling less often; they always poll every tick when unset.
+    pub empty_backoff_after: Option<u32>,
+    /// Longest a backed-off cycle goes between scans.
+    pub empty_backoff_max_secs: u64,
+    /// Share of MINT_LIMIT, in percent, at which an account's mints raise an
+    /// alert.
+    pub mint_alert_pct: u64,
+    /// Alerts are also posted here as `{"text": ...}` when set.
+    pub alert_webhook: Option<String>,
+    /// Trace-log every RPC request and response with its body.
+    pub log_rpc_bodies: bool,
+    /// Logged bodies are cut to this many bytes.
+    pub rpc_log_max_bytes: usize,
+    /// Mask addresses in logged URLs and bodies.
+    pub rpc_log_redact: bool,
+    /// Blocks, counting its own, that must hold a trade before it is marked
+    /// confirmed; 1 takes first inclusion as final.
+    pub min_confirmations: u64,
+    /// Persist each decided list and buy before running it, so one cut off
+    /// by a crash is finished on the next start.
+    pub action_queue: bool,
+    /// Submissions each account may make per minute, on top of the global
+    /// MAX_INFLIGHT_ACTIONS; unlimited when unset.
+    pub per_account_rate_limit: Option<u32>,
+    /// Submissions an account may make back to back before that rate applies.
+    pub per_account_burst: u32,
+    /// Whether the floor schedule starts over or holds its last price once
+    /// it runs out.
+    pub floor_mode: FloorMode,
+    /// Most UTXO inputs one transaction may spend; unlimited when unset.
+    pub max_tx_inputs: Option<usize>,
+    /// Whether a spend over MAX_TX_INPUTS merges the inputs first rather
+    /// than being skipped.
+    pub consolidate_utxos: bool,
+    /// How a listing deficit is shared across mint accounts.
+    pub split_strategy: SplitStrategy,
+    /// Whether a list scan cut short by a crash picks up at its last page
+    /// on the next cycle. Listings on the pages it skips aren't seen by that
+    /// cycle's pricing and cancels.
+    pub scan_resume: bool,
+    /// How much the book's size may change, in percent, before a saved
+    /// scan is thrown away.
+    pub scan_resume_tolerance_pct: u32,
+    /// Scans in a row a listing must stay under the floor before it is
+    /// bought; 1 buys on first sight.
+    pub buy_confirm_scans: u32,
+    /// Sellers the buy cycle may buy from; anyone when unset.
+    pub seller_whitelist: Option<HashSet<String>>,
+    /// Sellers the buy cycle never buys from.
+    pub seller_blacklist: Option<HashSet<String>>,
+    /// Own listings older than this, by the exchange's creation time, are
+    /// cancelled by the reprice cycle; the exchange has no expiry of its own.
+    pub listing_ttl_secs: Option<u64>,
+    /// Each account starts acting up to this many seconds after startup, at
+    /// its own random offset. Offsets are stored so restarts keep them.
+    pub account_start_spread_secs: Option<u64>,
+    /// Seed of those offsets; a fresh one is used when unset.
+    pub account_start_seed: Option<u64>,
+    /// Blocks the exchange may trail the node before `diagnose` warns.
+    pub height_gap_warn: u64,
+    /// Blocks past which `diagnose` fails.
+    pub height_gap_max: u64,
+    /// Whether a list scan missing a failed page goes on. A partial scan
+    /// undercounts the listed sum, so it never lists more, only cancels.
+    pub scan_on_partial_failure: PartialFailure,
+    /// Marketplace adapter the cycles trade through.
+    pub exchange: ExchangeKind,
+    /// Buys must make at least this much, in base units, relisted at the
+    /// floor after the listing fee, besides being under the floor at all.
+    pub min_abs_profit: Option<u64>,
+    /// Whether the cycles keep acting while trades can't be recorded.
+    pub db_failure_policy: DbFailurePolicy,
+    /// Minting and listing pause while the cheapest ask is under this unit
+    /// price; buying carries on.
+    pub min_economic_price: Option<u64>,
+    /// Most own listings of a token open at once; new ones wait for a fill
+    /// or cancel. Unlimited when unset.
+    pub max_open_listings: Option<u64>,
+    /// Whether buy accounts under `topup_min_balance` are topped up with
+    /// `topup_amount` from the earnings pool; see `BotServer::top_up`.
+    pub auto_topup: bool,
+    pub topup_min_balance: u64,
+    pub topup_amount: u64,
+    /// Mint or buy account top-ups are paid from; the mint account holding
+    /// the most FRA when unset.
+    pub earnings_account: Option<String>,
+    /// Wall-clock limit on fetching a whole book, in milliseconds; the pages
+    /// still missing then are left out. Unlimited when unset.
+    pub listings_deadline_ms: Option<u64>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let tokens: Vec<String> = env_required("TOKEN")?
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if tokens.is_empty() {
+            return Err(Error::msg("TOKEN is empty"));
+        }
+        let active_window = match (
+            env_opt::<String>("ACTIVE_START")?,
+            env_opt::<String>("ACTIVE_END")?,
+        ) {
+            (Some(start), Some(end)) => {
+                let offset = env_or("ACTIVE_UTC_OFFSET", "+00:00".to_string())?;
+                let window = ActiveWindow::parse(&start, &end, &offset)
+                    .context("parsing ACTIVE_START, ACTIVE_END and ACTIVE_UTC_OFFSET")?;
+                Some(window)
+            }
+            (None, None) => None,
+            _ => return Err(Error::msg("ACTIVE_START and ACTIVE_END must be set together")),
+        };
+        let max_inflight_actions = env_or("MAX_INFLIGHT_ACTIONS", 8)?;
+        if max_inflight_actions == 0 {
+            return Err(Error::msg("MAX_INFLIGHT_ACTIONS must be at least 1"));
+        }
+        let token_decimals = env_or("TOKEN_DECIMALS", 0)?;
+        let amount = |key: &str| env_amount(key, token_decimals);
+        let fra = |key: &str| env_amount(key, FRA_DECIMALS);
+        let mut token_budgets = HashMap::new();
+        for token in tokens.iter() {
+            if let Some(budget) = fra(&format!("BUY_BUDGET_{}", token.to_uppercase()))? {
+                token_budgets.insert(token.clone(), budget);
+            }
+        }
+
+        Ok(Self {
+            database_url: env_required("DATABASE_URL")?,
+            database_replica_url: env_opt("DATABASE_REPLICA_URL")?,
+            token: tokens[0].clone(),
+            tokens,
+            token_budgets,
+            token_decimals,
+            ex_rpc_url: env_required("EX_RPC")?,
+            node_rpc_url: env_required("NODE_RPC")?,
+            node_api_port: env_required("NODE_API_PORT")?,
+            node_td_port: env_or("NODE_TD_PORT", "26657".to_string())?,
+            center_account: env_required("CENTER_ACCOUNT")?,
+            list_sum_min: amount("LIST_SUM_MIN")?.or(amount("LIST_SUM_AMOUNT")?),
+            list_sum_max: amount("LIST_SUM_MAX")?,
+            empty_list_retries: env_or("EMPTY_LIST_RETRIES", 2)?,
+            metrics_addr: env::var("METRICS_ADDR").ok(),
+            balance_refresh_secs: env_or("BALANCE_REFRESH_SECS", 60)?,
+            partial_fills: env_or("PARTIAL_FILLS", false)?,
+            buy_tie_break: TieBreak::parse(&env_or("BUY_TIE_BREAK", "largest".to_string())?)?,
+            buy_budget: fra("BUY_BUDGET")?,
+            buy_soft_cap_pct: env_opt("BUY_SOFT_CAP_PCT")?,
+            buy_target_amount: amount("BUY_TARGET_AMOUNT")?,
+            warmup_ticks: env_or("WARMUP_TICKS", 0)?,
+            buy_probability: env_or("BUY_PROBABILITY", 1.0)?,
+            buy_jitter_ms: env_or("BUY_JITTER_MS", 0)?,
+            buy_jitter_seed: env_opt("BUY_JITTER_SEED")?,
+            sorted_scan: env_or("SORTED_SCAN", false)?,
+            min_utxo_value: fra("MIN_UTXO_VALUE")?.unwrap_or(0),
+            enable_panic_sell: env_or("ENABLE_PANIC_SELL", false)?,
+            panic_drop_pct: env_or("PANIC_DROP_PCT", 30.0)?,
+            panic_window_secs: env_or("PANIC_WINDOW_SECS", 600)?,
+            mint_before_list: env_or("MINT_BEFORE_LIST", false)?,
+            mint_to_list_delay_secs: env_or("MINT_TO_LIST_DELAY_SECS", 0)?,
+            mint_confirm_timeout_secs: env_or("MINT_CONFIRM_TIMEOUT_SECS", 60)?,
+            no_mint: env_or("NO_MINT", false)?,
+            no_buy: env_or("NO_BUY", false)?,
+            no_list: env_or("NO_LIST", false)?,
+            active_window,
+            list_spread_bps: env_opt("LIST_SPREAD_BPS")?,
+            max_pages_per_scan: env_opt("MAX_PAGES_PER_SCAN")?,
+            min_mint_interval_secs: env_or("MIN_MINT_INTERVAL_SECS", 0)?,
+            list_undercut_tick: env_opt("LIST_UNDERCUT_TICK")?,
+            sell_floor: fra("SELL_FLOOR")?,
+            db_max_connections: env_opt("DB_MAX_CONNECTIONS")?,
+            rpc_pool_size: env_opt("RPC_POOL_SIZE")?,
+            db_idle_timeout_secs: env_or("DB_IDLE_TIMEOUT", 300)?,
+            max_inflight_actions,
+            fee_reserve: fra("FEE_RESERVE")?.unwrap_or(0),
+            max_gen_accounts: env_or("MAX_GEN_ACCOUNTS", 1000)?,
+            accounts_dir: env_or("ACCOUNTS_DIR", ".".to_string())?,
+            accounts_checksum_strict: env_or("ACCOUNTS_CHECKSUM_STRICT", false)?,
+            min_listings_to_buy: env_or("MIN_LISTINGS_TO_BUY", 0)?,
+            cycle_retry_budget: env_opt("CYCLE_RETRY_BUDGET")?,
+            balance_tolerance: fra("BALANCE_TOLERANCE")?,
+            min_list_amount: amount("MIN_LIST_AMOUNT")?.unwrap_or(0),
+            max_listed_sum: amount("MAX_LISTED_SUM")?,
+            ma_window_secs: env_opt("MA_WINDOW_SECS")?,
+            strict_pagination: env_or("STRICT_PAGINATION", false)?,
+            max_actions_per_tick: env_opt("MAX_ACTIONS_PER_TICK")?,
+            shuffle_accounts: env_or("SHUFFLE_ACCOUNTS", false)?,
+            shuffle_seed: env_opt("SHUFFLE_SEED")?,
+            startup_grace_secs: env_or("STARTUP_GRACE_SECS", 5)?,
+            concurrent_cycles: env_or("CONCURRENT_CYCLES", false)?,
+            mint_target_total: amount("MINT_TARGET_TOTAL")?,
+            mint_amount: amount("MINT_AMOUNT")?.unwrap_or(1000),
+            reprice_drift_bps: env_opt("REPRICE_DRIFT_BPS")?,
+            reprice_min_interval_secs: env_or("REPRICE_MIN_INTERVAL_SECS", 600)?,
+            rpc_proxy: env_opt("RPC_PROXY")?,
+            empty_backoff_after: env_opt("EMPTY_BACKOFF_AFTER")?,
+            empty_backoff_max_secs: env_or("EMPTY_BACKOFF_MAX_SECS", 300)?,
+            mint_alert_pct: env_or("MINT_ALERT_PCT", 80)?,
+            alert_webhook: env_opt("ALERT_WEBHOOK")?,
+            log_rpc_bodies: env_or("LOG_RPC_BODIES", false)?,
+            rpc_log_max_bytes: env_or("RPC_LOG_MAX_BYTES", 2048)?,
+            rpc_log_redact: env_or("RPC_LOG_REDACT", true)?,
+            min_confirmations: env_or("MIN_CONFIRMATIONS", 1)?,
+            action_queue: env_or("ACTION_QUEUE", false)?,
+            per_account_rate_limit: env_opt("PER_ACCOUNT_RATE_LIMIT")?,
+            per_account_burst: env_or("PER_ACCOUNT_BURST", 1)?,
+            floor_mode: FloorMode::parse(&env_or("FLOOR_MODE", "rotate".to_string())?)?,
+            max_tx_inputs: env_opt("MAX_TX_INPUTS")?,
+            consolidate_utxos: env_or("CONSOLIDATE_UTXOS", false)?,
+            split_strategy: SplitStrategy::parse(&env_or(
+                "SPLIT_STRATEGY",
+                "single".to_string(),
+            )?)?,
+            scan_resume: env_or("SCAN_RESUME", false)?,
+            scan_resume_tolerance_pct: env_or("SCAN_RESUME_TOLERANCE_PCT", 10)?,
+            buy_confirm_scans: env_or("BUY_CONFIRM_SCANS", 1)?,
+            seller_whitelist: env_addresses("SELLER_WHITELIST")?,
+            seller_blacklist: env_addresses("SELLER_BLACKLIST")?,
+            listing_ttl_secs: env_opt("LISTING_TTL_SECS")?,
+            account_start_spread_secs: env_opt("ACCOUNT_START_SPREAD_SECS")?,
+            account_start_seed: env_opt("ACCOUNT_START_SEED")?,
+            height_gap_warn: env_or("HEIGHT_GAP_WARN", 3)?,
+            height_gap_max: env_or("HEIGHT_GAP_MAX", 20)?,
+            scan_on_partial_failure: PartialFailure::parse(&env_or(
+                "SCAN_ON_PARTIAL_FAILURE",
+                "abort".to_string(),
+            )?)?,
+            exchange: ExchangeKind::parse(&env_or("EXCHANGE", "findora".to_string())?)?,
+            min_abs_profit: fra("MIN_ABS_PROFIT")?,
+            db_failure_policy: DbFailurePolicy::parse(&env_or(
+                "DB_FAILURE_POLICY",
+                "fail-closed".to_string(),
+            )?)?,
+            min_economic_price: fra("MIN_ECONOMIC_PRICE")?,
+            max_open_listings: env_opt("MAX_OPEN_LISTINGS")?,
+            auto_topup: env_or("AUTO_TOPUP", false)?,
+            topup_min_balance: fra("TOPUP_MIN_BALANCE")?.unwrap_or(0),
+            topup_amount: fra("TOPUP_AMOUNT")?.unwrap_or(0),
+            earnings_account: env_opt("EARNINGS_ACCOUNT")?,
+            listings_deadline_ms: env_opt("LISTINGS_DEADLINE_MS")?,
+        })
+    }
+
+    /// Every structural problem with this config, found without touching the
+    /// database or the network.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut check_url = |key: &str, url: &str, schemes: &[&str]| match Url::parse(url) {
+            Ok(parsed) if schemes.contains(&parsed.scheme()) && parsed.has_host() => {}
+            Ok(_) => problems.push(format!(
+                "{} {} is not a {} URL",
+                key,
+                redact_url(url),
+                schemes.join("/")
+            )),
+            Err(e) => problems.push(format!("{} is not a URL: {}", key, e)),
+        };
+        check_url("EX_RPC", &self.ex_rpc_url, &["http", "https"]);
+        check_url("NODE_RPC", &self.node_rpc_url, &["http", "https"]);
+        check_url("DATABASE_URL", &self.database_url, &["postgres", "postgresql"]);
+        if let Some(url) = self.database_replica_url.as_deref() {
+            check_url("DATABASE_REPLICA_URL", url, &["postgres", "postgresql"]);
+        }
+        if let Some(url) = self.rpc_proxy.as_deref() {
+            check_url("RPC_PROXY", url, &["http", "https", "socks5"]);
+        }
+        if let Some(url) = self.alert_webhook.as_deref() {
+            check_url("ALERT_WEBHOOK", url, &["http", "https"]);
+        }
+        for (key, port) in [
+            ("NODE_API_PORT", &self.node_api_port),
+            ("NODE_TD_PORT", &self.node_td_port),
+        ] {
+            if port.parse::<u16>().is_err() {
+                problems.push(format!("{} {:?} is not a port", key, port));
+            } else if Url::parse(&self.node_rpc_url).is_ok() {
+                if let Err(e) = node_url(&self.node_rpc_url, key, port) {
+                    problems.push(e.to_string());
+                }
+            }
+        }
+        if let Err(e) = public_key_from_bech32(&self.center_account) {
+            problems.push(format!("CENTER_ACCOUNT is not an address: {:?}", e));
+        }
+        for (key, list) in [
+            ("SELLER_WHITELIST", &self.seller_whitelist),
+            ("SELLER_BLACKLIST", &self.seller_blacklist),
+        ] {
+            for address in list.iter().flatten() {
+                if public_key_from_bech32(address).is_err() {
+                    problems.push(format!("{} has {:?}, which is not an address", key, address));
+                }
+            }
+        }
+        if let (Some(min), Some(max)) = (self.list_sum_min, self.list_sum_max) {
+            if min > max {
+                problems.push(format!("LIST_SUM_MIN {} is above LIST_SUM_MAX {}", min, max));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.buy_probability) {
+            problems.push(format!("BUY_PROBABILITY {} is not within 0..1", self.buy_probability));
+        }
+        if self.balance_refresh_secs == 0 {
+            problems.push("BALANCE_REFRESH_SECS must be positive".to_string());
+        }
+        if self.buy_soft_cap_pct.map_or(false, |pct| pct > 100) {
+            problems.push("BUY_SOFT_CAP_PCT must be at most 100".to_string());
+        }
+        if self.max_tx_inputs.map_or(false, |max| max < 2) {
+            problems.push("MAX_TX_INPUTS must be at least 2".to_string());
+        }
+        if self.height_gap_warn > self.height_gap_max {
+            problems.push(format!(
+                "HEIGHT_GAP_WARN {} is above HEIGHT_GAP_MAX {}",
+                self.height_gap_warn, self.height_gap_max
+            ));
+        }
+        if self.mint_alert_pct > 100 {
+            problems.push("MINT_ALERT_PCT must be at most 100".to_string());
+        }
+        if self.mint_target_total.is_some() && self.mint_amount == 0 {
+            problems.push("MINT_AMOUNT must be positive with MINT_TARGET_TOTAL".to_string());
+        }
+        if self.auto_topup && self.topup_amount == 0 {
+            problems.push("TOPUP_AMOUNT must be positive with AUTO_TOPUP".to_string());
+        }
+        if let Some(address) = self.earnings_account.as_deref() {
+            if public_key_from_bech32(address).is_err() {
+                problems.push(format!("EARNINGS_ACCOUNT {:?} is not an address", address));
+            }
+        }
+        problems
+    }
+
+    /// Whether the buy cycle may take listings from `seller`, going by
+    /// SELLER_WHITELIST and SELLER_BLACKLIST.
+    pub fn buys_from(&self, seller: &str) -> bool {
+        self.seller_whitelist.as_ref().map_or(true, |list| list.contains(seller))
+            && !self.seller_blacklist.as_ref().map_or(false, |list| list.contains(seller))
+    }
+
+    /// Tasks that may want a connection at once: MAX_INFLIGHT_ACTIONS
+    /// submissions, plus the cycle loops running side by side.
+    pub fn concurrency(&self) -> usize {
+        let cycles = if self.concurrent_cycles { 3 } else { 1 };
+        self.max_inflight_actions + cycles
+    }
+
+    /// DB and RPC pool sizes: DB_MAX_CONNECTIONS and RPC_POOL_SIZE when set,
+    /// else enough for `concurrency`, with a DB connection to spare for the
+    /// confirmation check.
+    pub fn pool_sizes(&self) -> PoolSizes {
+        let concurrency = self.concurrency();
+        PoolSizes {
+            db: self.db_max_connections.unwrap_or(concurrency as u32 + 1),
+            rpc: self.rpc_pool_size.unwrap_or(concurrency),
+        }
+    }
+
+    /// The node's API endpoint, NODE_RPC on NODE_API_PORT.
+    pub fn node_api_url(&self) -> Result<String> {
+        node_url(&self.node_rpc_url, "NODE_API_PORT", &self.node_api_port)
+    }
+
+    /// The node's tendermint endpoint, NODE_RPC on NODE_TD_PORT.
+    pub fn node_td_url(&self) -> Result<String> {
+        node_url(&self.node_rpc_url, "NODE_TD_PORT", &self.node_td_port)
+    }
+
+    /// This config narrowed to `token`, with that token's own budget.
+    pub fn for_token(&self, token: &str) -> Self {
+        let mut config = self.clone();
+        config.token = token.to_string();
+        if let Some(budget) = self.token_budgets.get(token) {
+            config.buy_budget = Some(*budget);
+        }
+        config
+    }
+}
+
+/// Connection pool sizes in effect; see `Config::pool_sizes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSizes {
+    pub db: u32,
+    pub rpc: usize,
+}
+
+#[cfg(test)]
+impl Config {
+    pub fn for_tests(tokens: &[&str]) -> Self {
+        Self {
+            database_url: "postgres://localhost/test".to_string(),
+            database_replica_url: None,
+            token: tokens[0].to_string(),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            token_budgets: HashMap::new(),
+            token_decimals: 0,
+            ex_rpc_url: "http://127.0.0.1:1/".to_string(),
+            node_rpc_url: "http://127.0.0.1".to_string(),
+            node_api_port: "1".to_string(),
+            node_td_port: "1".to_string(),
+            center_account: String::new(),
+            list_sum_min: None,
+            list_sum_max: None,
+            empty_list_retries: 0,
+            metrics_addr: None,
+            balance_refresh_secs: 60,
+            partial_fills: false,
+            buy_tie_break: TieBreak::LargestFirst,
+            buy_budget: None,
+            buy_soft_cap_pct: None,
+            buy_target_amount: None,
+            warmup_ticks: 0,
+            buy_probability: 1.0,
+            buy_jitter_ms: 0,
+            buy_jitter_seed: None,
+            sorted_scan: false,
+            min_utxo_value: 0,
+            enable_panic_sell: false,
+            panic_drop_pct: 30.0,
+            panic_window_secs: 600,
+            mint_before_list: false,
+            mint_to_list_delay_secs: 0,
+            mint_confirm_timeout_secs: 60,
+            no_mint: false,
+            no_buy: false,
+            no_list: false,
+            active_window: None,
+            list_spread_bps: None,
+            max_pages_per_scan: None,
+            min_mint_interval_secs: 0,
+            list_undercut_tick: None,
+            sell_floor: None,
+            db_max_connections: None,
+            rpc_pool_size: None,
+            db_idle_timeout_secs: 300,
+            max_inflight_actions: 8,
+            fee_reserve: 0,
+            max_gen_accounts: 1000,
+            accounts_dir: ".".to_string(),
+            accounts_checksum_strict: false,
+            min_listings_to_buy: 0,
+            cycle_retry_budget: None,
+            balance_tolerance: None,
+            min_list_amount: 0,
+            max_listed_sum: None,
+            ma_window_secs: None,
+            strict_pagination: false,
+            max_actions_per_tick: None,
+            shuffle_accounts: false,
+            shuffle_seed: None,
+            startup_grace_secs: 0,
+            concurrent_cycles: false,
+            mint_target_total: None,
+            mint_amount: 1000,
+            reprice_drift_bps: None,
+            reprice_min_interval_secs: 600,
+            rpc_proxy: None,
+            empty_backoff_after: None,
+            empty_backoff_max_secs: 300,
+            mint_alert_pct: 80,
+            alert_webhook: None,
+            log_rpc_bodies: false,
+            rpc_log_max_bytes: 2048,
+            rpc_log_redact: true,
+            min_confirmations: 1,
+            action_queue: false,
+            per_account_rate_limit: None,
+            per_account_burst: 1,
+            floor_mode: FloorMode::Rotate,
+            max_tx_inputs: None,
+            consolidate_utxos: false,
+            split_strategy: SplitStrategy::Single,
+            scan_resume: false,
+            scan_resume_tolerance_pct: 10,
+            buy_confirm_scans: 1,
+            seller_whitelist: None,
+            seller_blacklist: None,
+            listing_ttl_secs: None,
+            account_start_spread_secs: None,
+            account_start_seed: None,
+            height_gap_warn: 3,
+            height_gap_max: 20,
+            scan_on_partial_failure: PartialFailure::Abort,
+            exchange: ExchangeKind::Findora,
+            min_abs_profit: None,
+            db_failure_policy: DbFailurePolicy::FailClosed,
+            min_economic_price: None,
+            max_open_listings: None,
+            auto_topup: false,
+            topup_min_balance: 0,
+            topup_amount: 0,
+            earnings_account: None,
+            listings_deadline_ms: None,
+        }
+    }
+}
+
+fn env_required(key: &str) -> Result<String> {
+    env::var(key).with_context(|| format!("{} is not set", key))
+}
+
+fn env_or<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(env_opt(key)?.unwrap_or(default))
+}
+
+fn env_opt<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(key) {
+        Ok(v) if !v.is_empty() => {
+            let value = v.parse::<T>().with_context(|| format!("parsing {}={:?}", key, v))?;
+            Ok(Some(value))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A set of addresses, listed in the variable separated by commas, or in the
+/// file it names after an `@`, one per line with `#` comments.
+fn env_addresses(key: &str) -> Result<Option<HashSet<String>>> {
+    let value = match env_opt::<String>(key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let addresses = match value.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("reading {} from {}", key, path))?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        None => value
+            .split(',')
+            .map(|address| address.trim().to_string())
+            .filter(|address| !address.is_empty())
+            .collect(),
+    };
+    Ok(Some(addresses))
+}
+
+/// Reads an amount in the form `parse_amount` takes.
+fn env_amount(key: &str, decimals: u32) -> Result<Option<u64>> {
+    match env::var(key) {
+        Ok(v) if !v.is_empty() => {
+            let value =
+                parse_amount(&v, decimals).with_context(|| format!("parsing {}={:?}", key, v))?;
+            Ok(Some(value))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Base units of `s`, written either as before in raw base units
+/// ("12300000") or, with a decimal point, in whole units of `decimals`
+/// places ("12.3"). Digits past `decimals` are refused, not rounded.
+pub fn parse_amount(s: &str, decimals: u32) -> Result<u64> {
+    let (whole, frac) = match s.split_once('.') {
+        Some(parts) => parts,
+        None => return s.parse().with_context(|| format!("{:?} is not an amount", s)),
+    };
+    let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(whole) || !is_digits(frac) {
+        return Err(Error::msg(format!("{:?} is not an amount", s)));
+    }
+    let frac = frac.trim_end_matches('0');
+    if frac.len() > decimals as usize {
+        return Err(Error::msg(format!("{:?} has more than {} decimals", s, decimals)));
+    }
+    let too_large = || Error::msg(format!("{:?} is too large", s));
+    let scale = 10u64.checked_pow(decimals).ok_or_else(too_large)?;
+    let frac = format!("{:0<width$}", frac, width = decimals as usize);
+    let frac: u64 = if frac.is_empty() { 0 } else { frac.parse()? };
+    whole
+        .parse::<u64>()
+        .ok()
+        .and_then(|whole| whole.checked_mul(scale))
+        .and_then(|units| units.checked_add(frac))
+        .ok_or_else(too_large)
+}
+
+/// `base` on `port`, unless it already names one; a different port there is
+/// an error rather than a guess. Any path is kept, with a trailing slash so
+/// endpoints join onto it.
+fn node_url(base: &str, port_key: &str, port: &str) -> Result<String> {
+    let mut url = Url::parse(base).with_context(|| format!("NODE_RPC {:?} is not a URL", base))?;
+    let port: u16 = port.parse().with_context(|| format!("{} {:?} is not a port", port_key, port))?;
+    match url.port() {
+        None => {
+            url.set_port(Some(port))
+                .map_err(|_| Error::msg(format!("NODE_RPC {} can't take a port", base)))?;
+        }
+        Some(embedded) if embedded == port => {}
+        Some(embedded) => {
+            return Err(Error::msg(format!(
+                "NODE_RPC {} already has port {}, which conflicts with {}={}",
+                redact_url(base),
+                embedded,
+                port_key,
+                port
+            )));
+        }
+    }
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url.to_string())
+}
+
+/// `url` with any user and password stripped, for logging.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            if !parsed.username().is_empty() || parsed.password().is_some() {
+                let _ = parsed.set_username("***");
+                let _ = parsed.set_password(None);
+            }
+            parsed.to_string()
+        }
+        Err(_) => "<unparsable url>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{node_url, parse_amount, redact_url, Config, PoolSizes};
+    use crate::utils::gen_accounts;
+    use anyhow::Result;
+
+    #[test]
+    fn test_redact_url() {
+        assert_eq!(
+            redact_url("postgres://bot:secret@db:5432/brc20"),
+            "postgres://***@db:5432/brc20"
+        );
+        assert_eq!(redact_url("https://api.example.org/"), "https://api.example.org/");
+        assert_eq!(redact_url("not a url"), "<unparsable url>");
+    }
+
+    #[test]
+    fn test_node_rpc_port_is_applied_once() -> Result<()> {
+        assert_eq!(node_url("http://host", "NODE_API_PORT", "8668")?, "http://host:8668/");
+        assert_eq!(node_url("http://host:1234", "NODE_API_PORT", "1234")?, "http://host:1234/");
+        assert_eq!(
+            node_url("http://host/path", "NODE_API_PORT", "8668")?,
+            "http://host:8668/path/"
+        );
+
+        let err = node_url("http://host:1234", "NODE_TD_PORT", "26657").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "NODE_RPC http://host:1234/ already has port 1234, which conflicts with NODE_TD_PORT=26657"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_sizes_follow_concurrency_unless_overridden() {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.max_inflight_actions = 8;
+        config.concurrent_cycles = true;
+        assert_eq!(config.pool_sizes(), PoolSizes { db: 12, rpc: 11 });
+
+        config.db_max_connections = Some(4);
+        config.rpc_pool_size = Some(32);
+        assert_eq!(config.pool_sizes(), PoolSizes { db: 4, rpc: 32 });
+    }
+
+    #[test]
+    fn test_problems_are_all_reported() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.center_account = gen_accounts(1)?[0].address.clone();
+        assert!(config.problems().is_empty());
+
+        config.ex_rpc_url = "ftp://example.org".to_string();
+        config.list_sum_min = Some(10);
+        config.list_sum_max = Some(5);
+        config.balance_refresh_secs = 0;
+        assert_eq!(config.problems().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimal_amounts() -> Result<()> {
+        assert_eq!(parse_amount("12.3", 6)?, 12_300_000);
+        assert_eq!(parse_amount("12.300", 1)?, 123);
+        // no decimal point: raw base units, as before
+        assert_eq!(parse_amount("12", 6)?, 12);
+
+        let err = parse_amount("12.3456789", 6).unwrap_err();
+        assert_eq!(err.to_string(), "\"12.3456789\" has more than 6 decimals");
+        assert!(parse_amount("12.3", 0).is_err());
+        assert!(parse_amount("1.2.3", 6).is_err());
+        assert!(parse_amount(".5", 6).is_err());
+        assert!(parse_amount("18446744073709.551616", 6).is_err());
+        Ok(())
+    }
+}
@@ -1,28 +1,499 @@
-use crate::types::FraAccount;
+use crate::types::{FraAccount, QueuedAction, Trade};
+use crate::utils::now_secs;
 use anyhow::Result;
-use sqlx::PgPool;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use sqlx::{Executor, PgPool};
+use std::fmt::Debug;
+
+/// The tables the bot uses. Every statement is `if not exists`, so it can be
+/// applied to a database that already has them.
+pub const SCHEMA: &str = include_str!("../schema.sql");
+
+/// Outcome of an `insert_accounts` batch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccountInsert {
+    pub inserted: usize,
+    /// Already stored under a different type, which was overwritten.
+    pub updated: usize,
+    /// Already stored with the same type.
+    pub skipped: usize,
+}
+
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+    /// Applies [`SCHEMA`]; safe to re-run.
+    async fn migrate(&self) -> Result<()>;
+
+    /// Upserts `accounts` under `ty`; safe to re-run with the same batch.
+    async fn insert_accounts(&self, ty: i32, accounts: &[FraAccount]) -> Result<AccountInsert>;
+
+    /// Every stored account as `(address, ty)`.
+    async fn list_accounts(&self) -> Result<Vec<(String, i32)>>;
+
+    async fn record_trade(&self, trade: &Trade) -> Result<()>;
+
+    /// Mints recorded for `address`, over every token.
+    async fn account_mint_count(&self, address: &str) -> Result<u64>;
+
+    /// Txids of the recorded trades not yet confirmed on chain; cancels
+    /// have none.
+    async fn pending_trades(&self) -> Result<Vec<String>>;
+
+    async fn confirm_trade(&self, txid: &str) -> Result<()>;
+
+    /// Trades recorded at or after `since` (unix seconds), oldest first.
+    fn recent_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>>;
+
+    /// [`recent_trades`](Self::recent_trades) for the reports, which may read
+    /// a replica and miss the latest trades. Trading decisions use
+    /// `recent_trades`.
+    fn report_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>>;
+
+    async fn load_cursor(&self, name: &str) -> Result<Option<i64>>;
+
+    async fn save_cursor(&self, name: &str, value: i64) -> Result<()>;
+
+    /// Tx hash recorded for an idempotency key, if it was ever submitted.
+    async fn load_action(&self, key: &str) -> Result<Option<String>>;
+
+    async fn save_action(&self, key: &str, txid: &str) -> Result<()>;
+
+    /// Forgets an idempotency key whose transaction the node refused.
+    async fn delete_action(&self, key: &str) -> Result<()>;
+
+    /// Persists `action` of `token` and returns its queue id.
+    async fn enqueue_action(&self, token: &str, action: &QueuedAction) -> Result<i64>;
+
+    /// Queued actions of `token` that never finished, oldest first.
+    async fn queued_actions(&self, token: &str) -> Result<Vec<(i64, QueuedAction)>>;
+
+    async fn finish_action(&self, id: i64) -> Result<()>;
+}
 
 #[derive(Debug)]
-pub struct Storage {
+pub struct PgStorage {
     pool: PgPool,
+    /// Serves the reporting reads, `list_accounts` and `report_trades`, so
+    /// they don't queue behind the trading loop's writes.
+    replica: PgPool,
 }
 
-impl Storage {
+impl PgStorage {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self {
+            replica: pool.clone(),
+            pool,
+        }
+    }
+
+    /// Sends reporting reads to `replica`; they may trail the primary by the
+    /// replication lag.
+    pub fn new_with_replica(primary: PgPool, replica: PgPool) -> Self {
+        Self {
+            pool: primary,
+            replica,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PgStorage {
+    async fn migrate(&self) -> Result<()> {
+        // without binds this goes out as one simple query, statements and all
+        self.pool.execute(SCHEMA).await?;
+
+        Ok(())
+    }
+
+    async fn insert_accounts(&self, ty: i32, accounts: &[FraAccount]) -> Result<AccountInsert> {
+        let mut report = AccountInsert::default();
+        for account in accounts {
+            // No row comes back when the stored type already matches; `xmax`
+            // is 0 only for a freshly inserted row.
+            let row: Option<(bool,)> = sqlx::query_as(
+                "INSERT INTO brc20_accounts VALUES($1,$2) ON CONFLICT(address) DO UPDATE SET ty=$2 WHERE brc20_accounts.ty<>$2 RETURNING (xmax = 0)",
+            )
+            .bind(&account.address)
+            .bind(ty)
+            .fetch_optional(&self.pool)
+            .await?;
+            match row {
+                Some((true,)) => report.inserted += 1,
+                Some((false,)) => report.updated += 1,
+                None => report.skipped += 1,
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<(String, i32)>> {
+        let rows: Vec<(String, i32)> = sqlx::query_as("SELECT address,ty FROM brc20_accounts")
+            .fetch_all(&self.replica)
+            .await?;
+
+        Ok(rows)
+    }
+
+    async fn record_trade(&self, trade: &Trade) -> Result<()> {
+        sqlx::query("INSERT INTO brc20_trades(action,token,address,amount,price,txid,create_time) VALUES($1,$2,$3,$4,$5,$6,$7)")
+            .bind(trade.action.as_str())
+            .bind(&trade.token)
+            .bind(&trade.address)
+            .bind(trade.amount as i64)
+            .bind(trade.price as i64)
+            .bind(&trade.txid)
+            .bind(trade.create_time)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn account_mint_count(&self, address: &str) -> Result<u64> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM brc20_trades WHERE action='mint' AND address=$1")
+                .bind(address)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn pending_trades(&self) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT DISTINCT txid FROM brc20_trades WHERE NOT confirmed AND action<>'cancel'",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|(txid,)| txid).collect())
+    }
+
+    async fn confirm_trade(&self, txid: &str) -> Result<()> {
+        sqlx::query("UPDATE brc20_trades SET confirmed=true WHERE txid=$1")
+            .bind(txid)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    fn recent_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>> {
+        trades_since(&self.pool, since)
+    }
+
+    fn report_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>> {
+        trades_since(&self.replica, since)
     }
 
-    pub async fn insert_accounts(&self, ty: i32, accounts: &Vec<FraAccount>) -> Result<()> {
-        if !accounts.is_empty() {
+    async fn load_cursor(&self, name: &str) -> Result<Option<i64>> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT value FROM brc20_cursors WHERE name=$1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|(value,)| value))
+    }
+
+    async fn save_cursor(&self, name: &str, value: i64) -> Result<()> {
+        sqlx::query("INSERT INTO brc20_cursors VALUES($1,$2) ON CONFLICT(name) DO UPDATE SET value=$2")
+            .bind(name)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn load_action(&self, key: &str) -> Result<Option<String>> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT txid FROM brc20_actions WHERE key=$1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|(txid,)| txid))
+    }
+
+    async fn save_action(&self, key: &str, txid: &str) -> Result<()> {
+        sqlx::query("INSERT INTO brc20_actions VALUES($1,$2,$3) ON CONFLICT(key) DO NOTHING")
+            .bind(key)
+            .bind(txid)
+            .bind(now_secs())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_action(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM brc20_actions WHERE key=$1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn enqueue_action(&self, token: &str, action: &QueuedAction) -> Result<i64> {
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO brc20_queue(token,action,create_time) VALUES($1,$2,$3) RETURNING id",
+        )
+        .bind(token)
+        .bind(serde_json::to_string(action)?)
+        .bind(now_secs())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    async fn queued_actions(&self, token: &str) -> Result<Vec<(i64, QueuedAction)>> {
+        let rows: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id,action FROM brc20_queue WHERE token=$1 ORDER BY id")
+                .bind(token)
+                .fetch_all(&self.pool)
+                .await?;
+
+        rows.into_iter()
+            .map(|(id, action)| Ok((id, serde_json::from_str(&action)?)))
+            .collect()
+    }
+
+    async fn finish_action(&self, id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM brc20_queue WHERE id=$1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Stream of the trades in `pool` recorded at or after `since`, oldest first.
+fn trades_since(pool: &PgPool, since: i64) -> BoxStream<'_, Result<Trade>> {
+    sqlx::query_as::<_, (String, String, String, i64, i64, String, i64)>(
+        "SELECT action,token,address,amount,price,txid,create_time FROM brc20_trades WHERE create_time>=$1 ORDER BY create_time",
+    )
+    .bind(since)
+    .fetch(pool)
+    .map(|row| -> Result<Trade> {
+        let (action, token, address, amount, price, txid, create_time) = row?;
+        Ok(Trade {
+            action: action.parse()?,
+            token,
+            address,
+            amount: amount as u64,
+            price: price as u64,
+            txid,
+            create_time,
+        })
+    })
+    .boxed()
+}
+
+/// In-process storage for tests and offline commands such as `replay`.
+pub mod mem {
+    use super::{AccountInsert, StorageBackend};
+    use crate::types::{FraAccount, QueuedAction, Trade, TradeAction};
+    use anyhow::{Error, Result};
+    use async_trait::async_trait;
+    use futures::stream::{self, BoxStream};
+    use futures::StreamExt;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    pub struct MemStorage {
+        pub accounts: Mutex<HashMap<String, i32>>,
+        pub trades: Mutex<Vec<Trade>>,
+        /// Txids `confirm_trade` has marked.
+        pub confirmed: Mutex<HashSet<String>>,
+        pub cursors: Mutex<HashMap<String, i64>>,
+        pub actions: Mutex<HashMap<String, String>>,
+        /// `(id, token, action)` in queue order.
+        pub queue: Mutex<Vec<(i64, String, QueuedAction)>>,
+        /// While set, trades can't be recorded, as if the database were gone.
+        pub down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl StorageBackend for MemStorage {
+        async fn migrate(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn insert_accounts(&self, ty: i32, accounts: &[FraAccount]) -> Result<AccountInsert> {
+            let mut stored = self.accounts.lock().unwrap();
+            let mut report = AccountInsert::default();
             for account in accounts {
-                sqlx::query("INSERT INTO brc20_accounts VALUES($1,$2) ON CONFLICT(address) DO UPDATE SET ty=$2")
-                    .bind(&account.address)
-                    .bind(ty)
-                    .execute(&self.pool)
-                    .await?;
+                match stored.insert(account.address.clone(), ty) {
+                    None => report.inserted += 1,
+                    Some(prev) if prev != ty => report.updated += 1,
+                    Some(_) => report.skipped += 1,
+                }
+            }
+            Ok(report)
+        }
+
+        async fn list_accounts(&self) -> Result<Vec<(String, i32)>> {
+            let stored = self.accounts.lock().unwrap();
+            Ok(stored.iter().map(|(a, ty)| (a.clone(), *ty)).collect())
+        }
+
+        async fn record_trade(&self, trade: &Trade) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(Error::msg("database unavailable"));
+            }
+            self.trades.lock().unwrap().push(trade.clone());
+            Ok(())
+        }
+
+        async fn account_mint_count(&self, address: &str) -> Result<u64> {
+            let trades = self.trades.lock().unwrap();
+            Ok(trades
+                .iter()
+                .filter(|trade| trade.action == TradeAction::Mint && trade.address == address)
+                .count() as u64)
+        }
+
+        async fn pending_trades(&self) -> Result<Vec<String>> {
+            let confirmed = self.confirmed.lock().unwrap();
+            let mut pending: Vec<String> = vec![];
+            for trade in self.trades.lock().unwrap().iter() {
+                if trade.action != TradeAction::Cancel
+                    && !confirmed.contains(&trade.txid)
+                    && !pending.contains(&trade.txid)
+                {
+                    pending.push(trade.txid.clone());
+                }
             }
+            Ok(pending)
         }
 
+        async fn confirm_trade(&self, txid: &str) -> Result<()> {
+            self.confirmed.lock().unwrap().insert(txid.to_string());
+            Ok(())
+        }
+
+        fn recent_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>> {
+            let trades: Vec<Result<Trade>> = self
+                .trades
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|trade| trade.create_time >= since)
+                .cloned()
+                .map(Ok)
+                .collect();
+            stream::iter(trades).boxed()
+        }
+
+        fn report_trades(&self, since: i64) -> BoxStream<'_, Result<Trade>> {
+            self.recent_trades(since)
+        }
+
+        async fn load_cursor(&self, name: &str) -> Result<Option<i64>> {
+            Ok(self.cursors.lock().unwrap().get(name).copied())
+        }
+
+        async fn save_cursor(&self, name: &str, value: i64) -> Result<()> {
+            self.cursors.lock().unwrap().insert(name.to_string(), value);
+            Ok(())
+        }
+
+        async fn load_action(&self, key: &str) -> Result<Option<String>> {
+            Ok(self.actions.lock().unwrap().get(key).cloned())
+        }
+
+        async fn save_action(&self, key: &str, txid: &str) -> Result<()> {
+            self.actions
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_insert_with(|| txid.to_string());
+            Ok(())
+        }
+
+        async fn delete_action(&self, key: &str) -> Result<()> {
+            self.actions.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn enqueue_action(&self, token: &str, action: &QueuedAction) -> Result<i64> {
+            let mut queue = self.queue.lock().unwrap();
+            let id = queue.last().map_or(1, |(id, _, _)| id + 1);
+            queue.push((id, token.to_string(), action.clone()));
+            Ok(id)
+        }
+
+        async fn queued_actions(&self, token: &str) -> Result<Vec<(i64, QueuedAction)>> {
+            let queue = self.queue.lock().unwrap();
+            Ok(queue
+                .iter()
+                .filter(|(_, queued_for, _)| queued_for == token)
+                .map(|(id, _, action)| (*id, action.clone()))
+                .collect())
+        }
+
+        async fn finish_action(&self, id: i64) -> Result<()> {
+            self.queue.lock().unwrap().retain(|(queued, _, _)| *queued != id);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::mem::MemStorage;
+    use crate::db::{AccountInsert, StorageBackend, SCHEMA};
+    use crate::utils::gen_accounts;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_mem_storage_cursor() -> Result<()> {
+        let storage = MemStorage::default();
+        assert_eq!(storage.load_cursor("price_index").await?, None);
+        storage.save_cursor("price_index", 3).await?;
+        assert_eq!(storage.load_cursor("price_index").await?, Some(3));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_insert_accounts_counts() -> Result<()> {
+        let storage = MemStorage::default();
+        let accounts = gen_accounts(4)?;
+        storage.insert_accounts(1, &accounts[..2]).await?;
+        storage.insert_accounts(2, &accounts[2..3]).await?;
+
+        let report = storage.insert_accounts(1, &accounts).await?;
+        assert_eq!(
+            report,
+            AccountInsert {
+                inserted: 1,
+                updated: 1,
+                skipped: 2,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_can_be_reapplied() {
+        let statements: Vec<&str> =
+            SCHEMA.split(';').map(str::trim).filter(|s| !s.is_empty()).collect();
+        assert!(!statements.is_empty());
+        for statement in statements {
+            let statement = statement.to_lowercase();
+            assert!(
+                statement.contains("create table if not exists")
+                    || statement.contains("add column if not exists"),
+                "not re-runnable: {}",
+                statement
+            );
+        }
+    }
 }
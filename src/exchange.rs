@@ -0,0 +1,194 @@
+use crate::types::{FraAccount, ListItem, ListQuery, ListResponse, Rpc};
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Which marketplace the bot trades on, from EXCHANGE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeKind {
+    /// The Findora BRC-20 exchange at EX_RPC.
+    Findora,
+}
+
+impl ExchangeKind {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "findora" => Ok(Self::Findora),
+            other => Err(Error::msg(format!("EXCHANGE must be findora, not {:?}", other))),
+        }
+    }
+
+    /// The adapter for this kind, talking through `rpc`.
+    pub fn adapter(&self, rpc: Arc<Rpc>) -> Arc<dyn Exchange> {
+        match self {
+            Self::Findora => Arc::new(FindoraExchange { rpc }),
+        }
+    }
+}
+
+/// The marketplace side of trading. Transactions are still built and sent
+/// through the node; an exchange only sees them by txid.
+#[async_trait]
+pub trait Exchange: Debug + Send + Sync {
+    /// One page of the open listings of `token`.
+    async fn get_token_list(
+        &self,
+        token: &str,
+        page: i32,
+        page_size: i32,
+        query: &ListQuery,
+    ) -> Result<ListResponse>;
+
+    /// Registers a listing whose escrow transfer `txid` was already sent.
+    async fn create_listing(
+        &self,
+        account: &FraAccount,
+        token: &str,
+        amount: u64,
+        price: u64,
+        txid: &str,
+    ) -> Result<()>;
+
+    /// Reports the payment `txid` for `amount` tokens of `item`.
+    async fn buy_listing(
+        &self,
+        account: &FraAccount,
+        item: &ListItem,
+        amount: u64,
+        partial: bool,
+        txid: &str,
+    ) -> Result<()>;
+
+    async fn cancel_listing(&self, account: &FraAccount, id: i32) -> Result<()>;
+}
+
+/// The exchange this bot was written for, reached through `Rpc`.
+#[derive(Debug)]
+pub struct FindoraExchange {
+    rpc: Arc<Rpc>,
+}
+
+#[async_trait]
+impl Exchange for FindoraExchange {
+    async fn get_token_list(
+        &self,
+        token: &str,
+        page: i32,
+        page_size: i32,
+        query: &ListQuery,
+    ) -> Result<ListResponse> {
+        self.rpc.get_token_list_sorted(token, page, page_size, query).await
+    }
+
+    async fn create_listing(
+        &self,
+        account: &FraAccount,
+        token: &str,
+        amount: u64,
+        price: u64,
+        txid: &str,
+    ) -> Result<()> {
+        self.rpc.confirm_listing(account, token, amount, price, txid).await
+    }
+
+    async fn buy_listing(
+        &self,
+        account: &FraAccount,
+        item: &ListItem,
+        amount: u64,
+        partial: bool,
+        txid: &str,
+    ) -> Result<()> {
+        self.rpc.confirm_buy(account, item, amount, partial, txid).await
+    }
+
+    async fn cancel_listing(&self, account: &FraAccount, id: i32) -> Result<()> {
+        self.rpc.cancel_listing(account, id).await
+    }
+}
+
+/// An in-process exchange for tests.
+#[cfg(test)]
+pub mod mock {
+    use super::Exchange;
+    use crate::types::{FraAccount, ListItem, ListQuery, ListResponse};
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Debug, Default)]
+    pub struct MockExchange {
+        /// Open listings, in book order.
+        pub book: Mutex<Vec<ListItem>>,
+        /// `(action, listing id or txid)` per call that changed something.
+        pub calls: Mutex<Vec<(&'static str, String)>>,
+        /// How long each page takes to come back.
+        pub page_delay: Duration,
+    }
+
+    impl MockExchange {
+        pub fn with_book(book: Vec<ListItem>) -> Self {
+            Self {
+                book: Mutex::new(book),
+                ..Self::default()
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Exchange for MockExchange {
+        async fn get_token_list(
+            &self,
+            _token: &str,
+            page: i32,
+            page_size: i32,
+            _query: &ListQuery,
+        ) -> Result<ListResponse> {
+            tokio::time::sleep(self.page_delay).await;
+            let book = self.book.lock().unwrap();
+            let start = ((page - 1) * page_size).max(0) as usize;
+            let items: Vec<ListItem> =
+                book.iter().skip(start).take(page_size as usize).cloned().collect();
+            Ok(ListResponse {
+                total: book.len() as i32,
+                current_page: page,
+                page_size,
+                total_pages: (book.len() as i32 + page_size - 1) / page_size,
+                data: Some(items),
+            })
+        }
+
+        async fn create_listing(
+            &self,
+            _account: &FraAccount,
+            _token: &str,
+            _amount: u64,
+            _price: u64,
+            txid: &str,
+        ) -> Result<()> {
+            self.calls.lock().unwrap().push(("list", txid.to_string()));
+            Ok(())
+        }
+
+        async fn buy_listing(
+            &self,
+            _account: &FraAccount,
+            item: &ListItem,
+            _amount: u64,
+            _partial: bool,
+            txid: &str,
+        ) -> Result<()> {
+            self.book.lock().unwrap().retain(|listed| listed.id != item.id);
+            self.calls.lock().unwrap().push(("buy", txid.to_string()));
+            Ok(())
+        }
+
+        async fn cancel_listing(&self, _account: &FraAccount, id: i32) -> Result<()> {
+            self.book.lock().unwrap().retain(|listed| listed.id != id);
+            self.calls.lock().unwrap().push(("cancel", id.to_string()));
+            Ok(())
+        }
+    }
+}
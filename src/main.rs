@@ -1,71 +1,427 @@
+mod commands;
+mod config;
 mod db;
+mod exchange;
+mod metrics;
 mod platform;
 mod robot;
+mod strategy;
+mod submit;
 mod types;
 mod utils;
+mod wallet;
 
-use crate::db::Storage;
-use crate::types::{FraAccount, ListResponse, Rpc};
-use anyhow::Result;
-use clap::Parser;
+use crate::commands::Output;
+use crate::config::{redact_url, Config};
+use crate::db::mem::MemStorage;
+use crate::db::{PgStorage, StorageBackend};
+use crate::exchange::Exchange;
+use crate::metrics::Metrics;
+use crate::strategy::{
+    order_book, BotState, BuyOrder, BuyStrategy, FloorStrategy, MovingAverageFloor,
+};
+use crate::submit::{idempotency_key, next_nonce, settle_nonce, submit_once, with_permit};
+use crate::types::{
+    checked_cost, FraAccount, ListItem, ListQuery, ListResponse, QueuedAction, Rpc, Trade,
+    TradeAction,
+};
+use crate::wallet::Wallet;
+use anyhow::{Context, Error, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use env_logger::Target;
-use log::info;
-use serde_json::from_str;
+use futures::StreamExt;
+use globutils::wallet::public_key_from_bech32;
+use ledger::data_model::TX_FEE_MIN_V1;
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use sqlx::pool::PoolOptions;
-use sqlx::{PgPool, Pool, Postgres};
-use std::io::Read;
-use std::sync::Arc;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{env, io};
-use std::{fs::File, io::Write};
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinSet};
 use tokio::time::interval;
 use tokio::{runtime, time};
-use utils::gen_accounts;
+use tracing::{debug, info_span, instrument, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use utils::{
+    clamp_list_price, compute_list_price, gen_accounts, now_secs, plan_listings, plan_topups,
+    reconcile_balance, resale_profit, retry_io, retry_io_with, scan_pages, shuffle_accounts,
+    spend_retry, spendable, start_offsets, supply_action, take_actions, wait_until, with_action_cap,
+    with_retry_budget, AccountLocks, AccountRateLimit, ActiveWindow, BuyJitter, DbFailurePolicy,
+    DipDampener, EmptyBackoff, FloorWindow, PageDedup, PartialFailure, ScanCheckpoint,
+    SplitStrategy, SupplyAction,
+};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(long, default_value_t = 10)]
     accounts: i32,
+    /// Subcommand output format
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    output: Output,
+    /// Don't mint, same as NO_MINT=true
+    #[arg(long)]
+    no_mint: bool,
+    /// Don't buy, same as NO_BUY=true
+    #[arg(long)]
+    no_buy: bool,
+    /// Don't list or cancel, same as NO_LIST=true
+    #[arg(long)]
+    no_list: bool,
+    /// Load or generate the accounts, apply schema.sql, store the accounts in
+    /// the DB, then exit
+    #[arg(long)]
+    prepare_only: bool,
+    /// Dotenv file to load first; `.env` still fills in anything it leaves out
+    #[arg(long, global = true)]
+    env_file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Top up mint/buy accounts from a treasury account
+    Fund {
+        /// Treasury account mnemonic. It shows up in `ps` and shell history;
+        /// prefer --from-file or TREASURY_MNEMONIC
+        #[arg(long)]
+        from: Option<String>,
+        /// File holding the treasury mnemonic
+        #[arg(long, conflicts_with = "from")]
+        from_file: Option<String>,
+        /// FRA sent to each account, in base units
+        #[arg(long)]
+        amount: u64,
+        /// Only accounts below this balance are funded [default: amount]
+        #[arg(long)]
+        threshold: Option<u64>,
+    },
+    /// Write recorded trades to a CSV file
+    ExportTrades {
+        #[arg(long, default_value = "trades.csv")]
+        out: String,
+        /// Earliest trade date, YYYY-MM-DD (UTC)
+        #[arg(long, default_value = "1970-01-01")]
+        since: String,
+    },
+    /// Cancel every listing the bot recorded placing. Listings are matched to
+    /// the recorded list trades by seller, amount and price, since the
+    /// exchange gives no listing ids; one placed by hand from a bot account
+    /// with the same amount and price as a filled bot listing is cancelled too
+    CancelAll {
+        /// Skip the confirmation prompt
+        #[arg(long)]
+        force: bool,
+    },
+    /// Time generating accounts without saving them
+    BenchGen {
+        #[arg(long)]
+        count: i32,
+    },
+    /// Print what the cycles would do with the live book, without acting
+    Status,
+    /// Print the version, commit and build time of this binary
+    Version,
+    /// Check the configuration without connecting to the DB or RPCs
+    ValidateConfig,
+    /// Print what the list and buy cycles would do with a saved book
+    Replay {
+        /// ListResponse JSON, as returned by the exchange's list endpoint
+        #[arg(long)]
+        file: String,
+        /// Buy floor [default: the first floor price used after startup]
+        #[arg(long)]
+        floor: Option<u64>,
+        /// Price for created listings [default: floor]
+        #[arg(long)]
+        list_price: Option<u64>,
+    },
+    /// Estimate when the mint and buy targets are met at the recent pace
+    Eta {
+        /// How far back the pace is measured
+        #[arg(long, default_value_t = 6)]
+        window_hours: u64,
+    },
+    /// Check that the node and the exchange are at the same chain height
+    Diagnose,
+    /// Write recorded trades as timestamped OpenMetrics, to backfill Prometheus
+    ReplayMetrics {
+        #[arg(long, default_value = "trades.om")]
+        out: String,
+        /// Earliest trade date, YYYY-MM-DD (UTC)
+        #[arg(long, default_value = "1970-01-01")]
+        since: String,
+    },
+    /// Compare the account files with the accounts table
+    VerifyAccounts {
+        /// Insert accounts that are in the files but not in the DB
+        #[arg(long)]
+        fix: bool,
+    },
 }
 
 const ACCOUNT_MINT: &'static str = "accounts-mint.txt";
 const ACCOUNT_BUY: &'static str = "accounts-buy.txt";
-const MINT_LIMIT: usize = 7;
+const MINT_LIMIT: u64 = 7;
 const ACCOUNT_TYPE_MINT: i32 = 1;
 const ACCOUNT_TYPE_BUY: i32 = 2;
+const CURSOR_PRICE_INDEX: &'static str = "price_index";
+const CURSOR_SPENT: &'static str = "spent";
+const CURSOR_BOUGHT: &'static str = "bought";
+const CURSOR_MINTED: &'static str = "minted";
+const CURSOR_LAST_MINT: &'static str = "last_mint";
+const CURSOR_SHUFFLE_SEED: &'static str = "shuffle_seed";
+const CURSOR_SCAN_PAGE: &'static str = "scan_page";
+const CURSOR_SCAN_SUM: &'static str = "scan_sum";
+const CURSOR_SCAN_TOTAL: &'static str = "scan_total";
+const CURSOR_START_OFFSET: &'static str = "start_offset";
+const CURSOR_OPEN_LISTINGS: &'static str = "open_listings";
+const EMPTY_LIST_RETRY_DELAY: Duration = Duration::from_millis(500);
+const FILE_IO_ATTEMPTS: u32 = 4;
+const FILE_IO_BACKOFF: Duration = Duration::from_millis(250);
+const PAGE_SIZE: i32 = 50;
+/// Cheapest listings shown per token by `status`.
+const STATUS_CHEAPEST: usize = 5;
+/// Times a listing repriced between reads is re-read before the buy gives up.
+const BUY_REPRICE_RETRIES: usize = 2;
+const FLOOR_PRICES: [u64; 6] = [
+    123000000, 250000000, 450000000, 200000000, 220000000, 300000000,
+];
+const DB_MAX_LIFETIME: Duration = Duration::from_secs(30 * 60);
+const DB_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(10);
+const MINT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const LIST_INTERVAL: Duration = Duration::from_secs(5);
+const BUY_INTERVAL: Duration = Duration::from_secs(10);
+const MINT_INTERVAL: Duration = Duration::from_secs(10);
+const REPRICE_INTERVAL: Duration = Duration::from_secs(60);
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What the cycles would do with one book; see `BotServer::plan`.
+#[derive(Debug, Serialize)]
+struct ReplayPlan {
+    listed_sum: u64,
+    sum_min: Option<u64>,
+    sum_max: Option<u64>,
+    floor_price: u64,
+    supply: String,
+    list_amount: Option<u64>,
+    list_price: Option<u64>,
+    /// Own listings the list cycle would cancel, by id.
+    cancels: Vec<i32>,
+    /// `(listing id, price, quantity)` the buy cycle would take, jitter aside.
+    buys: Vec<(i32, u64, u64)>,
+}
+
+impl ReplayPlan {
+    fn describe(&self, tag: &str, listings: usize) -> String {
+        let mut text = format!(
+            "[{}] {} listings, sum {} (min {:?}, max {:?}): {}",
+            tag, listings, self.listed_sum, self.sum_min, self.sum_max, self.supply
+        );
+        if let (Some(amount), Some(price)) = (self.list_amount, self.list_price) {
+            text.push_str(&format!("\n[{}] would list {} at {}", tag, amount, price));
+        }
+        for id in self.cancels.iter() {
+            text.push_str(&format!("\n[{}] would cancel listing {}", tag, id));
+        }
+        for (id, price, quantity) in self.buys.iter() {
+            text.push_str(&format!(
+                "\n[{}] would buy {} of listing {} at {} (floor {})",
+                tag, quantity, id, price, self.floor_price
+            ));
+        }
+        if self.buys.is_empty() {
+            text.push_str(&format!("\n[{}] would buy nothing under {}", tag, self.floor_price));
+        }
+        text
+    }
+}
+
+/// A book fetched by `get_all_token_listings`.
+#[derive(Debug)]
+struct Listings {
+    items: Vec<ListItem>,
+    /// Whether LISTINGS_DEADLINE_MS cut the scan short, so later pages are
+    /// missing and anything derived from the whole book is unreliable.
+    truncated: bool,
+}
 
 #[derive(Debug)]
 struct BotServer {
-    storage: Arc<Storage>,
+    config: Config,
+    storage: Arc<dyn StorageBackend>,
     accounts_mint: Vec<FraAccount>,
     accounts_buy: Vec<FraAccount>,
     rpc: Arc<Rpc>,
+    exchange: Arc<dyn Exchange>,
+    /// Pays the top-ups; the node behind `rpc` outside tests.
+    wallet: Arc<dyn Wallet>,
+    metrics: Arc<Metrics>,
+    inflight: Arc<Semaphore>,
+    buy_account_index: AtomicUsize,
+    list_account_index: AtomicUsize,
+    ticks: AtomicU64,
+    /// List and buy ticks so far, the ones WARMUP_TICKS counts.
+    observed_ticks: AtomicU64,
+    spent: AtomicU64,
+    bought: AtomicU64,
+    minted: AtomicU64,
+    /// Cheapest ask of the last scanned book; 0 until one is seen.
+    market_price: AtomicU64,
+    /// Whether MIN_ECONOMIC_PRICE has paused minting and listing.
+    uneconomic: AtomicBool,
+    jitter: Mutex<BuyJitter>,
+    scans: AtomicUsize,
+    floors: Mutex<FloorWindow>,
+    dampener: Mutex<DipDampener>,
+    list_backoff: Mutex<EmptyBackoff>,
+    buy_backoff: Mutex<EmptyBackoff>,
+    panicked: AtomicBool,
+    strategy: Mutex<Box<dyn BuyStrategy>>,
+    account_locks: Arc<AccountLocks>,
+    account_rates: Arc<AccountRateLimit>,
+    started_at: i64,
+    /// ACCOUNT_START_SPREAD_SECS offset per address; see `assign_start_offsets`.
+    start_offsets: Mutex<HashMap<String, u64>>,
+    /// Trades the database refused, oldest first, waiting to be written.
+    unflushed: Mutex<Vec<Trade>>,
 }
 
 impl BotServer {
     pub fn new(
-        pool: PgPool,
-        rpc: Rpc,
+        config: Config,
+        storage: Arc<dyn StorageBackend>,
+        rpc: Arc<Rpc>,
         accounts_mint: Vec<FraAccount>,
         accounts_buy: Vec<FraAccount>,
     ) -> Result<Self> {
+        let jitter = BuyJitter::new(
+            config.buy_probability,
+            Duration::from_millis(config.buy_jitter_ms),
+            config.buy_jitter_seed,
+        );
+        let floors = FloorWindow::new(Duration::from_secs(config.panic_window_secs));
+        let dampener = DipDampener::new(config.buy_confirm_scans);
+        let exchange = config.exchange.adapter(rpc.clone());
+        let wallet: Arc<dyn Wallet> = rpc.clone();
+        let backoff = |interval: Duration| {
+            let max_skip = (config.empty_backoff_max_secs / interval.as_secs().max(1)).max(1) - 1;
+            Mutex::new(EmptyBackoff::new(config.empty_backoff_after, max_skip as u32))
+        };
+        let list_backoff = backoff(LIST_INTERVAL);
+        let buy_backoff = backoff(BUY_INTERVAL);
+        let inflight = Arc::new(Semaphore::new(config.max_inflight_actions));
+        let account_rates = AccountRateLimit::new(
+            config.per_account_rate_limit,
+            config.per_account_burst,
+        );
         Ok(Self {
-            storage: Arc::new(Storage::new(pool)),
+            config,
+            storage,
             accounts_mint,
             accounts_buy,
-            rpc: Arc::new(rpc),
+            rpc,
+            exchange,
+            wallet,
+            metrics: Arc::new(Metrics::default()),
+            inflight,
+            buy_account_index: AtomicUsize::new(0),
+            list_account_index: AtomicUsize::new(0),
+            ticks: AtomicU64::new(0),
+            observed_ticks: AtomicU64::new(0),
+            spent: AtomicU64::new(0),
+            bought: AtomicU64::new(0),
+            minted: AtomicU64::new(0),
+            market_price: AtomicU64::new(0),
+            uneconomic: AtomicBool::new(false),
+            jitter: Mutex::new(jitter),
+            scans: AtomicUsize::new(0),
+            floors: Mutex::new(floors),
+            dampener: Mutex::new(dampener),
+            list_backoff,
+            buy_backoff,
+            panicked: AtomicBool::new(false),
+            strategy: Mutex::new(Box::new(FloorStrategy)),
+            account_locks: Arc::new(AccountLocks::default()),
+            account_rates: Arc::new(account_rates),
+            started_at: now_secs(),
+            start_offsets: Mutex::new(HashMap::new()),
+            unflushed: Mutex::new(vec![]),
         })
     }
 
-    pub async fn prepare_accounts(&self) -> Result<()> {
-        self.storage
-            .insert_accounts(ACCOUNT_TYPE_MINT, &self.accounts_mint)
-            .await?;
+    /// Shares one registry between the servers of a multi-token process.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 
-        self.storage
-            .insert_accounts(ACCOUNT_TYPE_BUY, &self.accounts_buy)
-            .await?;
+    /// Shares the MAX_INFLIGHT_ACTIONS permits between the servers of a
+    /// multi-token process.
+    pub fn with_inflight(mut self, inflight: Arc<Semaphore>) -> Self {
+        self.inflight = inflight;
+        self
+    }
+
+    /// Shares the account locks between the servers of a multi-token process,
+    /// whose accounts are the same.
+    pub fn with_account_locks(mut self, locks: Arc<AccountLocks>) -> Self {
+        self.account_locks = locks;
+        self
+    }
+
+    /// Shares the PER_ACCOUNT_RATE_LIMIT buckets the same way.
+    pub fn with_account_rates(mut self, rates: Arc<AccountRateLimit>) -> Self {
+        self.account_rates = rates;
+        self
+    }
+
+    /// Trades on `exchange` instead of the EXCHANGE adapter.
+    #[cfg(test)]
+    pub fn with_exchange(mut self, exchange: Arc<dyn Exchange>) -> Self {
+        self.exchange = exchange;
+        self
+    }
+
+    /// Pays top-ups through `wallet` instead of the node.
+    #[cfg(test)]
+    pub fn with_wallet(mut self, wallet: Arc<dyn Wallet>) -> Self {
+        self.wallet = wallet;
+        self
+    }
+
+    /// Replaces the default floor strategy of the buy cycle.
+    pub fn with_strategy(mut self, strategy: Box<dyn BuyStrategy>) -> Self {
+        self.strategy = Mutex::new(strategy);
+        self
+    }
+
+    pub async fn prepare_accounts(&self) -> Result<()> {
+        let roles = [
+            (ACCOUNT_TYPE_MINT, "mint", &self.accounts_mint),
+            (ACCOUNT_TYPE_BUY, "buy", &self.accounts_buy),
+        ];
+        for (ty, role, accounts) in roles {
+            let report = self
+                .storage
+                .insert_accounts(ty, accounts)
+                .await
+                .with_context(|| format!("storing the {} accounts", role))?;
+            info!(
+                "[accounts] {}: inserted {}, updated {}, skipped {} existing",
+                role, report.inserted, report.updated, report.skipped
+            );
+        }
 
         Ok(())
     }
@@ -75,155 +431,3815 @@ impl BotServer {
         token: &str,
         page: i32,
         page_size: i32,
+        query: &ListQuery,
     ) -> Result<ListResponse> {
-        let res = self.rpc.get_token_list(token, page, page_size).await?;
+        let res = self
+            .exchange
+            .get_token_list(token, page, page_size, query)
+            .await
+            .with_context(|| format!("fetching {} listings page {}", token, page))?;
         Ok(res)
     }
 
+    /// Fetches the first page, retrying when the book looks empty so that a
+    /// single flaky response isn't taken for "no lists".
+    pub async fn get_first_token_list(
+        &self,
+        token: &str,
+        page_size: i32,
+        query: &ListQuery,
+    ) -> Result<ListResponse> {
+        fetch_with_empty_retry(self.config.empty_list_retries, EMPTY_LIST_RETRY_DELAY, || {
+            self.get_token_list(token, 1, page_size, query)
+        })
+        .await
+    }
+
+    /// Fetches the book for `token`, all of it unless MAX_PAGES_PER_SCAN
+    /// limits this call to a rotating window of pages. Pages still missing
+    /// when LISTINGS_DEADLINE_MS passes are left out and the result is marked
+    /// truncated; the first page is always waited for.
+    pub async fn get_all_token_listings(
+        &self,
+        token: &str,
+        query: &ListQuery,
+    ) -> Result<Listings> {
+        let deadline = self
+            .config
+            .listings_deadline_ms
+            .map(|ms| time::Instant::now() + Duration::from_millis(ms));
+        let list_res = self.get_first_token_list(token, PAGE_SIZE, query).await?;
+        let pages = (list_res.total + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut dedup = PageDedup::new(self.config.strict_pagination);
+        let mut items = dedup.filter(list_res.data.unwrap_or_default())?;
+        let rotation = self.scans.fetch_add(1, Ordering::SeqCst);
+        let scan = scan_pages(pages, self.config.max_pages_per_scan, rotation);
+        if scan.len() + 1 < pages as usize {
+            info!(
+                "[scan] capped at {} of {} pages, scanning 1 and {:?}",
+                scan.len() + 1,
+                pages,
+                scan
+            );
+        }
+        let total = scan.len();
+        for (fetched, page) in scan.into_iter().enumerate() {
+            let fetch = self.get_token_list(token, page, PAGE_SIZE, query);
+            let list_res = match deadline {
+                Some(deadline) => match time::timeout_at(deadline, fetch).await {
+                    Ok(list_res) => list_res?,
+                    Err(_) => {
+                        warn!(
+                            "[scan] {} listings deadline passed, {} of {} pages left out",
+                            token,
+                            total - fetched,
+                            total + 1
+                        );
+                        return Ok(Listings {
+                            items,
+                            truncated: true,
+                        });
+                    }
+                },
+                None => fetch.await?,
+            };
+            items.extend(dedup.filter(list_res.data.unwrap_or_default())?);
+        }
+        Ok(Listings {
+            items,
+            truncated: false,
+        })
+    }
+
     pub async fn get_owned_utxos(&self) {}
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv().ok();
-    env_logger::builder().target(Target::Stdout).init();
+    /// Records whether a scan found the book empty, logging when `cycle`
+    /// starts or stops backing off.
+    fn record_scan(&self, backoff: &Mutex<EmptyBackoff>, cycle: &str, base: Duration, empty: bool) {
+        match backoff.lock().unwrap().record(empty) {
+            Some(0) => info!("[{}] listings are back, scanning every {:?}", cycle, base),
+            Some(skip) => info!(
+                "[{}] book still empty, backing off to every {:?}",
+                cycle,
+                base * (skip + 1)
+            ),
+            None => {}
+        }
+    }
 
-    let db_url = env::var("DATABASE_URL")?;
-    let pool: Pool<Postgres> = PoolOptions::new()
-        .connect(&db_url)
-        .await
-        .expect("connect DB");
-    println!("Connecting DB...ok");
+    /// Counts a cycle run and returns its zero-based tick number.
+    fn tick(&self) -> u64 {
+        self.ticks.fetch_add(1, Ordering::SeqCst)
+    }
 
-    let args = Args::parse();
-    let accounts_mint: Vec<FraAccount> = match File::open(ACCOUNT_MINT) {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
-            let accounts = serde_json::from_str(&contents)?;
-            println!("Reading accounts-mint... ok");
-            accounts
+    /// Counts a list or buy tick toward WARMUP_TICKS; true while it is one
+    /// of the warm-up ticks.
+    fn observe_tick(&self) -> bool {
+        self.observed_ticks.fetch_add(1, Ordering::SeqCst) < self.config.warmup_ticks
+    }
+
+    /// Whether the list and buy cycles are still in their warm-up, for the
+    /// cycles that don't count toward it.
+    fn warming_up(&self) -> bool {
+        self.observed_ticks.load(Ordering::SeqCst) < self.config.warmup_ticks
+    }
+
+    fn next_buy_account(&self) -> &FraAccount {
+        let index = self.buy_account_index.fetch_add(1, Ordering::SeqCst);
+        &self.accounts_buy[index % self.accounts_buy.len()]
+    }
+
+    fn next_list_account(&self) -> &FraAccount {
+        let index = self.list_account_index.fetch_add(1, Ordering::SeqCst);
+        &self.accounts_mint[index % self.accounts_mint.len()]
+    }
+
+    /// Whether `address` is past its ACCOUNT_START_SPREAD_SECS offset at `now`.
+    fn account_started(&self, address: &str, now: i64) -> bool {
+        match self.start_offsets.lock().unwrap().get(address) {
+            Some(&offset) => now >= self.started_at + offset as i64,
+            None => true,
         }
-        Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
-                let accounts = gen_accounts(args.accounts)?;
-                let mut f = File::create(ACCOUNT_MINT)?;
-                let s = serde_json::to_string_pretty(&accounts)?;
-                let _ = f.write_all(s.as_bytes())?;
-                println!("Generating accounts-mint... ok");
-                accounts
-            } else {
-                panic!("{}", e);
+    }
+
+    /// Next list account, in rotation, past its start offset.
+    fn next_started_list_account(&self, now: i64) -> Option<&FraAccount> {
+        (0..self.accounts_mint.len())
+            .map(|_| self.next_list_account())
+            .find(|account| self.account_started(&account.address, now))
+    }
+
+    /// Next buy account, in rotation, that can pay `cost` plus the fee and
+    /// still keep FEE_RESERVE; each is tried once. One held back by
+    /// PER_ACCOUNT_RATE_LIMIT is only picked when every payable one is.
+    async fn select_account(&self, cost: u64) -> Option<&FraAccount> {
+        let now = now_secs();
+        let mut throttled = None;
+        for _ in 0..self.accounts_buy.len() {
+            let account = self.next_buy_account();
+            if !self.account_started(&account.address, now) {
+                continue;
+            }
+            let ready = self.account_rates.available(&account.address);
+            if !ready && throttled.is_some() {
+                continue;
+            }
+            let balance = match self.rpc.get_balance(account).await {
+                Ok(balance) => balance,
+                Err(e) => {
+                    warn!("[buy] {} balance query failed: {}", account.address, e);
+                    continue;
+                }
+            };
+            if cost.saturating_add(TX_FEE_MIN_V1) <= spendable(balance, self.config.fee_reserve) {
+                if ready {
+                    return Some(account);
+                }
+                throttled = Some(account);
             }
         }
-    };
+        throttled
+    }
 
-    let accounts_buy: Vec<FraAccount> = match File::open(ACCOUNT_BUY) {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
-            let accounts = serde_json::from_str(&contents)?;
-            println!("Reading accounts-buy... ok");
-            accounts
-        }
-        Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
-                let accounts = gen_accounts(args.accounts)?;
-                let mut f = File::create(ACCOUNT_BUY)?;
-                let s = serde_json::to_string_pretty(&accounts)?;
-                let _ = f.write_all(s.as_bytes())?;
-                println!("Generating accounts-buy... ok");
-                accounts
-            } else {
-                panic!("{}", e);
+    /// Next mint account past its start offset and MIN_MINT_INTERVAL_SECS rest
+    /// and under MINT_LIMIT, trying each once. Both are tracked per address, shared
+    /// across tokens. Accounts with a PER_ACCOUNT_RATE_LIMIT permit free go
+    /// first.
+    async fn next_mint_account(&self, now: i64) -> Result<Option<&FraAccount>> {
+        let interval = self.config.min_mint_interval_secs as i64;
+        let mut throttled = None;
+        for _ in 0..self.accounts_mint.len() {
+            let account = self.next_list_account();
+            if !self.account_started(&account.address, now) {
+                continue;
+            }
+            let cursor = format!("{}:{}", CURSOR_LAST_MINT, account.address);
+            match self.storage.load_cursor(&cursor).await.context("loading last mint time")? {
+                Some(at) if now - at < interval => continue,
+                _ => {}
+            }
+            let mints = self
+                .storage
+                .account_mint_count(&account.address)
+                .await
+                .context("counting mints")?;
+            if mints >= MINT_LIMIT {
+                continue;
             }
+            if self.account_rates.available(&account.address) {
+                return Ok(Some(account));
+            }
+            throttled = throttled.or(Some(account));
         }
-    };
-    let token = env::var("TOKEN")?;
-    let ex_rpc_url = env::var("EX_RPC")?;
-    let node_rpc_url = env::var("NODE_RPC")?;
-    let node_api_port = env::var("NODE_API_PORT")?;
-    let list_sum_amount = from_str::<u64>(&env::var("LIST_SUM_AMOUNT")?)?;
+        Ok(throttled)
+    }
 
-    let rpc = Rpc::new(&ex_rpc_url, &format!("{}:{}", node_rpc_url, node_api_port))?;
+    /// The alert due once `address` has minted again: when its mints reach
+    /// MINT_ALERT_PCT of MINT_LIMIT, and when they reach the limit itself.
+    async fn mint_allowance_alert(&self, address: &str) -> Result<Option<String>> {
+        let mints = self.storage.account_mint_count(address).await.context("counting mints")?;
+        let warn_at = (MINT_LIMIT * self.config.mint_alert_pct).div_ceil(100);
+        Ok(if mints == MINT_LIMIT {
+            Some(format!("{} minted {} times, the limit; no longer minting", address, mints))
+        } else if mints == warn_at && mints < MINT_LIMIT {
+            Some(format!("{} minted {} of {} allowed times", address, mints, MINT_LIMIT))
+        } else {
+            None
+        })
+    }
 
-    let floor_prices: Vec<u64> = vec![
-        123000000, 250000000, 450000000, 200000000, 220000000, 300000000,
-    ];
-    let mut price_index = 1;
-    let mut account_index = 0;
+    /// Logs `text` and posts it to ALERT_WEBHOOK when set.
+    async fn alert(&self, text: &str) {
+        warn!("[alert] {}", text);
+        if let Some(webhook) = self.config.alert_webhook.as_deref() {
+            if let Err(e) = self.rpc.notify(webhook, text).await {
+                warn!("[alert] webhook failed: {:#}", e);
+            }
+        }
+    }
 
-    let server = BotServer::new(pool, rpc, accounts_mint, accounts_buy)?;
-    server.prepare_accounts().await?;
+    fn own_account(&self, address: &str) -> Option<&FraAccount> {
+        self.accounts_mint
+            .iter()
+            .chain(self.accounts_buy.iter())
+            .find(|account| account.address == address)
+    }
 
-    let mut timer1 = time::interval(time::Duration::from_secs(5));
-    let mut timer2 = time::interval(time::Duration::from_secs(10));
+    /// Cursors are kept per token so each token resumes on its own.
+    fn cursor_name(&self, name: &str) -> String {
+        format!("{}:{}", name, self.config.token)
+    }
 
-    loop {
-        tokio::select! {
-            _ = timer1.tick() => {
-                let list_res = server.get_token_list(&token, 1, 50).await?;
-                if list_res.total == 0 {
-                    println!("[List] no lists");
-                    continue;
+    /// Restores what this token has already spent, bought and minted, so a
+    /// restart doesn't hand it a fresh budget or mint target.
+    pub async fn restore_progress(&self) -> Result<()> {
+        if let Some(spent) = self.storage.load_cursor(&self.cursor_name(CURSOR_SPENT)).await? {
+            self.spent.store(spent as u64, Ordering::SeqCst);
+        }
+        if let Some(bought) = self.storage.load_cursor(&self.cursor_name(CURSOR_BOUGHT)).await? {
+            self.bought.store(bought as u64, Ordering::SeqCst);
+        }
+        if let Some(minted) = self.storage.load_cursor(&self.cursor_name(CURSOR_MINTED)).await? {
+            self.minted.store(minted as u64, Ordering::SeqCst);
+        }
+        self.export_progress();
+        Ok(())
+    }
+
+    /// Draws each account's ACCOUNT_START_SPREAD_SECS offset, unless an earlier
+    /// run stored one, so accounts don't all start acting on the same tick.
+    pub async fn assign_start_offsets(&self) -> Result<()> {
+        let spread = match self.config.account_start_spread_secs {
+            Some(spread) => spread,
+            None => return Ok(()),
+        };
+        let accounts: Vec<&FraAccount> =
+            self.accounts_mint.iter().chain(self.accounts_buy.iter()).collect();
+        let seed = self.config.account_start_seed.unwrap_or_else(rand::random);
+        let drawn = start_offsets(accounts.len(), spread, seed);
+        let mut offsets = HashMap::new();
+        for (account, offset) in accounts.into_iter().zip(drawn) {
+            let cursor = format!("{}:{}", CURSOR_START_OFFSET, account.address);
+            let stored = self.storage.load_cursor(&cursor).await.context("loading start offset")?;
+            let offset = match stored {
+                Some(stored) => stored as u64,
+                None => {
+                    self.storage
+                        .save_cursor(&cursor, offset as i64)
+                        .await
+                        .context("saving start offset")?;
+                    offset
                 }
-                let mut sum = 0;
-                for item in list_res.data.unwrap() {
-                    sum += from_str::<u64>(&item.amount)?;
+            };
+            offsets.insert(account.address.clone(), offset);
+        }
+        *self.start_offsets.lock().unwrap() = offsets;
+        Ok(())
+    }
+
+    /// Labels are limited to the configured tokens, so series stay bounded.
+    fn export_progress(&self) {
+        let labels = [("token", self.config.token.as_str())];
+        let spent = self.spent.load(Ordering::SeqCst);
+        self.metrics.set_gauge("buy_spent", &labels, spent as f64);
+        let bought = self.bought.load(Ordering::SeqCst);
+        self.metrics.set_gauge("buy_bought", &labels, bought as f64);
+        let minted = self.minted.load(Ordering::SeqCst);
+        self.metrics.set_gauge("mint_minted", &labels, minted as f64);
+    }
+
+    /// Counts a decision not to act as
+    /// `actions_skipped_total{cycle,reason,token}`.
+    fn skipped(&self, cycle: &str, reason: &str) {
+        self.metrics.inc_counter(
+            "actions_skipped_total",
+            &[("cycle", cycle), ("reason", reason), ("token", &self.config.token)],
+        );
+    }
+
+    /// With AUTO_TOPUP, sends TOPUP_AMOUNT to every buy account under
+    /// TOPUP_MIN_BALANCE, in one transfer from the earnings pool. When the
+    /// pool can't cover them all the lowest go first; each top-up is recorded
+    /// as a trade. Like the other cycles it waits out WARMUP_TICKS and, under
+    /// fail-closed, a database that is down; the transfer is keyed like a
+    /// mint, so a broadcast that timed out isn't paid again.
+    pub async fn top_up(&self) -> Result<()> {
+        if !self.db_available("topup").await {
+            return Ok(());
+        }
+        if self.warming_up() {
+            self.skipped("topup", "warmup");
+            return Ok(());
+        }
+        let mut low = vec![];
+        for account in self.accounts_buy.iter() {
+            match self.wallet.balance(account).await {
+                Ok(balance) if balance < self.config.topup_min_balance => {
+                    low.push((account, balance))
                 }
-                let pages = list_res.total / 50 + 1;
-                for page in 1..pages {
-                    let list_res = server.get_token_list(&token, page, 50).await?;
-                    if list_res.total == 0 {
-                        continue;
+                Ok(_) => {}
+                Err(e) => warn!("[topup] {} balance query failed: {}", account.address, e),
+            }
+        }
+        if low.is_empty() {
+            return Ok(());
+        }
+        let (source, available) = match self.earnings_pool().await? {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+        low.retain(|(account, _)| account.address != source.address);
+        let amount = self.config.topup_amount;
+        let recipients = plan_topups(&low, available, amount, TX_FEE_MIN_V1);
+        if recipients.is_empty() {
+            warn!(
+                "[topup] {} buy accounts under {}, but {} can spend only {}",
+                low.len(),
+                self.config.topup_min_balance,
+                source.address,
+                available
+            );
+            self.skipped("topup", "no_earnings");
+            return Ok(());
+        }
+        let mut outputs = vec![];
+        for account in recipients.iter() {
+            let pk = public_key_from_bech32(&account.address)
+                .map_err(|e| Error::msg(format!("bad address {}: {:?}", account.address, e)))?;
+            outputs.push((pk, amount));
+        }
+        let _source = self.account_locks.lock(&source.address).await;
+        let nonce = next_nonce(self.storage.as_ref(), "topup", &source.address).await?;
+        let (nonce_str, amount_str) = (nonce.to_string(), amount.to_string());
+        let mut parts: Vec<&str> = vec!["topup", &source.address, &nonce_str, &amount_str];
+        parts.extend(recipients.iter().map(|account| account.address.as_str()));
+        let key = idempotency_key(&parts);
+        let tx = self
+            .wallet
+            .build_transfer(source, &outputs)
+            .await
+            .with_context(|| format!("building top-up from {}", source.address))?;
+        let txid = with_permit(
+            &self.inflight,
+            submit_once(
+                self.storage.as_ref(),
+                &key,
+                &Rpc::encoded_tx_hash(&tx),
+                || self.wallet.submit(&tx),
+                |prev| async move { self.wallet.is_committed(&prev).await },
+            ),
+        )
+        .await
+        .with_context(|| format!("top-up transfer from {}", source.address))?;
+        settle_nonce(self.storage.as_ref(), "topup", &source.address, nonce).await?;
+        for account in recipients {
+            info!("[topup] {} +{} from {} in {}", account.address, amount, source.address, txid);
+            self.record_trade(&Trade {
+                action: TradeAction::TopUp,
+                token: self.config.token.clone(),
+                address: account.address.clone(),
+                amount,
+                price: 0,
+                txid: txid.clone(),
+                create_time: now_secs(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// The account top-ups are paid from, with what it can spend past
+    /// FEE_RESERVE: EARNINGS_ACCOUNT, else the mint account holding the most,
+    /// since listings are sold from mint accounts.
+    async fn earnings_pool(&self) -> Result<Option<(&FraAccount, u64)>> {
+        let candidates: Vec<&FraAccount> = match self.config.earnings_account.as_deref() {
+            Some(address) => {
+                let mut accounts = self.accounts_mint.iter().chain(self.accounts_buy.iter());
+                match accounts.find(|account| account.address == address) {
+                    Some(account) => vec![account],
+                    None => {
+                        return Err(Error::msg(format!(
+                            "EARNINGS_ACCOUNT {} is not a mint or buy account",
+                            address
+                        )));
                     }
-                    for item in list_res.data.unwrap() {
-                        sum += from_str::<u64>(&item.amount)?;
+                }
+            }
+            None => self.accounts_mint.iter().collect(),
+        };
+        let mut pool: Option<(&FraAccount, u64)> = None;
+        for account in candidates {
+            match self.wallet.balance(account).await {
+                Ok(balance) => {
+                    let available = spendable(balance, self.config.fee_reserve);
+                    if pool.map_or(true, |(_, best)| available > best) {
+                        pool = Some((account, available));
                     }
                 }
+                Err(e) => warn!("[topup] {} balance query failed: {}", account.address, e),
+            }
+        }
+        Ok(pool)
+    }
 
-                if sum >= list_sum_amount{
-                    continue;
+    /// Logs a completed action and counts it as `trades_total{action,token}`.
+    /// A trade the database refuses is kept for `db_available` to write;
+    /// with DB_FAILURE_POLICY fail-closed the cycle stops there too.
+    async fn record_trade(&self, trade: &Trade) -> Result<()> {
+        if let Err(e) = self.storage.record_trade(trade).await {
+            self.unflushed.lock().unwrap().push(trade.clone());
+            let what = format!("recording {} {}", trade.action.as_str(), trade.txid);
+            if self.config.db_failure_policy == DbFailurePolicy::FailClosed {
+                return Err(e.context(format!("{}, kept to retry", what)));
+            }
+            warn!("[db] {} failed, kept in memory: {:#}", what, e);
+        }
+        self.metrics.inc_counter(
+            "trades_total",
+            &[("action", trade.action.as_str()), ("token", &trade.token)],
+        );
+        Ok(())
+    }
+
+    /// Writes the trades the database refused earlier, oldest first, and
+    /// whether a cycle may act: with DB_FAILURE_POLICY fail-closed only
+    /// once they are all written.
+    async fn db_available(&self, cycle: &str) -> bool {
+        let pending: Vec<Trade> = self.unflushed.lock().unwrap().drain(..).collect();
+        for (index, trade) in pending.iter().enumerate() {
+            if let Err(e) = self.storage.record_trade(trade).await {
+                let left = pending.len() - index;
+                self.unflushed.lock().unwrap().splice(0..0, pending[index..].iter().cloned());
+                if self.config.db_failure_policy == DbFailurePolicy::FailOpen {
+                    warn!("[{}] {} trades still unrecorded: {:#}", cycle, left, e);
+                    return true;
                 }
-                println!("[List] add lists");
-                todo!()
+                warn!("[{}] paused, {} trades unrecorded: {:#}", cycle, left, e);
+                self.skipped(cycle, "db_down");
+                return false;
+            }
+        }
+        if !pending.is_empty() {
+            info!("[{}] recorded {} trades kept while the database was down", cycle, pending.len());
+        }
+        true
+    }
+
+    async fn record_spend(&self, cost: u64, quantity: u64) -> Result<()> {
+        let spent = add_checked(&self.spent, cost, "spent")?;
+        let bought = add_checked(&self.bought, quantity, "bought")?;
+        self.export_progress();
+        self.storage
+            .save_cursor(&self.cursor_name(CURSOR_SPENT), spent as i64)
+            .await
+            .context("saving the spent total")?;
+        self.storage
+            .save_cursor(&self.cursor_name(CURSOR_BOUGHT), bought as i64)
+            .await
+            .context("saving the bought total")
+    }
+
+    async fn record_mint(&self, amount: u64) -> Result<()> {
+        let minted = add_checked(&self.minted, amount, "minted")?;
+        self.export_progress();
+        self.storage
+            .save_cursor(&self.cursor_name(CURSOR_MINTED), minted as i64)
+            .await
+            .context("saving the minted total")
+    }
+
+    /// Size of the mint cycle's next mint; `None` once MINT_TARGET_TOTAL is
+    /// reached or without one.
+    fn next_mint_amount(&self) -> Option<u64> {
+        let target = self.config.mint_target_total?;
+        let left = target.saturating_sub(self.minted.load(Ordering::SeqCst));
+        match left.min(self.config.mint_amount) {
+            0 => None,
+            amount => Some(amount),
+        }
+    }
+
+    /// Notes the cheapest ask of a scanned book as the market price.
+    fn observe_market(&self, items: &[ListItem]) {
+        if let Some(cheapest) = items.iter().filter_map(|item| item.price_value().ok()).min() {
+            self.market_price.store(cheapest, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether minting and listing pause because the market price last seen
+    /// is under MIN_ECONOMIC_PRICE, where fees cost more than tokens fetch.
+    /// Buying goes on. Logs when the pause starts and ends.
+    fn uneconomic(&self, cycle: &str) -> bool {
+        let min = match self.config.min_economic_price {
+            Some(min) => min,
+            None => return false,
+        };
+        let price = self.market_price.load(Ordering::SeqCst);
+        let paused = price != 0 && price < min;
+        if paused != self.uneconomic.swap(paused, Ordering::SeqCst) {
+            if paused {
+                warn!(
+                    "[economic] market price {} is under MIN_ECONOMIC_PRICE {}, pausing",
+                    price, min
+                );
+            } else {
+                info!("[economic] market price {} is back at {}, resuming", price, min);
+            }
+        }
+        if paused {
+            self.skipped(cycle, "uneconomic");
+        }
+        paused
+    }
+
+    /// Mints toward MINT_TARGET_TOTAL from the next rested mint account,
+    /// whatever the book holds, unless MIN_ECONOMIC_PRICE pauses it.
+    pub async fn run_mint_cycle(&self) -> Result<()> {
+        let cycle = with_action_cap(self.config.max_actions_per_tick, self.mint_cycle());
+        with_retry_budget(self.config.cycle_retry_budget, cycle).await
+    }
+
+    #[instrument(name = "mint_cycle", skip(self), fields(tick))]
+    async fn mint_cycle(&self) -> Result<()> {
+        if self.config.no_mint {
+            self.skipped("mint", "disabled");
+            return Ok(());
+        }
+        if self.accounts_mint.is_empty() {
+            self.skipped("mint", "no_accounts");
+            return Ok(());
+        }
+        if !self.db_available("mint").await {
+            return Ok(());
+        }
+        let amount = match self.next_mint_amount() {
+            Some(amount) => amount,
+            // minting is off without MINT_TARGET_TOTAL
+            None if self.config.mint_target_total.is_none() => return Ok(()),
+            None => {
+                self.skipped("mint", "target_reached");
+                return Ok(());
+            }
+        };
+        let tick = self.tick();
+        Span::current().record("tick", tick);
+        if self.warming_up() {
+            self.skipped("mint", "warmup");
+            return Ok(());
+        }
+        if self.uneconomic("mint") {
+            return Ok(());
+        }
+        let account = match self.next_mint_account(now_secs()).await? {
+            Some(account) => account,
+            None => {
+                self.skipped("mint", "mint_resting");
+                return Ok(());
+            }
+        };
+        if !self.can_pay_fee(account, "mint").await {
+            return Ok(());
+        }
+        if !take_actions(1) {
+            self.skipped("mint", "action_cap");
+            return Ok(());
+        }
+        if let Err(e) = self.mint_token(account, amount).await {
+            warn!("[mint] {} mint {} failed: {}", account.address, amount, e);
+        }
+        Ok(())
+    }
+
+    fn budget_left(&self) -> Option<u64> {
+        self.config
+            .buy_budget
+            .map(|budget| budget.saturating_sub(self.spent.load(Ordering::SeqCst)))
+    }
+
+    fn target_left(&self) -> Option<u64> {
+        self.config
+            .buy_target_amount
+            .map(|target| target.saturating_sub(self.bought.load(Ordering::SeqCst)))
+    }
+
+    pub async fn run_list_cycle(&self, list_price: u64) -> Result<()> {
+        let cycle = with_action_cap(self.config.max_actions_per_tick, self.list_cycle(list_price));
+        with_retry_budget(self.config.cycle_retry_budget, cycle).await
+    }
+
+    #[instrument(name = "list_cycle", skip(self), fields(tick))]
+    async fn list_cycle(&self, list_price: u64) -> Result<()> {
+        if !self.db_available("list").await || !self.list_backoff.lock().unwrap().should_scan() {
+            return Ok(());
+        }
+        let tick = self.tick();
+        Span::current().record("tick", tick);
+        let warming_up = self.observe_tick();
+        let token = &self.config.token;
+        let query = ListQuery::default();
+        let first = self.get_first_token_list(token, PAGE_SIZE, &query).await?;
+        // Without an upper bound only crossing the minimum matters, so the
+        // rest of the book needn't be fetched once it's reached.
+        let stop_at = match self.config.list_sum_max {
+            Some(_) => None,
+            None => self.config.list_sum_min,
+        };
+        let dedup = PageDedup::new(self.config.strict_pagination);
+        let resume = self.scan_checkpoint(first.total).await?;
+        let resumed = resume.is_some();
+        let mode = self.config.scan_on_partial_failure;
+        let partial = AtomicBool::new(false);
+        let (sum, items) = sum_listings(
+            first,
+            resume,
+            stop_at,
+            self.config.max_listed_sum,
+            dedup,
+            |page| {
+                let fetch = self.get_token_list(token, page, PAGE_SIZE, &query);
+                fetch_or_skip(mode, page, &partial, fetch)
             },
-            _ = timer2.tick() => {
-                let cur_floor_price = floor_prices[price_index%floor_prices.len()];
+            |checkpoint| self.save_scan_checkpoint(checkpoint),
+        )
+        .await?;
+        self.save_scan_checkpoint(ScanCheckpoint::default()).await?;
+        // fills only show as listings missing from a scan of the whole book
+        let whole_book =
+            !resumed && !partial.load(Ordering::SeqCst) && stop_at.map_or(true, |at| sum < at);
+        if whole_book {
+            let open = self.own_listings(&items).len() as i64;
+            self.storage
+                .save_cursor(&self.cursor_name(CURSOR_OPEN_LISTINGS), open)
+                .await
+                .context("saving the open listing count")?;
+        }
+        self.record_scan(&self.list_backoff, "List", LIST_INTERVAL, items.is_empty());
+        if items.is_empty() {
+            info!("[List] no lists");
+            return Ok(());
+        }
+        self.observe_market(&items);
 
-                let list_res = server.get_token_list(&token, 1, 50).await?;
-                if list_res.total == 0 {
-                    println!("[buy] no lists");
-                    continue;
-                }
-                let pages = list_res.total / 50 + 1;
-                if let Some(items) = list_res.data {
-                    for i in 0..items.len() {
-                        let price = from_str::<u64>(&items[i].price)?;
-                        if price <= cur_floor_price {
-                            todo!()
-                        }
-                    }
+        let action = supply_action(sum, self.config.list_sum_min, self.config.list_sum_max);
+        if action == SupplyAction::Hold {
+            return Ok(());
+        }
+        if partial.load(Ordering::SeqCst) && matches!(action, SupplyAction::List(_)) {
+            info!("[List] partial scan: sum {} may be short, not listing", sum);
+            self.skipped("list", "partial_scan");
+            return Ok(());
+        }
+        if matches!(action, SupplyAction::List(_)) && self.uneconomic("list") {
+            return Ok(());
+        }
+        if warming_up {
+            info!("[List] warm-up: sum {} wants {:?}, not acting", sum, action);
+            self.skipped("list", "warmup");
+            return Ok(());
+        }
+        if self.config.no_list {
+            info!("[List] listing disabled: sum {} wants {:?}", sum, action);
+            self.skipped("list", "disabled");
+            return Ok(());
+        }
+        match action {
+            SupplyAction::List(deficit) => {
+                let list_price = self.creation_price(&items, list_price);
+                let parts = self.split_deficit(self.listing_amount(deficit)).await?;
+                for (index, (account, amount)) in parts.into_iter().enumerate() {
+                    let action = QueuedAction::List {
+                        tick,
+                        amount,
+                        price: list_price,
+                        account,
+                    };
+                    self.perform(action)
+                        .instrument(info_span!("list", id = %action_id(tick, index + 1)))
+                        .await?;
                 }
-                for page in 1..pages {
-                    let list_res = server.get_token_list(&token, page, 50).await?;
-                    if list_res.total == 0 {
-                        println!("[List] no lists");
-                        continue;
-                    }
+                Ok(())
+            }
+            SupplyAction::Cancel(excess) => self.cancel_excess(&items, excess, tick).await,
+            SupplyAction::Hold => Ok(()),
+        }
+    }
 
-                    println!("total lists: {}", list_res.total);
-                    if let Some(items) = list_res.data {
-                        for i in 0..items.len() {
-                            todo!()
-                        }
-                    }
+    /// The list scan to pick up, with SCAN_RESUME, if the last one was cut
+    /// short and the book, now `total` listings, hasn't changed much since.
+    async fn scan_checkpoint(&self, total: i32) -> Result<Option<ScanCheckpoint>> {
+        if !self.config.scan_resume {
+            return Ok(None);
+        }
+        let load = |name| async move {
+            let value = self.storage.load_cursor(&self.cursor_name(name)).await?;
+            Ok::<i64, Error>(value.unwrap_or(0))
+        };
+        let checkpoint = ScanCheckpoint {
+            page: load(CURSOR_SCAN_PAGE).await? as i32,
+            sum: load(CURSOR_SCAN_SUM).await? as u64,
+            total: load(CURSOR_SCAN_TOTAL).await? as i32,
+        };
+        if checkpoint.page == 0 {
+            return Ok(None);
+        }
+        if !checkpoint.resumes(total, self.config.scan_resume_tolerance_pct) {
+            info!(
+                "[List] book went from {} to {} listings, rescanning from page 1",
+                checkpoint.total, total
+            );
+            return Ok(None);
+        }
+        info!("[List] resuming the scan after page {}", checkpoint.page);
+        Ok(Some(checkpoint))
+    }
+
+    /// Records list scan progress with SCAN_RESUME; page 0 clears it.
+    async fn save_scan_checkpoint(&self, checkpoint: ScanCheckpoint) -> Result<()> {
+        if !self.config.scan_resume {
+            return Ok(());
+        }
+        let cursors = [
+            (CURSOR_SCAN_SUM, checkpoint.sum as i64),
+            (CURSOR_SCAN_TOTAL, checkpoint.total as i64),
+            // last, so a checkpoint is never live with a stale sum
+            (CURSOR_SCAN_PAGE, checkpoint.page as i64),
+        ];
+        for (name, value) in cursors {
+            self.storage
+                .save_cursor(&self.cursor_name(name), value)
+                .await
+                .context("saving scan progress")?;
+        }
+        Ok(())
+    }
+
+    /// Runs `action`, first persisting it when ACTION_QUEUE is on; it leaves
+    /// the queue only once it has run. Whether a buy went through.
+    async fn perform(&self, action: QueuedAction) -> Result<bool> {
+        if !self.config.action_queue {
+            return self.execute(&action).await;
+        }
+        let id = self
+            .storage
+            .enqueue_action(&self.config.token, &action)
+            .await
+            .context("queueing action")?;
+        let done = self.execute(&action).await?;
+        self.storage.finish_action(id).await.context("dequeuing action")?;
+        Ok(done)
+    }
+
+    async fn execute(&self, action: &QueuedAction) -> Result<bool> {
+        match action {
+            QueuedAction::List {
+                amount,
+                price,
+                account,
+                ..
+            } => {
+                let account = account.as_deref();
+                self.create_listing(*amount, *price, account).await.map(|()| true)
+            }
+            QueuedAction::Buy {
+                item,
+                quantity,
+                partial,
+                floor_price,
+            } => self.buy(item, *quantity, *partial, *floor_price).await,
+        }
+    }
+
+    /// Runs the actions a crashed run queued but never finished. Their
+    /// idempotency keys keep one that was already submitted from going out
+    /// twice, and a buy rechecks its listing first.
+    pub async fn resume_queue(&self) -> Result<()> {
+        let token = &self.config.token;
+        let queued = self
+            .storage
+            .queued_actions(token)
+            .await
+            .context("loading the action queue")?;
+        if !queued.is_empty() {
+            info!("[queue] resuming {} unfinished actions of {}", queued.len(), token);
+        }
+        for (id, action) in queued {
+            if let Err(e) = self.execute(&action).await {
+                warn!("[queue] {:?} failed, left queued: {:#}", action, e);
+                continue;
+            }
+            self.storage.finish_action(id).await.context("dequeuing action")?;
+        }
+        Ok(())
+    }
+
+    /// Amount to list for `deficit`: one listing, raised to MIN_LIST_AMOUNT
+    /// rather than leaving dust on the book.
+    fn listing_amount(&self, deficit: u64) -> u64 {
+        deficit.max(self.config.min_list_amount)
+    }
+
+    fn mints_before_list(&self) -> bool {
+        self.config.mint_before_list && !self.config.no_mint
+    }
+
+    /// Which mint account lists how much of `amount`. Past SPLIT_STRATEGY
+    /// single, up to one account per MIN_LIST_AMOUNT is drawn in rotation
+    /// and shares it; `None` leaves the pick to `create_listing`.
+    async fn split_deficit(&self, amount: u64) -> Result<Vec<(Option<String>, u64)>> {
+        let strategy = self.config.split_strategy;
+        if strategy == SplitStrategy::Single || self.accounts_mint.is_empty() {
+            return Ok(vec![(None, amount)]);
+        }
+        let wanted = amount / self.config.min_list_amount.max(1);
+        let wanted = wanted.clamp(1, self.accounts_mint.len() as u64);
+        let mut accounts: Vec<(String, u64)> = vec![];
+        for _ in 0..wanted {
+            let account = if self.mints_before_list() {
+                match self.next_mint_account(now_secs()).await? {
+                    Some(account) => account,
+                    None => break,
                 }
+            } else {
+                match self.next_started_list_account(now_secs()) {
+                    Some(account) => account,
+                    None => break,
+                }
+            };
+            if accounts.iter().any(|(address, _)| *address == account.address) {
+                break;
+            }
+            let balance = if strategy == SplitStrategy::BalanceWeighted {
+                self.rpc.get_balance(account).await.unwrap_or_else(|e| {
+                    warn!("[List] {} balance query failed: {}", account.address, e);
+                    0
+                })
+            } else {
+                0
+            };
+            accounts.push((account.address.clone(), balance));
+        }
+        if accounts.is_empty() {
+            return Ok(vec![(None, amount)]);
+        }
+        let parts = plan_listings(amount, &accounts, strategy);
+        Ok(parts.into_iter().map(|(address, part)| (Some(address), part)).collect())
+    }
+
+    /// Price for a new listing: `list_price`, or the book median plus
+    /// LIST_SPREAD_BPS, clamped under the best foreign ask and over SELL_FLOOR.
+    fn creation_price(&self, items: &[ListItem], list_price: u64) -> u64 {
+        let list_price = match self.config.list_spread_bps {
+            Some(bps) => match compute_list_price(items, bps) {
+                0 => list_price,
+                price => price,
+            },
+            None => list_price,
+        };
+        match self.config.list_undercut_tick {
+            Some(tick_size) => {
+                // undercutting our own listings would only chase the price down
+                let foreign: Vec<ListItem> = items
+                    .iter()
+                    .filter(|item| self.own_account(&item.from).is_none())
+                    .cloned()
+                    .collect();
+                clamp_list_price(list_price, &foreign, tick_size, self.config.sell_floor)
+            }
+            None => list_price.max(self.config.sell_floor.unwrap_or(0)),
+        }
+    }
 
-                price_index += 1;
+    /// Whether `account` can pay for its next transaction and keep
+    /// FEE_RESERVE, going by the lower of the node's and the exchange's
+    /// balance. Warns and says no when they disagree beyond BALANCE_TOLERANCE.
+    async fn can_pay_fee(&self, account: &FraAccount, cycle: &str) -> bool {
+        let balances = tokio::try_join!(
+            self.rpc.get_balance(account),
+            self.rpc.get_exchange_balance(&account.address)
+        );
+        let (node, exchange) = match balances {
+            Ok(balances) => balances,
+            Err(e) => {
+                warn!("[{}] {} balance query failed: {}", cycle, account.address, e);
+                self.skipped(cycle, "balance_unknown");
+                return false;
             }
+        };
+        if node != exchange {
+            info!(
+                "[{}] {} balance: node {}, exchange {}",
+                cycle, account.address, node, exchange
+            );
         }
+        let balance = match reconcile_balance(node, exchange, self.config.balance_tolerance) {
+            Some(balance) => balance,
+            None => {
+                warn!(
+                    "[{}] {} node balance {} and exchange balance {} differ by more than {}, skipping",
+                    cycle,
+                    account.address,
+                    node,
+                    exchange,
+                    self.config.balance_tolerance.unwrap_or(0)
+                );
+                self.skipped(cycle, "balance_mismatch");
+                return false;
+            }
+        };
+        if spendable(balance, self.config.fee_reserve) < TX_FEE_MIN_V1 {
+            warn!("[{}] {} balance {} can't pay the fee", cycle, account.address, balance);
+            self.skipped(cycle, "fee_reserve");
+            return false;
+        }
+        true
     }
 
-    Ok(())
+    async fn create_listing(&self, amount: u64, price: u64, account: Option<&str>) -> Result<()> {
+        info!("[List] add lists");
+        if self.accounts_mint.is_empty() {
+            warn!("[List] no mint accounts to list from, skipping");
+            self.skipped("list", "no_accounts");
+            return Ok(());
+        }
+        let token = &self.config.token;
+        let minting = self.mints_before_list();
+        let account = match account {
+            Some(address) => match self.accounts_mint.iter().find(|a| a.address == address) {
+                Some(account) => account,
+                None => {
+                    warn!("[List] {} is no longer a mint account, skipping", address);
+                    self.skipped("list", "unknown_account");
+                    return Ok(());
+                }
+            },
+            None if minting => match self.next_mint_account(now_secs()).await? {
+                Some(account) => account,
+                None => {
+                    info!("[List] every mint account is resting or at MINT_LIMIT, skipping");
+                    self.skipped("list", "mint_resting");
+                    return Ok(());
+                }
+            },
+            None => match self.next_started_list_account(now_secs()) {
+                Some(account) => account,
+                None => {
+                    info!("[List] no mint account has reached its start offset, skipping");
+                    self.skipped("list", "not_started");
+                    return Ok(());
+                }
+            },
+        };
+        if self.at_listing_cap().await? {
+            return Ok(());
+        }
+        if !self.can_pay_fee(account, "list").await {
+            return Ok(());
+        }
+        // the mint and its listing go together or not at all
+        if !take_actions(if minting { 2 } else { 1 }) {
+            self.skipped("list", "action_cap");
+            return Ok(());
+        }
+        if minting {
+            if let Err(e) = self.mint_token(account, amount).await {
+                warn!("[List] {} mint {} failed: {}", account.address, amount, e);
+                return Ok(());
+            }
+        }
+        match self.submit_listing(account, amount, price).await {
+            Ok(txid) => {
+                info!("[List] {} listed {} at {} ({})", account.address, amount, price, txid);
+                self.count_open_listings(1).await?;
+                self.record_trade(&Trade {
+                    action: TradeAction::List,
+                    token: token.clone(),
+                    address: account.address.clone(),
+                    amount,
+                    price,
+                    txid,
+                    create_time: now_secs(),
+                })
+                .await?;
+            }
+            Err(e) => warn!("[List] {} listing {} failed: {}", account.address, amount, e),
+        }
+
+        Ok(())
+    }
+
+    /// Whether this token already has MAX_OPEN_LISTINGS own listings open, by
+    /// the count the list cycle refreshes from the book.
+    async fn at_listing_cap(&self) -> Result<bool> {
+        let max = match self.config.max_open_listings {
+            Some(max) => max,
+            None => return Ok(false),
+        };
+        let cursor = self.cursor_name(CURSOR_OPEN_LISTINGS);
+        let open = self.storage.load_cursor(&cursor).await.context("loading open listings")?;
+        let open = open.unwrap_or(0).max(0) as u64;
+        if open >= max {
+            info!("[List] {} listings open, MAX_OPEN_LISTINGS {}; deferring", open, max);
+            self.skipped("list", "listing_cap");
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Moves the open listing count by `delta` as listings are placed and
+    /// cancelled, between the scans that recount it.
+    async fn count_open_listings(&self, delta: i64) -> Result<()> {
+        let cursor = self.cursor_name(CURSOR_OPEN_LISTINGS);
+        let open = self.storage.load_cursor(&cursor).await.context("loading open listings")?;
+        self.storage
+            .save_cursor(&cursor, (open.unwrap_or(0) + delta).max(0))
+            .await
+            .context("saving the open listing count")
+    }
+
+    /// Mints `amount` to `account`, then waits for the mint to commit and
+    /// for MINT_TO_LIST_DELAY_SECS so a listing doesn't outrun the indexer.
+    async fn mint_token(&self, account: &FraAccount, amount: u64) -> Result<String> {
+        let token = &self.config.token;
+        let _account = self.account_locks.lock(&account.address).await;
+        let nonce = next_nonce(self.storage.as_ref(), "mint", &account.address).await?;
+        let key = idempotency_key(&[
+            "mint",
+            token,
+            &account.address,
+            &nonce.to_string(),
+            &amount.to_string(),
+        ]);
+        self.account_rates.take(&account.address).await;
+        let to = account.key_pair().context("deriving the mint key pair")?.get_pk();
+        let tx = self
+            .rpc
+            .build_brc20(account, "mint", token, amount, to)
+            .await
+            .with_context(|| format!("building mint of {} {}", amount, token))?;
+        let txid = with_permit(
+            &self.inflight,
+            submit_once(
+                self.storage.as_ref(),
+                &key,
+                &Rpc::tx_hash(&tx)?,
+                || self.rpc.submit_transaction(&tx),
+                |prev| async move { self.rpc.is_tx_committed(&prev).await },
+            ),
+        )
+        .await
+        .context("submitting mint")?;
+        settle_nonce(self.storage.as_ref(), "mint", &account.address, nonce).await?;
+        self.storage
+            .save_cursor(&format!("{}:{}", CURSOR_LAST_MINT, account.address), now_secs())
+            .await
+            .context("saving last mint time")?;
+        self.record_mint(amount).await?;
+        self.record_trade(&Trade {
+            action: TradeAction::Mint,
+            token: token.clone(),
+            address: account.address.clone(),
+            amount,
+            price: 0,
+            txid: txid.clone(),
+            create_time: now_secs(),
+        })
+        .await?;
+        if let Some(text) = self.mint_allowance_alert(&account.address).await? {
+            self.alert(&text).await;
+        }
+
+        let timeout = Duration::from_secs(self.config.mint_confirm_timeout_secs);
+        let committed = wait_until(MINT_POLL_INTERVAL, timeout, || self.rpc.is_tx_committed(&txid))
+            .await
+            .with_context(|| format!("polling mint {}", txid))?;
+        if !committed {
+            return Err(Error::msg(format!("mint {} not committed after {:?}", txid, timeout)));
+        }
+        info!("[mint] {} minted {} ({})", account.address, amount, txid);
+        time::sleep(Duration::from_secs(self.config.mint_to_list_delay_secs)).await;
+
+        Ok(txid)
+    }
+
+    /// The key of a listing transfer counts the account's listings through a
+    /// stored nonce, so it is the same for a retry but new after a restart.
+    async fn submit_listing(
+        &self,
+        account: &FraAccount,
+        amount: u64,
+        price: u64,
+    ) -> Result<String> {
+        let center = public_key_from_bech32(&self.config.center_account)
+            .map_err(|e| Error::msg(format!("CENTER_ACCOUNT: {:?}", e)))?;
+        let token = &self.config.token;
+        let _account = self.account_locks.lock(&account.address).await;
+        let nonce = next_nonce(self.storage.as_ref(), "list", &account.address).await?;
+        let key = idempotency_key(&[
+            "list",
+            token,
+            &account.address,
+            &nonce.to_string(),
+            &amount.to_string(),
+            &self.config.center_account,
+        ]);
+        self.account_rates.take(&account.address).await;
+        let tx = self
+            .rpc
+            .build_brc20(account, "transfer", token, amount, center)
+            .await
+            .with_context(|| format!("building transfer of {} {} to list", amount, token))?;
+        let txid_hint = Rpc::tx_hash(&tx)?;
+        with_permit(&self.inflight, async {
+            let txid = submit_once(
+                self.storage.as_ref(),
+                &key,
+                &txid_hint,
+                || self.rpc.submit_transaction(&tx),
+                |prev| async move { self.rpc.is_tx_committed(&prev).await },
+            )
+            .await
+            .context("submitting listing transfer")?;
+            settle_nonce(self.storage.as_ref(), "list", &account.address, nonce).await?;
+            self.exchange
+                .create_listing(account, token, amount, price, &txid)
+                .await
+                .with_context(|| format!("confirming listing {} with the exchange", txid))?;
+            Ok(txid)
+        })
+        .await
+    }
+
+    /// Our listings in `items`, dearest first, which is the order excess
+    /// supply is pulled in.
+    fn own_listings<'a>(&'a self, items: &'a [ListItem]) -> Vec<(&'a ListItem, &'a FraAccount)> {
+        let mut own: Vec<(&ListItem, &FraAccount)> = items
+            .iter()
+            .filter_map(|item| self.own_account(&item.from).map(|account| (item, account)))
+            .collect();
+        own.sort_by_key(|(item, _)| std::cmp::Reverse(item.price_value().unwrap_or(0)));
+        own
+    }
+
+    /// Cancels own listings, dearest first, until `excess` tokens are pulled.
+    async fn cancel_excess(&self, items: &[ListItem], excess: u64, tick: u64) -> Result<()> {
+        let mut cancelled = 0;
+        for (index, (item, account)) in self.own_listings(items).into_iter().enumerate() {
+            if cancelled >= excess {
+                break;
+            }
+            let span = info_span!("cancel", id = %action_id(tick, index + 1), listing = item.id);
+            cancelled += self.cancel(item, account).instrument(span).await?;
+        }
+        info!("[List] cancelled {} of {} excess", cancelled, excess);
+
+        Ok(())
+    }
+
+    pub async fn run_reprice_cycle(&self, list_price: u64) -> Result<()> {
+        let cycle = self.reprice_cycle(list_price);
+        let cycle = with_action_cap(self.config.max_actions_per_tick, cycle);
+        with_retry_budget(self.config.cycle_retry_budget, cycle).await
+    }
+
+    /// Cancels own listings past LISTING_TTL_SECS, which the list cycle
+    /// replaces if supply runs short, then reprices those that drifted.
+    #[instrument(name = "reprice_cycle", skip(self), fields(tick))]
+    async fn reprice_cycle(&self, list_price: u64) -> Result<()> {
+        let config = &self.config;
+        if config.reprice_drift_bps.is_none() && config.listing_ttl_secs.is_none() {
+            return Ok(());
+        }
+        if config.no_list {
+            self.skipped("reprice", "disabled");
+            return Ok(());
+        }
+        if !self.db_available("reprice").await {
+            return Ok(());
+        }
+        let tick = self.tick();
+        Span::current().record("tick", tick);
+        if self.warming_up() {
+            self.skipped("reprice", "warmup");
+            return Ok(());
+        }
+        let listings = self
+            .get_all_token_listings(&self.config.token, &ListQuery::default())
+            .await?;
+        let items = listings.items;
+        let now = now_secs();
+        let expired = self.expired_listings(&items, now);
+        for (index, (item, account)) in expired.iter().enumerate() {
+            if !take_actions(1) {
+                self.skipped("reprice", "action_cap");
+                continue;
+            }
+            let span = info_span!("expire", id = %action_id(tick, index + 1), listing = item.id);
+            if self.cancel(item, account).instrument(span).await? > 0 {
+                info!(
+                    "[expire] {} cancelled listing {}, up for {}s",
+                    account.address,
+                    item.id,
+                    now - item.create_time
+                );
+            }
+        }
+        // a partial book can't price relistings; expiring only cancels
+        if listings.truncated {
+            self.skipped("reprice", "truncated_scan");
+            return Ok(());
+        }
+        let price = self.creation_price(&items, list_price);
+        let stale = self
+            .stale_listings(&items, price, now)
+            .into_iter()
+            .filter(|(item, _)| !expired.iter().any(|(gone, _)| gone.id == item.id));
+        for (index, (item, account)) in stale.enumerate() {
+            if !take_actions(1) {
+                self.skipped("reprice", "action_cap");
+                continue;
+            }
+            let id = action_id(tick, expired.len() + index + 1);
+            let span = info_span!("reprice", id = %id, listing = item.id);
+            self.reprice(item, account, price).instrument(span).await?;
+        }
+        Ok(())
+    }
+
+    /// Own listings up for LISTING_TTL_SECS or longer at `now`.
+    fn expired_listings<'a>(
+        &'a self,
+        items: &'a [ListItem],
+        now: i64,
+    ) -> Vec<(&'a ListItem, &'a FraAccount)> {
+        let ttl = match self.config.listing_ttl_secs {
+            Some(ttl) => ttl as i64,
+            None => return vec![],
+        };
+        self.own_listings(items)
+            .into_iter()
+            .filter(|(item, _)| now - item.create_time >= ttl)
+            .collect()
+    }
+
+    /// Own listings priced more than REPRICE_DRIFT_BPS away from `price` and
+    /// listed at least REPRICE_MIN_INTERVAL_SECS before `now`.
+    fn stale_listings<'a>(
+        &'a self,
+        items: &'a [ListItem],
+        price: u64,
+        now: i64,
+    ) -> Vec<(&'a ListItem, &'a FraAccount)> {
+        let drift_bps = match self.config.reprice_drift_bps {
+            Some(bps) => bps as u128,
+            None => return vec![],
+        };
+        let min_age = self.config.reprice_min_interval_secs as i64;
+        self.own_listings(items)
+            .into_iter()
+            .filter(|(item, _)| now - item.create_time >= min_age)
+            .filter(|(item, _)| match item.price_value() {
+                Ok(listed) => listed.abs_diff(price) as u128 * 10_000 > drift_bps * price as u128,
+                Err(_) => false,
+            })
+            .collect()
+    }
+
+    /// Cancels `item` and lists what it held again at `price`.
+    async fn reprice(&self, item: &ListItem, account: &FraAccount, price: u64) -> Result<()> {
+        let amount = self.cancel(item, account).await?;
+        if amount == 0 {
+            return Ok(());
+        }
+        match self.submit_listing(account, amount, price).await {
+            Ok(txid) => {
+                self.count_open_listings(1).await?;
+                info!(
+                    "[reprice] {} relisted {} from {} at {} ({})",
+                    account.address, amount, item.price, price, txid
+                );
+                self.record_trade(&Trade {
+                    action: TradeAction::List,
+                    token: self.config.token.clone(),
+                    address: account.address.clone(),
+                    amount,
+                    price,
+                    txid,
+                    create_time: now_secs(),
+                })
+                .await?;
+            }
+            Err(e) => warn!("[reprice] {} relisting {} failed: {}", account.address, amount, e),
+        }
+        Ok(())
+    }
+
+    /// Returns the amount pulled from the book, zero if the cancel failed.
+    async fn cancel(&self, item: &ListItem, account: &FraAccount) -> Result<u64> {
+        let amount = item
+            .amount_value()
+            .with_context(|| format!("amount of listing {}", item.id))?;
+        // checked up front: a listing cancelled on the exchange must get its
+        // Cancel trade, or `cancel_all` keeps counting it
+        let price = item
+            .price_value()
+            .with_context(|| format!("price of listing {}", item.id))?;
+        if let Err(e) = self.exchange.cancel_listing(account, item.id).await {
+            warn!("[List] cancel listing {} failed: {}", item.id, e);
+            return Ok(0);
+        }
+        self.count_open_listings(-1).await?;
+        self.record_trade(&Trade {
+            action: TradeAction::Cancel,
+            token: self.config.token.clone(),
+            address: account.address.clone(),
+            amount,
+            price,
+            txid: item.id.to_string(),
+            create_time: now_secs(),
+        })
+        .await?;
+
+        Ok(amount)
+    }
+
+    pub async fn run_buy_cycle(&self, floor_price: u64) -> Result<()> {
+        let cycle = with_action_cap(self.config.max_actions_per_tick, self.buy_cycle(floor_price));
+        with_retry_budget(self.config.cycle_retry_budget, cycle).await
+    }
+
+    #[instrument(name = "buy_cycle", skip(self), fields(tick))]
+    async fn buy_cycle(&self, floor_price: u64) -> Result<()> {
+        if !self.db_available("buy").await || !self.buy_backoff.lock().unwrap().should_scan() {
+            return Ok(());
+        }
+        let tick = self.tick();
+        Span::current().record("tick", tick);
+        let warming_up = self.observe_tick();
+        if self.panicked.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let token = &self.config.token;
+        // The floor check below still applies; the filter only trims pages.
+        let query = if self.config.sorted_scan && !warming_up {
+            ListQuery::cheapest_up_to(floor_price)
+        } else {
+            ListQuery::default()
+        };
+        // every order is still checked on its own, so a truncated book only
+        // means fewer buys
+        let items = self.get_all_token_listings(token, &query).await?.items;
+        self.record_scan(&self.buy_backoff, "buy", BUY_INTERVAL, items.is_empty());
+        if items.is_empty() {
+            info!("[buy] no lists");
+            return Ok(());
+        }
+        info!("total lists: {}", items.len());
+
+        let mut cheapest = u64::MAX;
+        for item in items.iter() {
+            let price = item
+                .price_value()
+                .with_context(|| format!("price of listing {}", item.id))?;
+            cheapest = cheapest.min(price);
+        }
+        self.market_price.store(cheapest, Ordering::SeqCst);
+        if self.config.enable_panic_sell
+            && !self.config.no_list
+            && !self.panicked.load(Ordering::SeqCst)
+        {
+            let (fall, high) = {
+                let mut floors = self.floors.lock().unwrap();
+                floors.observe(now_secs(), cheapest);
+                (floors.drop_pct(), floors.high())
+            };
+            // warm-up only watches; the window still fills from its snapshots
+            if fall >= self.config.panic_drop_pct && !warming_up {
+                warn!(
+                    "[panic] floor fell {:.1}% from {} to {} within {}s (limit {}%), selling inventory",
+                    fall, high, cheapest, self.config.panic_window_secs, self.config.panic_drop_pct
+                );
+                self.panicked.store(true, Ordering::SeqCst);
+                return self.panic_sell(tick, cheapest).await;
+            }
+        }
+
+        if warming_up {
+            info!(
+                "[buy] warm-up: cheapest {} vs floor {}, not buying",
+                cheapest, floor_price
+            );
+            self.skipped("buy", "warmup");
+            return Ok(());
+        }
+        if self.config.no_buy {
+            info!(
+                "[buy] buying disabled: cheapest {} vs floor {}",
+                cheapest, floor_price
+            );
+            self.skipped("buy", "disabled");
+            return Ok(());
+        }
+        if self.accounts_buy.is_empty() {
+            warn!("[buy] no buy accounts, not buying");
+            self.skipped("buy", "no_accounts");
+            return Ok(());
+        }
+
+        let mut orders = self.decide_buys(&items, floor_price);
+        let confirmed = self.dampener.lock().unwrap().confirm(orders.iter().map(|o| o.item.id));
+        orders.retain(|order| {
+            let held = !confirmed.contains(&order.item.id);
+            if held {
+                info!("[buy] listing {} not cheap for long enough yet", order.item.id);
+                self.skipped("buy", "unconfirmed_dip");
+            }
+            !held
+        });
+        let mut action_index = 0;
+        let mut bought = 0;
+        for order in orders.iter() {
+            let item = &order.item;
+            let delay = match self.jitter.lock().unwrap().gate() {
+                Some(delay) => delay,
+                None => {
+                    info!("[buy] listing {} held back by jitter", item.id);
+                    self.skipped("buy", "jitter");
+                    continue;
+                }
+            };
+            if !take_actions(1) {
+                self.skipped("buy", "action_cap");
+                continue;
+            }
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+
+            action_index += 1;
+            let span = info_span!("buy", id = %action_id(tick, action_index), listing = item.id);
+            let action = QueuedAction::Buy {
+                item: item.clone(),
+                quantity: order.quantity,
+                partial: order.is_partial(),
+                floor_price,
+            };
+            if self.perform(action).instrument(span).await? {
+                bought += 1;
+            }
+        }
+        debug!(floor_price, cheapest, bought, "[buy] tick summary");
+
+        Ok(())
+    }
+
+    /// Asks the strategy for this tick's orders at `floor_price`, offering
+    /// the book cheapest first; nothing is bought from a book below
+    /// MIN_LISTINGS_TO_BUY.
+    fn decide_buys(&self, items: &[ListItem], floor_price: u64) -> Vec<BuyOrder> {
+        if items.len() < self.config.min_listings_to_buy {
+            info!(
+                "[buy] thin book: {} listings, fewer than {}, not buying",
+                items.len(),
+                self.config.min_listings_to_buy
+            );
+            self.skipped("buy", "thin_book");
+            return vec![];
+        }
+        let sellers_allowed = |item: &&ListItem| {
+            let allowed = self.config.buys_from(&item.from);
+            if !allowed {
+                self.skipped("buy", "seller");
+            }
+            allowed
+        };
+        let state = BotState {
+            floor_price,
+            now: now_secs(),
+            budget_left: self.budget_left(),
+            target_left: self.target_left(),
+            partial_fills: self.config.partial_fills,
+        };
+        let mut book: Vec<ListItem> = items.iter().filter(sellers_allowed).cloned().collect();
+        order_book(&mut book, self.config.buy_tie_break);
+        let mut orders = self.strategy.lock().unwrap().decide(&book, &state);
+        if let Some(min) = self.config.min_abs_profit {
+            orders.retain(|order| {
+                let profit = resale_profit(floor_price, order.price, order.quantity, TX_FEE_MIN_V1);
+                if profit < min as i128 {
+                    let id = order.item.id;
+                    debug!("[buy] listing {} would make {}, under MIN_ABS_PROFIT", id, profit);
+                    self.skipped("buy", "min_profit");
+                    return false;
+                }
+                true
+            });
+        }
+        let allowed = self.soft_cap_orders(orders.len());
+        if allowed < orders.len() {
+            info!("[buy] past the soft cap, placing {} of {} buys", allowed, orders.len());
+            for _ in allowed..orders.len() {
+                self.skipped("buy", "soft_cap");
+            }
+            orders.truncate(allowed);
+        }
+        orders
+    }
+
+    /// How many of `wanted` orders a tick places once spending passes
+    /// BUY_SOFT_CAP_PCT of the budget: the share of the budget left beyond
+    /// the soft cap, rounded up so buying tapers instead of stopping.
+    fn soft_cap_orders(&self, wanted: usize) -> usize {
+        let (budget, pct) = match (self.config.buy_budget, self.config.buy_soft_cap_pct) {
+            (Some(budget), Some(pct)) => (budget as u128, pct.min(100) as u128),
+            _ => return wanted,
+        };
+        let soft = budget * pct / 100;
+        let spent = self.spent.load(Ordering::SeqCst) as u128;
+        if spent <= soft || soft >= budget {
+            return wanted;
+        }
+        let left = budget.saturating_sub(spent);
+        (wanted as u128 * left).div_ceil(budget - soft) as usize
+    }
+
+    /// Runs the list and buy decisions over `items` without acting, spending
+    /// the budget only on paper.
+    fn plan(&self, items: &[ListItem], list_price: u64, floor_price: u64) -> Result<ReplayPlan> {
+        let sum = add_listed(0, items, self.config.max_listed_sum)?;
+        let action = supply_action(sum, self.config.list_sum_min, self.config.list_sum_max);
+        let mut cancels = vec![];
+        let list_amount = match action {
+            SupplyAction::List(deficit) => Some(self.listing_amount(deficit)),
+            _ => None,
+        };
+        let list_price = match action {
+            SupplyAction::List(_) => Some(self.creation_price(items, list_price)),
+            SupplyAction::Cancel(excess) => {
+                let mut pulled = 0;
+                for (item, _) in self.own_listings(items) {
+                    if pulled >= excess {
+                        break;
+                    }
+                    pulled += item.amount_value()?;
+                    cancels.push(item.id);
+                }
+                None
+            }
+            SupplyAction::Hold => None,
+        };
+
+        let buys = self
+            .decide_buys(items, floor_price)
+            .iter()
+            .map(|order| (order.item.id, order.price, order.quantity))
+            .collect();
+
+        Ok(ReplayPlan {
+            listed_sum: sum,
+            sum_min: self.config.list_sum_min,
+            sum_max: self.config.list_sum_max,
+            floor_price,
+            supply: format!("{:?}", action),
+            list_amount,
+            list_price,
+            cancels,
+            buys,
+        })
+    }
+
+    /// Re-lists everything the buy accounts hold, per the trade log, at
+    /// `price`. Runs at most once per process; buying stays off afterwards.
+    async fn panic_sell(&self, tick: u64, price: u64) -> Result<()> {
+        let mut held: HashMap<String, i64> = HashMap::new();
+        let mut trades = self.storage.recent_trades(0);
+        while let Some(trade) = trades.next().await {
+            let trade = trade?;
+            if trade.token != self.config.token {
+                continue;
+            }
+            let delta = match trade.action {
+                TradeAction::Buy => trade.amount as i64,
+                TradeAction::List => -(trade.amount as i64),
+                _ => continue,
+            };
+            *held.entry(trade.address).or_default() += delta;
+        }
+        drop(trades);
+
+        for (index, account) in self.accounts_buy.iter().enumerate() {
+            let amount = held.get(&account.address).copied().unwrap_or(0);
+            if amount <= 0 {
+                continue;
+            }
+            let amount = amount as u64;
+            if !take_actions(1) {
+                self.skipped("panic", "action_cap");
+                continue;
+            }
+            let span = info_span!("panic_list", id = %action_id(tick, index + 1));
+            let res = self
+                .submit_listing(account, amount, price)
+                .instrument(span)
+                .await;
+            match res {
+                Ok(txid) => {
+                    warn!("[panic] {} listed {} at {} ({})", account.address, amount, price, txid);
+                    self.record_trade(&Trade {
+                        action: TradeAction::List,
+                        token: self.config.token.clone(),
+                        address: account.address.clone(),
+                        amount,
+                        price,
+                        txid,
+                        create_time: now_secs(),
+                    })
+                    .await?;
+                }
+                Err(e) => warn!("[panic] {} listing {} failed: {}", account.address, amount, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `item` as the exchange has it now, if it is still open with `quantity`
+    /// left. A listing repriced since the scan is re-read at its new price,
+    /// up to BUY_REPRICE_RETRIES times, and kept while at or under
+    /// `floor_price`.
+    async fn live_listing(
+        &self,
+        item: &ListItem,
+        quantity: u64,
+        floor_price: u64,
+    ) -> Result<Option<ListItem>> {
+        let mut expected = item.price.clone();
+        for _ in 0..=BUY_REPRICE_RETRIES {
+            let live = self
+                .rpc
+                .get_listing(&item.id.to_string())
+                .await
+                .with_context(|| format!("reading listing {}", item.id))?;
+            let live = match live {
+                Some(live) => live,
+                None => return Ok(None),
+            };
+            if live.state != 0 || live.amount_value().unwrap_or(0) < quantity {
+                return Ok(None);
+            }
+            if live.price == expected {
+                return Ok(Some(live));
+            }
+            let price = live.price_value()?;
+            if price > floor_price {
+                info!(
+                    "[buy] listing {} repriced to {}, over floor {}",
+                    item.id, price, floor_price
+                );
+                return Ok(None);
+            }
+            info!("[buy] listing {} repriced from {} to {}, re-reading", item.id, expected, price);
+            expected = live.price;
+        }
+        Ok(None)
+    }
+
+    /// Buys `quantity` of `item` at its current price; false when it was
+    /// skipped or failed.
+    async fn buy(
+        &self,
+        item: &ListItem,
+        quantity: u64,
+        partial: bool,
+        floor_price: u64,
+    ) -> Result<bool> {
+        // pages scanned earlier in the cycle may be stale by now
+        let live = match self.live_listing(item, quantity, floor_price).await {
+            Ok(Some(live)) => live,
+            Ok(None) => {
+                info!("[buy] listing {} is gone or changed, skipping", item.id);
+                self.skipped("buy", "listing_gone");
+                return Ok(false);
+            }
+            Err(e) => {
+                warn!("[buy] listing {} check failed: {}", item.id, e);
+                self.skipped("buy", "listing_unknown");
+                return Ok(false);
+            }
+        };
+        let item = &live;
+        let price = item.price_value()?;
+        let cost = checked_cost(price, quantity)?;
+        if self.budget_left().map_or(false, |left| cost > left) {
+            info!("[buy] listing {} now costs {}, over the budget left", item.id, cost);
+            self.skipped("buy", "budget");
+            return Ok(false);
+        }
+        let account = match self.select_account(cost).await {
+            Some(account) => account,
+            None => {
+                warn!(
+                    "[buy] no buy account can pay {} for listing {} and keep {} in reserve",
+                    cost,
+                    item.id,
+                    self.config.fee_reserve
+                );
+                self.skipped("buy", "fee_reserve");
+                return Ok(false);
+            }
+        };
+        match self.submit_buy(account, item, quantity, partial).await {
+            Ok(txid) => {
+                info!("[buy] {} bought {} at {} ({})", account.address, quantity, price, txid);
+                self.record_spend(cost, quantity).await?;
+                self.record_trade(&Trade {
+                    action: TradeAction::Buy,
+                    token: self.config.token.clone(),
+                    address: account.address.clone(),
+                    amount: quantity,
+                    price,
+                    txid,
+                    create_time: now_secs(),
+                })
+                .await?;
+                Ok(true)
+            }
+            Err(e) => {
+                warn!("[buy] listing {} failed: {}", item.id, e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// The buy key leaves out the paying account: rotation would hand a retry
+    /// to a different account, and paying a seller twice is the costly case.
+    async fn submit_buy(
+        &self,
+        account: &FraAccount,
+        item: &ListItem,
+        quantity: u64,
+        partial: bool,
+    ) -> Result<String> {
+        let key = idempotency_key(&[
+            "buy",
+            &item.id.to_string(),
+            &item.create_time.to_string(),
+            &quantity.to_string(),
+            &item.from,
+        ]);
+
+        let _account = self.account_locks.lock(&account.address).await;
+        self.account_rates.take(&account.address).await;
+        let tx = self
+            .rpc
+            .prepare_buy(account, item, quantity, partial)
+            .await
+            .with_context(|| format!("building payment for listing {}", item.id))?;
+        let txid_hint = Rpc::tx_hash(&tx)?;
+        with_permit(&self.inflight, async {
+            let txid = submit_once(
+                self.storage.as_ref(),
+                &key,
+                &txid_hint,
+                || self.rpc.submit_transaction(&tx),
+                |prev| async move { self.rpc.is_tx_committed(&prev).await },
+            )
+            .await
+            .context("submitting payment")?;
+            self.exchange
+                .buy_listing(account, item, quantity, partial, &txid)
+                .await
+                .with_context(|| format!("confirming buy {} with the exchange", txid))?;
+            Ok(txid)
+        })
+        .await
+    }
+
+    /// One line with the settings actually in effect; secrets never appear
+    /// and URLs lose their credentials.
+    pub fn log_banner(&self, floor_prices: &[u64]) {
+        let c = &self.config;
+        info!(
+            "[startup] token={} list_interval={:?} buy_interval={:?} floor_prices={:?} \
+             list_sum_min={:?} list_sum_max={:?} buy_budget={:?} buy_target={:?} \
+             partial_fills={} warmup_ticks={} buy_probability={} panic_sell={} \
+             ex_rpc={} node_rpc={} api_port={} td_port={} db={} db_replica={:?} \
+             mint_accounts={} buy_accounts={}",
+            c.token,
+            LIST_INTERVAL,
+            BUY_INTERVAL,
+            floor_prices,
+            c.list_sum_min,
+            c.list_sum_max,
+            c.buy_budget,
+            c.buy_target_amount,
+            c.partial_fills,
+            c.warmup_ticks,
+            c.buy_probability,
+            c.enable_panic_sell,
+            redact_url(&c.ex_rpc_url),
+            redact_url(&c.node_rpc_url),
+            c.node_api_port,
+            c.node_td_port,
+            redact_url(&c.database_url),
+            c.database_replica_url.as_deref().map(redact_url),
+            self.accounts_mint.len(),
+            self.accounts_buy.len()
+        );
+    }
+
+    /// Marks each pending trade confirmed once MIN_CONFIRMATIONS blocks hold
+    /// it; the rest are checked again next time, so a reorged trade stays
+    /// pending.
+    pub async fn confirm_trades(&self) -> Result<()> {
+        let pending = self.storage.pending_trades().await.context("loading pending trades")?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let tip = self.rpc.latest_height().await.context("reading the chain tip")?;
+        for txid in pending.iter() {
+            let height = match self.rpc.tx_height(txid).await {
+                Ok(Some(height)) => height,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("[confirm] {} lookup failed: {:#}", txid, e);
+                    continue;
+                }
+            };
+            if tip.saturating_sub(height) + 1 >= self.config.min_confirmations {
+                self.storage.confirm_trade(txid).await.context("confirming trade")?;
+                debug!("[confirm] {} confirmed at height {}", txid, height);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn refresh_balances(&self) {
+        let roles = [("mint", &self.accounts_mint), ("buy", &self.accounts_buy)];
+        for (role, accounts) in roles {
+            for account in accounts.iter() {
+                match self.rpc.get_balance(account).await {
+                    Ok(balance) => self.metrics.set_gauge(
+                        "account_balance",
+                        &[("address", account.address.as_str()), ("role", role)],
+                        balance as f64,
+                    ),
+                    Err(e) => warn!("[balance] {} {}: {}", role, account.address, e),
+                }
+            }
+        }
+    }
+}
+
+/// Correlation id shared by every log line of one action: `<tick>-<index>`.
+fn action_id(tick: u64, index: usize) -> String {
+    format!("{}-{}", tick, index)
+}
+
+async fn fetch_with_empty_retry<F, Fut>(
+    retries: u32,
+    delay: Duration,
+    mut fetch: F,
+) -> Result<ListResponse>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ListResponse>>,
+{
+    let mut res = fetch().await?;
+    for _ in 0..retries {
+        if res.total != 0 {
+            break;
+        }
+        spend_retry()?;
+        time::sleep(delay).await;
+        res = fetch().await?;
+    }
+    Ok(res)
+}
+
+/// Adds `delta` to a running total, failing instead of wrapping.
+fn add_checked(total: &AtomicU64, delta: u64, name: &str) -> Result<u64> {
+    let prev = total
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |t| t.checked_add(delta))
+        .map_err(|t| Error::msg(format!("{} total {} + {} overflows", name, t, delta)))?;
+    Ok(prev + delta)
+}
+
+/// Adds the amounts of `items` to `sum`. A sum that overflows or passes
+/// `cap` means a corrupt page, so it is an error rather than a number to act
+/// on.
+fn add_listed(mut sum: u64, items: &[ListItem], cap: Option<u64>) -> Result<u64> {
+    for item in items.iter() {
+        let amount = item.amount_value()?;
+        sum = match sum.checked_add(amount) {
+            Some(sum) if cap.map_or(true, |cap| sum <= cap) => sum,
+            _ => {
+                warn!(
+                    "[anomaly] listing {} amount {} takes the listed sum past {}, ignoring the book",
+                    item.id,
+                    amount,
+                    cap.unwrap_or(u64::MAX)
+                );
+                return Err(Error::msg(format!("listed sum anomaly at listing {}", item.id)));
+            }
+        };
+    }
+    Ok(sum)
+}
+
+/// Sums the book from `first` on, or from just past `resume`, fetching
+/// further pages until the book ends or the running total reaches `stop_at`,
+/// and handing `checkpoint` each page it finishes short of the end. Resumed
+/// scans only return the listings of the pages they fetched.
+async fn sum_listings<F, Fut, C, CFut>(
+    first: ListResponse,
+    resume: Option<ScanCheckpoint>,
+    stop_at: Option<u64>,
+    cap: Option<u64>,
+    mut dedup: PageDedup,
+    mut fetch_page: F,
+    mut checkpoint: C,
+) -> Result<(u64, Vec<ListItem>)>
+where
+    F: FnMut(i32) -> Fut,
+    Fut: Future<Output = Result<ListResponse>>,
+    C: FnMut(ScanCheckpoint) -> CFut,
+    CFut: Future<Output = Result<()>>,
+{
+    let total = first.total;
+    let pages = (total + PAGE_SIZE - 1) / PAGE_SIZE;
+    let (mut page, mut sum, mut page_items) = match resume.filter(|cp| cp.page < pages) {
+        Some(cp) => (cp.page + 1, cp.sum, fetch_page(cp.page + 1).await?.data.unwrap_or_default()),
+        None => (1, 0, first.data.unwrap_or_default()),
+    };
+    let mut items = vec![];
+    loop {
+        let fresh = dedup.filter(page_items)?;
+        sum = add_listed(sum, &fresh, cap)?;
+        items.extend(fresh);
+        if page >= pages || stop_at.map_or(false, |threshold| sum >= threshold) {
+            break;
+        }
+        checkpoint(ScanCheckpoint { page, sum, total }).await?;
+        page += 1;
+        page_items = fetch_page(page).await?.data.unwrap_or_default();
+    }
+    Ok((sum, items))
+}
+
+/// Page `page` of a list scan. With SCAN_ON_PARTIAL_FAILURE=continue a page
+/// that still fails after its retries reads as empty and sets `partial`.
+async fn fetch_or_skip<Fut>(
+    mode: PartialFailure,
+    page: i32,
+    partial: &AtomicBool,
+    fetch: Fut,
+) -> Result<ListResponse>
+where
+    Fut: Future<Output = Result<ListResponse>>,
+{
+    match fetch.await {
+        Err(e) if mode == PartialFailure::Continue => {
+            warn!("[List] page {} failed, scanning on without it: {:#}", page, e);
+            partial.store(true, Ordering::SeqCst);
+            Ok(ListResponse {
+                total: 0,
+                current_page: page,
+                page_size: PAGE_SIZE,
+                total_pages: 0,
+                data: None,
+            })
+        }
+        result => result,
+    }
+}
+
+/// Runs `cycle` every `period` from `start`, without waiting on any other
+/// cycle, until it fails fatally.
+async fn cycle_loop<F, Fut>(start: time::Instant, period: Duration, mut cycle: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut timer = time::interval_at(start, period);
+    loop {
+        timer.tick().await;
+        cycle().await?;
+    }
+}
+
+/// One list cycle per token, at the token's current floor price.
+async fn list_all(
+    servers: &[BotServer],
+    price_indices: &[AtomicUsize],
+    metrics: &Metrics,
+) -> Result<()> {
+    for (server, price_index) in servers.iter().zip(price_indices) {
+        let mode = server.config.floor_mode;
+        let list_price = mode.floor(&FLOOR_PRICES, price_index.load(Ordering::SeqCst));
+        let token = &server.config.token;
+        guarded(metrics, "list", token, server.run_list_cycle(list_price)).await?;
+    }
+    Ok(())
+}
+
+/// One buy cycle per token, each moving its token on to the next floor price.
+async fn buy_all(
+    servers: &[BotServer],
+    price_indices: &[AtomicUsize],
+    metrics: &Metrics,
+) -> Result<()> {
+    for (server, price_index) in servers.iter().zip(price_indices) {
+        let mode = server.config.floor_mode;
+        let cur_floor_price = mode.floor(&FLOOR_PRICES, price_index.load(Ordering::SeqCst));
+        let token = &server.config.token;
+        guarded(metrics, "buy", token, server.run_buy_cycle(cur_floor_price)).await?;
+
+        let next = mode.next(price_index.load(Ordering::SeqCst), FLOOR_PRICES.len());
+        price_index.store(next, Ordering::SeqCst);
+        let cursor = server.cursor_name(CURSOR_PRICE_INDEX);
+        let save = server.storage.save_cursor(&cursor, next as i64);
+        guarded(metrics, "cursor", token, save).await?;
+    }
+    Ok(())
+}
+
+/// Awaits one cycle, logging and counting its error so the loop carries on
+/// to the next tick. Only errors `is_fatal` deems unrecoverable are returned.
+async fn guarded<Fut>(metrics: &Metrics, cycle: &'static str, token: &str, fut: Fut) -> Result<()>
+where
+    Fut: Future<Output = Result<()>>,
+{
+    match fut.await {
+        Ok(()) => Ok(()),
+        Err(e) if is_fatal(&e) => Err(e.context(format!("{} cycle for {}", cycle, token))),
+        Err(e) => {
+            warn!("[{}] {} cycle failed: {:#}", cycle, token, e);
+            metrics.inc_counter("cycle_errors_total", &[("cycle", cycle), ("token", token)]);
+            Ok(())
+        }
+    }
+}
+
+/// The database pool shutting down or being misconfigured won't fix itself.
+fn is_fatal(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::PoolClosed) | Some(sqlx::Error::Configuration(_))
+    )
+}
+
+/// Whether the cycles may run now; logs once when pausing and resuming.
+fn check_active_window(window: Option<&ActiveWindow>, paused: &mut bool) -> bool {
+    let active = window.map_or(true, |w| w.contains(Utc::now()));
+    if !active && !*paused {
+        info!("[schedule] outside trading window, paused");
+    } else if active && *paused {
+        info!("[schedule] trading window open, resuming");
+    }
+    *paused = !active;
+    active
+}
+
+/// Mint accounts also create the listings, so they are only optional when
+/// both minting and listing are off.
+fn check_account_pools(config: &Config, mint: &[FraAccount], buy: &[FraAccount]) -> Result<()> {
+    if mint.is_empty() && !(config.no_mint && config.no_list) {
+        return Err(Error::msg(format!(
+            "{} has no accounts; add some or run with --no-mint --no-list",
+            accounts_path(config, ACCOUNT_MINT)
+        )));
+    }
+    if buy.is_empty() && !config.no_buy {
+        return Err(Error::msg(format!(
+            "{} has no accounts; add some or run with --no-buy",
+            accounts_path(config, ACCOUNT_BUY)
+        )));
+    }
+    Ok(())
+}
+
+/// The treasury mnemonic for `fund`: from `--from-file`, else
+/// TREASURY_MNEMONIC, else `--from`.
+fn treasury_mnemonic(from: Option<String>, from_file: Option<&str>) -> Result<String> {
+    if let Some(path) = from_file {
+        let mnemonic = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        return Ok(mnemonic.trim().to_string());
+    }
+    match std::env::var("TREASURY_MNEMONIC").ok().filter(|m| !m.trim().is_empty()) {
+        Some(mnemonic) => Ok(mnemonic.trim().to_string()),
+        None => from.ok_or_else(|| {
+            Error::msg("fund needs --from-file, TREASURY_MNEMONIC or --from for the treasury")
+        }),
+    }
+}
+
+/// Where the account file `name` lives under ACCOUNTS_DIR.
+fn accounts_path(config: &Config, name: &str) -> String {
+    Path::new(&config.accounts_dir).join(name).to_string_lossy().into_owned()
+}
+
+/// Reads the accounts at `path`, generating `amount` of them, at most
+/// MAX_GEN_ACCOUNTS, when the file doesn't exist yet.
+fn load_accounts(path: &str, amount: i32, config: &Config) -> Result<Vec<FraAccount>> {
+    match read_file(path) {
+        Ok(contents) => {
+            verify_checksum(path, &contents, config.accounts_checksum_strict)?;
+            let accounts =
+                serde_json::from_str(&contents).with_context(|| format!("parsing {}", path))?;
+            info!("Reading {}... ok", path);
+            Ok(accounts)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if amount > config.max_gen_accounts {
+                return Err(Error::msg(format!(
+                    "--accounts {} is above MAX_GEN_ACCOUNTS ({}) for {}",
+                    amount, config.max_gen_accounts, path
+                )));
+            }
+            let accounts = gen_accounts(amount)?;
+            if let Some(dir) = Path::new(path).parent() {
+                retry_io(FILE_IO_ATTEMPTS, FILE_IO_BACKOFF, || fs::create_dir_all(dir))
+                    .with_context(|| format!("creating {}", dir.display()))?;
+            }
+            let s = serde_json::to_string_pretty(&accounts)?;
+            write_file(path, &s)?;
+            write_checksum(path, &s)?;
+            info!("Generating {}... ok", path);
+            Ok(accounts)
+        }
+        Err(e) => Err(Error::new(e).context(format!("reading {}", path))),
+    }
+}
+
+/// Reads `path`, retrying transient errors; NotFound comes back at once.
+fn read_file(path: &str) -> io::Result<String> {
+    retry_io(FILE_IO_ATTEMPTS, FILE_IO_BACKOFF, || fs::read_to_string(path))
+}
+
+/// Writes `contents` to `path`, retrying transient errors.
+fn write_file(path: &str, contents: &str) -> Result<()> {
+    retry_io(FILE_IO_ATTEMPTS, FILE_IO_BACKOFF, || fs::write(path, contents))
+        .with_context(|| format!("writing {}", path))
+}
+
+fn checksum_path(path: &str) -> String {
+    format!("{}.sha256", path)
+}
+
+/// Writes the SHA-256 of `contents` next to the accounts file at `path`.
+fn write_checksum(path: &str, contents: &str) -> Result<()> {
+    let checksum = hex::encode(Sha256::digest(contents.as_bytes()));
+    write_file(&checksum_path(path), &checksum)
+}
+
+/// Checks `contents` of `path` against its sidecar checksum. A mismatch or a
+/// missing sidecar is an error when `strict`, otherwise a warning; sidecars
+/// are only written with the accounts file, so deleting one can't re-sign an
+/// edited file. Returns whether the file matched.
+fn verify_checksum(path: &str, contents: &str, strict: bool) -> Result<bool> {
+    let expected = match read_file(&checksum_path(path)) {
+        Ok(expected) => expected,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let msg = format!("{} has no checksum {}", path, checksum_path(path));
+            if strict {
+                return Err(Error::msg(msg));
+            }
+            warn!("!!! {}, it can't be checked !!!", msg);
+            return Ok(false);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if expected.trim() == hex::encode(Sha256::digest(contents.as_bytes())) {
+        return Ok(true);
+    }
+    let msg = format!(
+        "{} does not match {}: the accounts file was edited or tampered with",
+        path,
+        checksum_path(path)
+    );
+    if strict {
+        return Err(Error::msg(msg));
+    }
+    warn!("!!! {} !!!", msg);
+    Ok(false)
+}
+
+/// Seed of the account shuffle: SHUFFLE_SEED, else the one stored by an
+/// earlier run, else a fresh one. It is stored so the order outlives restarts.
+async fn shuffle_seed(storage: &dyn StorageBackend, configured: Option<u64>) -> Result<u64> {
+    let seed = match configured {
+        Some(seed) => seed,
+        None => {
+            let stored = storage.load_cursor(CURSOR_SHUFFLE_SEED).await;
+            match stored.context("loading shuffle seed")? {
+                Some(seed) => return Ok(seed as u64),
+                None => rand::random(),
+            }
+        }
+    };
+    storage
+        .save_cursor(CURSOR_SHUFFLE_SEED, seed as i64)
+        .await
+        .context("saving shuffle seed")?;
+    Ok(seed)
+}
+
+/// [`load_accounts`] on the blocking pool, where its retries may sleep.
+async fn load_accounts_blocking(
+    path: &str,
+    amount: i32,
+    config: &Config,
+) -> Result<Vec<FraAccount>> {
+    let (path, config) = (path.to_string(), config.clone());
+    task::spawn_blocking(move || load_accounts(&path, amount, &config)).await?
+}
+
+/// [`read_accounts`] on the blocking pool, where its retries may sleep.
+async fn read_accounts_blocking(path: &str) -> Result<Vec<FraAccount>> {
+    let path = path.to_string();
+    task::spawn_blocking(move || read_accounts(&path)).await?
+}
+
+/// Reads an account file without generating one; a missing file is empty.
+fn read_accounts(path: &str) -> Result<Vec<FraAccount>> {
+    match read_file(path) {
+        Ok(contents) => {
+            verify_checksum(path, &contents, false)?;
+            serde_json::from_str(&contents).with_context(|| format!("parsing {}", path))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("{} not found", path);
+            Ok(vec![])
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Connections are pinged before each use and recycled after
+/// DB_MAX_LIFETIME, so after a Postgres restart the stale ones are dropped and
+/// replaced on the next acquire. Queries already in flight during the restart
+/// fail and are logged by the cycle guard; the following tick recovers.
+/// To check by hand: run the bot, `pg_ctl restart` (or restart the container)
+/// and watch the next cycles succeed without restarting the bot.
+async fn connect_storage(config: &Config) -> Result<Arc<dyn StorageBackend>> {
+    let pool = connect_pool(config, "primary", &config.database_url).await?;
+    info!("Connecting DB...ok");
+    let storage = match config.database_replica_url.as_deref() {
+        Some(url) => {
+            let replica = connect_pool(config, "replica", url).await?;
+            info!("Connecting replica DB...ok");
+            PgStorage::new_with_replica(pool, replica)
+        }
+        None => PgStorage::new(pool),
+    };
+    Ok(Arc::new(storage))
+}
+
+/// Opens the `which` pool at `url`; errors name the pool and show the URL
+/// without its password.
+async fn connect_pool(config: &Config, which: &str, url: &str) -> Result<Pool<Postgres>> {
+    PoolOptions::new()
+        .max_connections(config.pool_sizes().db)
+        .idle_timeout(Duration::from_secs(config.db_idle_timeout_secs))
+        .max_lifetime(DB_MAX_LIFETIME)
+        .acquire_timeout(DB_ACQUIRE_TIMEOUT)
+        .test_before_acquire(true)
+        .connect(url)
+        .await
+        .with_context(|| format!("connecting {} DB {}", which, redact_url(url)))
+}
+
+/// Loads the env file at `path`, or the nearest `.env`, retrying transient
+/// read errors; the retries go to `retries`, as the logger isn't up yet.
+fn load_env(path: Option<&str>, retries: &mut Vec<String>) -> io::Result<()> {
+    retry_io_with(FILE_IO_ATTEMPTS, FILE_IO_BACKOFF, |retry| retries.push(retry), || {
+        let loaded = match path {
+            Some(path) => dotenv::from_path(path),
+            None => dotenv().map(|_| ()),
+        };
+        match loaded {
+            Ok(()) => Ok(()),
+            Err(dotenv::Error::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    })
+}
+
+/// Loads `--env-file`, then `.env`. dotenv never overrides a variable that is
+/// already set, so the named file wins over `.env`.
+fn load_env_files(env_file: Option<&str>, retries: &mut Vec<String>) -> Result<()> {
+    if let Some(path) = env_file {
+        if !Path::new(path).is_file() {
+            return Err(Error::msg(format!("--env-file {} does not exist", path)));
+        }
+        load_env(Some(path), retries).with_context(|| format!("loading --env-file {}", path))?;
+    }
+    load_env(None, retries).ok();
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    // the env files may set RUST_LOG, so they load before the subscriber and
+    // their retries are reported once it is installed
+    let env_file = args.env_file.clone();
+    let (loaded, io_retries) = task::spawn_blocking(move || {
+        let mut retries = vec![];
+        let loaded = load_env_files(env_file.as_deref(), &mut retries);
+        (loaded, retries)
+    })
+    .await?;
+    // `log` records are forwarded into tracing so they pick up the active spans.
+    // The cycles report through them, so they show at info unless RUST_LOG says otherwise.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if args.output == Output::Json {
+        // keep stdout for the JSON document
+        subscriber.with_writer(io::stderr).init();
+    } else {
+        subscriber.with_writer(io::stdout).init();
+    }
+    for retry in io_retries {
+        warn!("{}", retry);
+    }
+    loaded?;
+
+    // needs neither configuration nor the database
+    match args.command {
+        Some(Command::BenchGen { count }) => return commands::bench_gen(count, args.output),
+        Some(Command::Version) => return commands::version(args.output),
+        Some(Command::ValidateConfig) => {
+            return commands::validate_config(Config::from_env(), &FLOOR_PRICES, args.output)
+        }
+        _ => {}
+    }
+
+    let mut config = Config::from_env().context("reading configuration")?;
+    if let Some(problem) = config.problems().first() {
+        return Err(Error::msg(format!("{} (see validate-config)", problem)));
+    }
+    config.no_mint |= args.no_mint;
+    config.no_buy |= args.no_buy;
+    config.no_list |= args.no_list;
+    let rpc = Rpc::new(
+        &config.ex_rpc_url,
+        &config.node_api_url()?,
+        &config.node_td_url()?,
+    )
+    .context("building RPC clients")?
+    .with_min_utxo_value(config.min_utxo_value)
+    .with_max_inputs(config.max_tx_inputs, config.consolidate_utxos);
+    let rpc = match config.rpc_proxy.as_deref() {
+        Some(proxy) => rpc.with_proxy(proxy)?,
+        None => rpc,
+    };
+    let pools = config.pool_sizes();
+    let concurrency = config.concurrency();
+    info!("[startup] pools: db {} rpc {} for {} tasks at once", pools.db, pools.rpc, concurrency);
+    if (pools.db as usize) < concurrency || pools.rpc < concurrency {
+        warn!("[startup] a pool is smaller than the {} tasks that may use it at once", concurrency);
+    }
+    let rpc = rpc.with_pool_size(pools.rpc)?;
+    let rpc = if config.log_rpc_bodies {
+        rpc.with_body_logging(config.rpc_log_max_bytes, config.rpc_log_redact)
+    } else {
+        rpc
+    };
+    let mint_path = accounts_path(&config, ACCOUNT_MINT);
+    let buy_path = accounts_path(&config, ACCOUNT_BUY);
+
+    match args.command {
+        Some(Command::Fund {
+            from,
+            from_file,
+            amount,
+            threshold,
+        }) => {
+            let from = treasury_mnemonic(from, from_file.as_deref())?;
+            let accounts_mint = load_accounts_blocking(&mint_path, args.accounts, &config).await?;
+            let accounts_buy = load_accounts_blocking(&buy_path, args.accounts, &config).await?;
+            let treasury = FraAccount::from_mnemonic(&from).context("parsing --from mnemonic")?;
+            let roles = [("mint", &accounts_mint), ("buy", &accounts_buy)];
+            let threshold = threshold.unwrap_or(amount);
+            return commands::fund(&rpc, &treasury, &roles, amount, threshold, args.output).await;
+        }
+        Some(Command::ExportTrades { out, since }) => {
+            let storage = connect_storage(&config).await?;
+            return commands::export_trades(storage.as_ref(), &out, &since, args.output).await;
+        }
+        Some(Command::ReplayMetrics { out, since }) => {
+            let storage = connect_storage(&config).await?;
+            return commands::replay_metrics(storage.as_ref(), &out, &since, args.output).await;
+        }
+        Some(Command::CancelAll { force }) => {
+            let mut accounts = read_accounts_blocking(&mint_path).await?;
+            accounts.extend(read_accounts_blocking(&buy_path).await?);
+            let storage = connect_storage(&config).await?;
+            let tokens = &config.tokens;
+            let output = args.output;
+            return commands::cancel_all(&rpc, storage.as_ref(), tokens, &accounts, force, output)
+                .await;
+        }
+        Some(Command::Replay {
+            file,
+            floor,
+            list_price,
+        }) => {
+            let contents = fs::read_to_string(&file).with_context(|| format!("reading {}", file))?;
+            let book: ListResponse = serde_json::from_str(&contents)
+                .with_context(|| format!("parsing {} as a list response", file))?;
+            let items = book.data.unwrap_or_default();
+            let server = BotServer::new(
+                config,
+                Arc::new(MemStorage::default()),
+                Arc::new(rpc),
+                read_accounts_blocking(&mint_path).await?,
+                read_accounts_blocking(&buy_path).await?,
+            )?;
+            let floor = floor.unwrap_or(FLOOR_PRICES[1]);
+            let plan = server.plan(&items, list_price.unwrap_or(floor), floor)?;
+            return args.output.result(&plan, plan.describe("replay", items.len()));
+        }
+        Some(Command::Status) => {
+            let storage = connect_storage(&config).await?;
+            let rpc = Arc::new(rpc);
+            let accounts_mint = read_accounts_blocking(&mint_path).await?;
+            let accounts_buy = read_accounts_blocking(&buy_path).await?;
+            let mut report = vec![];
+            let mut text = vec![];
+            for token in config.tokens.iter() {
+                let server = BotServer::new(
+                    config.for_token(token),
+                    storage.clone(),
+                    rpc.clone(),
+                    accounts_mint.clone(),
+                    accounts_buy.clone(),
+                )?;
+                server.restore_progress().await?;
+                let price_index = storage
+                    .load_cursor(&server.cursor_name(CURSOR_PRICE_INDEX))
+                    .await?
+                    .unwrap_or(1) as usize;
+                let floor = server.config.floor_mode.floor(&FLOOR_PRICES, price_index);
+                let listings = server.get_all_token_listings(token, &ListQuery::default()).await?;
+                let items = listings.items;
+                let plan = server.plan(&items, floor, floor)?;
+
+                let mut cheapest: Vec<&ListItem> = items.iter().collect();
+                cheapest.sort_by_key(|item| item.price_value().unwrap_or(u64::MAX));
+                cheapest.truncate(STATUS_CHEAPEST);
+                text.push(format!("[status] {}: floor {}", token, floor));
+                if listings.truncated {
+                    text.push(format!("[status] {}: book truncated at the deadline", token));
+                }
+                for item in cheapest.iter() {
+                    text.push(format!(
+                        "[status] {}: listing {} of {} at {}",
+                        token, item.id, item.amount, item.price
+                    ));
+                }
+                text.push(plan.describe("status", items.len()));
+                report.push(serde_json::json!({
+                    "token": token,
+                    "cheapest": cheapest,
+                    "plan": plan,
+                    "truncated": listings.truncated,
+                }));
+            }
+            return args.output.result(&report, text.join("\n"));
+        }
+        Some(Command::Eta { window_hours }) => {
+            let storage = connect_storage(&config).await?;
+            commands::eta(storage.as_ref(), &config, window_hours * 3600, args.output).await?;
+            return Ok(());
+        }
+        Some(Command::Diagnose) => {
+            commands::diagnose(&rpc, &config, args.output).await?;
+            return Ok(());
+        }
+        Some(Command::VerifyAccounts { fix }) => {
+            let accounts_mint = read_accounts_blocking(&mint_path).await?;
+            let accounts_buy = read_accounts_blocking(&buy_path).await?;
+            let storage = connect_storage(&config).await?;
+            let roles = [
+                (ACCOUNT_TYPE_MINT, "mint", &accounts_mint),
+                (ACCOUNT_TYPE_BUY, "buy", &accounts_buy),
+            ];
+            commands::verify_accounts(storage.as_ref(), &roles, fix, args.output).await?;
+            return Ok(());
+        }
+        Some(Command::BenchGen { .. })
+        | Some(Command::Version)
+        | Some(Command::ValidateConfig)
+        | None => {}
+    }
+
+    let mut accounts_mint = load_accounts_blocking(&mint_path, args.accounts, &config).await?;
+    let mut accounts_buy = load_accounts_blocking(&buy_path, args.accounts, &config).await?;
+    check_account_pools(&config, &accounts_mint, &accounts_buy)?;
+    let storage = connect_storage(&config).await?;
+    if args.prepare_only {
+        storage.migrate().await.context("applying schema.sql")?;
+        let server = BotServer::new(config, storage, Arc::new(rpc), accounts_mint, accounts_buy)?;
+        server.prepare_accounts().await.context("registering accounts")?;
+        info!("[startup] accounts prepared, exiting (--prepare-only)");
+        return Ok(());
+    }
+    if config.shuffle_accounts {
+        let seed = shuffle_seed(storage.as_ref(), config.shuffle_seed).await?;
+        shuffle_accounts(&mut accounts_mint, seed);
+        shuffle_accounts(&mut accounts_buy, seed.wrapping_add(1));
+        info!("[startup] accounts shuffled with seed {}", seed);
+    }
+
+    let floor_prices = &FLOOR_PRICES;
+    info!("[startup] {}", commands::version_line());
+
+    let rpc = Arc::new(rpc);
+    let metrics = Arc::new(Metrics::default());
+    let inflight = Arc::new(Semaphore::new(config.max_inflight_actions));
+    let account_locks = Arc::new(AccountLocks::default());
+    let account_rates = Arc::new(AccountRateLimit::new(
+        config.per_account_rate_limit,
+        config.per_account_burst,
+    ));
+    let mut servers = vec![];
+    let mut price_indices = vec![];
+    for token in config.tokens.iter() {
+        // custom buy logic plugs in here
+        let strategy: Box<dyn BuyStrategy> = match config.ma_window_secs {
+            Some(window) => Box::new(MovingAverageFloor::new(window)),
+            None => Box::new(FloorStrategy),
+        };
+        let server = BotServer::new(
+            config.for_token(token),
+            storage.clone(),
+            rpc.clone(),
+            accounts_mint.clone(),
+            accounts_buy.clone(),
+        )?
+        .with_metrics(metrics.clone())
+        .with_inflight(inflight.clone())
+        .with_account_locks(account_locks.clone())
+        .with_account_rates(account_rates.clone())
+        .with_strategy(strategy);
+        server.log_banner(floor_prices);
+        server
+            .restore_progress()
+            .await
+            .with_context(|| format!("restoring progress of {}", token))?;
+        server
+            .assign_start_offsets()
+            .await
+            .with_context(|| format!("assigning start offsets of {}", token))?;
+        let price_index = storage
+            .load_cursor(&server.cursor_name(CURSOR_PRICE_INDEX))
+            .await
+            .with_context(|| format!("loading price index of {}", token))?
+            .unwrap_or(1) as usize;
+        servers.push(server);
+        price_indices.push(AtomicUsize::new(price_index));
+    }
+    let servers = Arc::new(servers);
+    let price_indices = Arc::new(price_indices);
+    // Accounts and balances are shared by every token.
+    let server = &servers[0];
+    server.prepare_accounts().await.context("registering accounts")?;
+    for server in servers.iter() {
+        server.resume_queue().await?;
+    }
+
+    if let Some(addr) = server.config.metrics_addr.clone() {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics, &addr).await {
+                warn!("metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    // tokio intervals fire at once; start them after the grace period instead
+    let grace = Duration::from_secs(server.config.startup_grace_secs);
+    info!("[startup] first cycles in {:?}", grace);
+    let start = time::Instant::now() + grace;
+    let mut timer1 = time::interval_at(start, LIST_INTERVAL);
+    let mut timer2 = time::interval_at(start, BUY_INTERVAL);
+    let mut timer4 = time::interval_at(start, MINT_INTERVAL);
+    let mut timer5 = time::interval_at(start, REPRICE_INTERVAL);
+    let mut timer6 = time::interval_at(start, CONFIRM_INTERVAL);
+    let balance_refresh = Duration::from_secs(server.config.balance_refresh_secs);
+    let mut timer3 = time::interval_at(start, balance_refresh);
+    let window = server.config.active_window;
+    let mut paused = false;
+
+    // list and buy get a task each; the loop below keeps the other timers
+    let concurrent = server.config.concurrent_cycles;
+    let mut cycles = JoinSet::new();
+    if concurrent {
+        info!("[startup] list and buy cycles run concurrently");
+        for (period, buy) in [(LIST_INTERVAL, false), (BUY_INTERVAL, true)] {
+            let (servers, indices, metrics) =
+                (servers.clone(), price_indices.clone(), metrics.clone());
+            let mut paused = false;
+            cycles.spawn(cycle_loop(start, period, move || {
+                let active = check_active_window(window.as_ref(), &mut paused);
+                let servers = servers.clone();
+                let indices = indices.clone();
+                let metrics = metrics.clone();
+                async move {
+                    match (active, buy) {
+                        (false, _) => Ok(()),
+                        (true, false) => list_all(&servers, &indices, &metrics).await,
+                        (true, true) => buy_all(&servers, &indices, &metrics).await,
+                    }
+                }
+            }));
+        }
+    }
+
+    loop {
+        tokio::select! {
+            _ = timer1.tick(), if !concurrent => {
+                if !check_active_window(window.as_ref(), &mut paused) {
+                    continue;
+                }
+                list_all(&servers, &price_indices, &metrics).await?;
+            },
+            _ = timer2.tick(), if !concurrent => {
+                if !check_active_window(window.as_ref(), &mut paused) {
+                    continue;
+                }
+                buy_all(&servers, &price_indices, &metrics).await?;
+            }
+            Some(res) = cycles.join_next() => {
+                res.context("cycle task panicked")??;
+            }
+            _ = timer3.tick() => {
+                server.refresh_balances().await;
+                // top-ups move funds, so they keep to the active window too
+                if server.config.auto_topup && check_active_window(window.as_ref(), &mut paused) {
+                    let token = &server.config.token;
+                    guarded(&metrics, "topup", token, server.top_up()).await?;
+                }
+            }
+            _ = timer6.tick() => {
+                // trades are shared by every token
+                let token = &server.config.token;
+                guarded(&metrics, "confirm", token, server.confirm_trades()).await?;
+            }
+            _ = timer4.tick() => {
+                if !check_active_window(window.as_ref(), &mut paused) {
+                    continue;
+                }
+                for server in servers.iter() {
+                    let token = &server.config.token;
+                    guarded(&metrics, "mint", token, server.run_mint_cycle()).await?;
+                }
+            }
+            _ = timer5.tick() => {
+                if !check_active_window(window.as_ref(), &mut paused) {
+                    continue;
+                }
+                for (server, price_index) in servers.iter().zip(price_indices.iter()) {
+                    let price_index = price_index.load(Ordering::SeqCst);
+                    let list_price = server.config.floor_mode.floor(floor_prices, price_index);
+                    let token = &server.config.token;
+                    guarded(&metrics, "reprice", token, server.run_reprice_cycle(list_price))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::db::mem::MemStorage;
+    use crate::db::StorageBackend;
+    use crate::exchange::mock::MockExchange;
+    use crate::types::mock::{json_response, mock_server};
+    use crate::types::{
+        FraAccount, ListItem, ListQuery, ListResponse, QueuedAction, Rpc, Trade, TradeAction,
+    };
+    use crate::utils::{
+        gen_accounts, now_secs, start_offsets, DbFailurePolicy, PageDedup, PartialFailure,
+        ScanCheckpoint,
+    };
+    use crate::wallet::mock::MockWallet;
+    use crate::metrics::Metrics;
+    use crate::{
+        check_account_pools, cycle_loop, fetch_or_skip, fetch_with_empty_retry, guarded,
+        load_accounts, sum_listings, treasury_mnemonic, verify_checksum, BotServer,
+        ACCOUNT_TYPE_BUY, ACCOUNT_TYPE_MINT, MINT_LIMIT, PAGE_SIZE,
+    };
+    use anyhow::Error;
+    use anyhow::Result;
+    use futures::future::{ready, Ready};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time;
+
+    /// A scan checkpoint sink that keeps nothing.
+    fn skip(_: ScanCheckpoint) -> Ready<Result<()>> {
+        ready(Ok(()))
+    }
+
+    /// A server whose RPCs all point at a closed port, on fresh storage.
+    fn offline_server(
+        config: Config,
+        mint: Vec<FraAccount>,
+        buy: Vec<FraAccount>,
+    ) -> Result<BotServer> {
+        offline_server_on(Arc::new(MemStorage::default()), config, mint, buy)
+    }
+
+    /// `offline_server` on `storage`, for tests that look at what was stored
+    /// or restart on it.
+    fn offline_server_on(
+        storage: Arc<dyn StorageBackend>,
+        config: Config,
+        mint: Vec<FraAccount>,
+        buy: Vec<FraAccount>,
+    ) -> Result<BotServer> {
+        let url = "http://127.0.0.1:1";
+        let rpc = Arc::new(Rpc::new(url, url, url)?);
+        BotServer::new(config, storage, rpc, mint, buy)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_empty_retry() -> Result<()> {
+        let calls = AtomicUsize::new(0);
+        let calls = &calls;
+        let res = fetch_with_empty_retry(2, Duration::ZERO, move || async move {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Ok(ListResponse::from_items(vec![]))
+            } else {
+                Ok(ListResponse::from_items(vec![ListItem::sample(1, 100, 100)]))
+            }
+        })
+        .await?;
+
+        assert_eq!(res.total, 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sum_listings_rejects_overflow() -> Result<()> {
+        let first = || {
+            let mut first = ListResponse::from_items(vec![ListItem::sample(1, 100, 100)]);
+            first.total = 100;
+            first
+        };
+        let page = |amount: u64| {
+            move |_| async move {
+                Ok(ListResponse::from_items(vec![ListItem::sample(2, amount, 1)]))
+            }
+        };
+
+        let dedup = PageDedup::default;
+        let sum = |cap: Option<u64>, amount: u64| {
+            sum_listings(first(), None, None, cap, dedup(), page(amount), skip)
+        };
+        assert!(sum(None, u64::MAX).await.is_err());
+        // a cap catches absurd but representable sums too
+        assert!(sum(Some(1 << 40), 1 << 60).await.is_err());
+        assert!(sum(Some(1 << 40), 100).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sum_listings_duplicate_ids() -> Result<()> {
+        let first = || {
+            let mut first = ListResponse::from_items(vec![
+                ListItem::sample(1, 100, 1),
+                ListItem::sample(2, 100, 1),
+            ]);
+            first.total = 100;
+            first
+        };
+        // page 2 repeats listing 2 from page 1
+        let overlapping = |_| async {
+            Ok(ListResponse::from_items(vec![
+                ListItem::sample(2, 100, 1),
+                ListItem::sample(3, 100, 1),
+            ]))
+        };
+
+        let scan = |strict| {
+            sum_listings(first(), None, None, None, PageDedup::new(strict), overlapping, skip)
+        };
+        let (sum, items) = scan(false).await?;
+        assert_eq!(sum, 300);
+        assert_eq!(items.len(), 3);
+        assert!(scan(true).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sum_listings_stops_at_threshold() -> Result<()> {
+        let mut first = ListResponse::from_items(vec![ListItem::sample(1, 100, 100)]);
+        first.total = 150;
+        let calls = AtomicUsize::new(0);
+        let calls = &calls;
+        let dedup = PageDedup::default();
+        let fetch = move |page| async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(ListResponse::from_items(vec![ListItem::sample(page, 100, 100)]))
+        };
+        let (sum, items) = sum_listings(first, None, Some(150), None, dedup, fetch, skip).await?;
+
+        assert_eq!(sum, 200);
+        assert_eq!(items.len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_page_aborts_or_marks_scan_partial() -> Result<()> {
+        let scan = |mode| async move {
+            let mut first = ListResponse::from_items(vec![ListItem::sample(1, 100, 1)]);
+            first.total = PAGE_SIZE * 3;
+            let partial = AtomicBool::new(false);
+            let fetch = |page| {
+                let fetch = async move {
+                    if page == 2 {
+                        return Err(Error::msg("exchange error 502"));
+                    }
+                    Ok(ListResponse::from_items(vec![ListItem::sample(page, 100, 1)]))
+                };
+                fetch_or_skip(mode, page, &partial, fetch)
+            };
+            let dedup = PageDedup::default();
+            let scanned = sum_listings(first, None, None, None, dedup, fetch, skip).await;
+            scanned.map(|(sum, _)| (sum, partial.load(Ordering::SeqCst)))
+        };
+
+        assert!(scan(PartialFailure::Abort).await.is_err());
+        assert_eq!(scan(PartialFailure::Continue).await?, (200, true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sum_listings_resumes_mid_scan() -> Result<()> {
+        let mut first = ListResponse::from_items(vec![ListItem::sample(1, 100, 1)]);
+        first.total = PAGE_SIZE * 4;
+        let fetched = Mutex::new(vec![]);
+        let saved = Mutex::new(vec![]);
+        let fetch = |page| {
+            fetched.lock().unwrap().push(page);
+            async move { Ok(ListResponse::from_items(vec![ListItem::sample(page, 100, 1)])) }
+        };
+        let save = |checkpoint: ScanCheckpoint| {
+            saved.lock().unwrap().push((checkpoint.page, checkpoint.sum));
+            async { Ok(()) }
+        };
+        let resume = ScanCheckpoint {
+            page: 2,
+            sum: 700,
+            total: PAGE_SIZE * 4 + 5,
+        };
+        assert!(resume.resumes(first.total, 10));
+        assert!(!resume.resumes(PAGE_SIZE * 2, 10));
+
+        let dedup = PageDedup::default();
+        let (sum, items) = sum_listings(first, Some(resume), None, None, dedup, fetch, save).await?;
+
+        assert_eq!(*fetched.lock().unwrap(), vec![3, 4]);
+        assert_eq!(*saved.lock().unwrap(), vec![(3, 800)]);
+        assert_eq!(sum, 900);
+        assert_eq!(items.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_budgets_are_isolated() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa", "bbbb"]);
+        config.buy_budget = Some(1000);
+        config.token_budgets.insert("bbbb".to_string(), 500);
+        let storage = Arc::new(MemStorage::default());
+        let server = |token: &str| {
+            let config = config.for_token(token);
+            offline_server_on(storage.clone(), config, vec![], vec![])
+        };
+
+        let a = server("aaaa")?;
+        let b = server("bbbb")?;
+        a.record_spend(600, 6).await?;
+        assert_eq!(a.budget_left(), Some(400));
+        assert_eq!(b.budget_left(), Some(500));
+
+        // a restarted process picks up each token's own spend
+        let a = server("aaaa")?;
+        let b = server("bbbb")?;
+        a.restore_progress().await?;
+        b.restore_progress().await?;
+        assert_eq!(a.budget_left(), Some(400));
+        assert_eq!(b.budget_left(), Some(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_listing_is_swept() -> Result<()> {
+        let accounts = gen_accounts(1)?;
+        let listing = |id, age| ListItem {
+            from: accounts[0].address.clone(),
+            create_time: now_secs() - age,
+            ..ListItem::sample(id, 100, 5)
+        };
+        let book = ListResponse::from_items(vec![listing(1, 7200), listing(2, 60)]);
+        let url = mock_server(vec![
+            json_response(&serde_json::to_string(&book)?),
+            json_response("{}"),
+        ])
+        .await;
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.listing_ttl_secs = Some(3600);
+        let storage = Arc::new(MemStorage::default());
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let server = BotServer::new(config, storage.clone(), rpc, accounts.clone(), vec![])?;
+
+        server.run_reprice_cycle(5).await?;
+
+        let trades = storage.trades.lock().unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].action, TradeAction::Cancel);
+        assert_eq!(trades[0].txid, "1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disabled_buy_never_submits() -> Result<()> {
+        let book = ListResponse::from_items(vec![ListItem::sample(1, 100, 1)]);
+        let url = mock_server(vec![json_response(&serde_json::to_string(&book)?)]).await;
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.no_buy = true;
+        let storage = Arc::new(MemStorage::default());
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let server = BotServer::new(config, storage.clone(), rpc, vec![], vec![])?;
+
+        server.run_buy_cycle(1000).await?;
+
+        assert!(storage.actions.lock().unwrap().is_empty());
+        assert!(storage.trades.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_guarded_cycle_keeps_looping() -> Result<()> {
+        let metrics = Metrics::default();
+        let mut runs = 0;
+        for tick in 0..3 {
+            runs += 1;
+            let cycle = async move {
+                if tick == 1 {
+                    return Err(Error::msg("rpc down"));
+                }
+                Ok(())
+            };
+            guarded(&metrics, "buy", "aaaa", cycle).await?;
+        }
+        assert_eq!(runs, 3);
+        assert!(metrics
+            .render()
+            .contains("cycle_errors_total{cycle=\"buy\",token=\"aaaa\"} 1"));
+
+        let fatal = async { Err(Error::new(sqlx::Error::PoolClosed)) };
+        assert!(guarded(&metrics, "buy", "aaaa", fatal).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_cycles_progress_independently() -> Result<()> {
+        let counter = |runs: &Arc<AtomicUsize>, stall: Duration| {
+            let runs = runs.clone();
+            move || {
+                let runs = runs.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    time::sleep(stall).await;
+                    Ok(())
+                }
+            }
+        };
+        let (list_runs, buy_runs) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let start = time::Instant::now();
+        let period = Duration::from_millis(20);
+        // a list scan that outlasts the whole test
+        let list = tokio::spawn(cycle_loop(start, period, counter(&list_runs, Duration::MAX)));
+        let buy = tokio::spawn(cycle_loop(start, period, counter(&buy_runs, Duration::ZERO)));
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(list_runs.load(Ordering::SeqCst), 1);
+        assert!(buy_runs.load(Ordering::SeqCst) >= 5);
+        list.abort();
+        buy.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recent_minter_is_skipped() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.min_mint_interval_secs = 60;
+        let storage = Arc::new(MemStorage::default());
+        let accounts = gen_accounts(2)?;
+        let (a, b) = (accounts[0].address.clone(), accounts[1].address.clone());
+        storage.save_cursor(&format!("last_mint:{}", a), 1000).await?;
+        let server = offline_server_on(storage, config, accounts, vec![])?;
+
+        assert_eq!(server.next_mint_account(1010).await?.unwrap().address, b);
+        assert_eq!(server.next_mint_account(1010).await?.unwrap().address, b);
+        assert_eq!(server.next_mint_account(1060).await?.unwrap().address, a);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_start_at_their_offsets() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.account_start_spread_secs = Some(100);
+        config.account_start_seed = Some(7);
+        let storage = Arc::new(MemStorage::default());
+        let accounts = gen_accounts(3)?;
+        let mut server =
+            offline_server_on(storage.clone(), config.clone(), accounts.clone(), vec![])?;
+        server.started_at = 1000;
+        server.assign_start_offsets().await?;
+
+        let offsets = start_offsets(3, 100, 7);
+        for (account, &offset) in accounts.iter().zip(offsets.iter()) {
+            let at = 1000 + offset as i64;
+            assert!(!server.account_started(&account.address, at - 1));
+            assert!(server.account_started(&account.address, at));
+        }
+        let (first, &earliest) =
+            accounts.iter().zip(offsets.iter()).min_by_key(|(_, o)| **o).unwrap();
+        if earliest > 0 {
+            assert!(server.next_mint_account(999 + earliest as i64).await?.is_none());
+        }
+        let minter = server.next_mint_account(1000 + earliest as i64).await?;
+        assert_eq!(minter.unwrap().address, first.address);
+
+        // a restart under another seed keeps the stored offsets
+        config.account_start_seed = Some(8);
+        let restarted = offline_server_on(storage, config, accounts.clone(), vec![])?;
+        restarted.assign_start_offsets().await?;
+        assert_eq!(*restarted.start_offsets.lock().unwrap(), *server.start_offsets.lock().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_excess_is_cancelled_through_the_exchange_adapter() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.list_sum_max = Some(150);
+        let storage = Arc::new(MemStorage::default());
+        let accounts = gen_accounts(1)?;
+        let mut ours = ListItem::sample(1, 100, 5);
+        ours.from = accounts[0].address.clone();
+        let book = vec![ours, ListItem::sample(2, 100, 5)];
+        let exchange = Arc::new(MockExchange::with_book(book));
+        let server = offline_server_on(storage.clone(), config, accounts, vec![])?
+            .with_exchange(exchange.clone());
+
+        server.list_cycle(5).await?;
+
+        assert_eq!(*exchange.calls.lock().unwrap(), vec![("cancel", "1".to_string())]);
+        assert_eq!(exchange.book.lock().unwrap().len(), 1);
+        assert_eq!(storage.trades.lock().unwrap()[0].action, TradeAction::Cancel);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_db_loss_under_each_policy() -> Result<()> {
+        let policies = [(DbFailurePolicy::FailOpen, true), (DbFailurePolicy::FailClosed, false)];
+        for (policy, acts) in policies {
+            let mut config = Config::for_tests(&["aaaa"]);
+            config.db_failure_policy = policy;
+            let storage = Arc::new(MemStorage::default());
+            let server = offline_server_on(storage.clone(), config, vec![], vec![])?;
+            let buy = Trade {
+                action: TradeAction::Buy,
+                token: "aaaa".to_string(),
+                address: "fra1abc".to_string(),
+                amount: 10,
+                price: 5,
+                txid: "ab".to_string(),
+                create_time: 0,
+            };
+
+            storage.down.store(true, Ordering::SeqCst);
+            assert_eq!(server.record_trade(&buy).await.is_ok(), acts);
+            assert_eq!(server.db_available("buy").await, acts);
+            assert!(storage.trades.lock().unwrap().is_empty());
+
+            storage.down.store(false, Ordering::SeqCst);
+            assert!(server.db_available("buy").await);
+            assert!(server.db_available("buy").await);
+            assert_eq!(storage.trades.lock().unwrap().len(), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_accounts_stores_both_roles() -> Result<()> {
+        let config = Config::for_tests(&["aaaa"]);
+        let storage = Arc::new(MemStorage::default());
+        let (mint, buy) = (gen_accounts(2)?, gen_accounts(1)?);
+        let server = offline_server_on(storage.clone(), config, mint.clone(), buy.clone())?;
+
+        // as --prepare-only runs it, twice
+        for _ in 0..2 {
+            storage.migrate().await?;
+            server.prepare_accounts().await?;
+        }
+
+        let mut stored = storage.list_accounts().await?;
+        let mut expected: Vec<(String, i32)> = mint
+            .iter()
+            .map(|a| (a.address.clone(), ACCOUNT_TYPE_MINT))
+            .chain(buy.iter().map(|a| (a.address.clone(), ACCOUNT_TYPE_BUY)))
+            .collect();
+        stored.sort();
+        expected.sort();
+        assert_eq!(stored, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mint_alert_then_exhausted_account_is_skipped() -> Result<()> {
+        let config = Config::for_tests(&["aaaa"]);
+        let storage = Arc::new(MemStorage::default());
+        let accounts = gen_accounts(2)?;
+        let (a, b) = (accounts[0].address.clone(), accounts[1].address.clone());
+        let server = offline_server_on(storage.clone(), config, accounts, vec![])?;
+
+        // 80% of a limit of 7 rounds up to 6 mints
+        let mut alerts = vec![];
+        for n in 0..MINT_LIMIT {
+            let mint = Trade {
+                action: TradeAction::Mint,
+                token: "aaaa".to_string(),
+                address: a.clone(),
+                amount: 1000,
+                price: 0,
+                txid: n.to_string(),
+                create_time: 0,
+            };
+            storage.record_trade(&mint).await?;
+            alerts.push(server.mint_allowance_alert(&a).await?.is_some());
+        }
+        assert_eq!(alerts, vec![false, false, false, false, false, true, true]);
+
+        assert_eq!(server.next_mint_account(0).await?.unwrap().address, b);
+        assert_eq!(server.next_mint_account(0).await?.unwrap().address, b);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trade_is_confirmed_once_buried() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.min_confirmations = 3;
+        let status = |tip: u64| {
+            json_response(&format!(
+                r#"{{"result":{{"sync_info":{{"latest_block_height":"{}"}}}}}}"#,
+                tip
+            ))
+        };
+        let included = json_response(r#"{"result":{"hash":"AB","height":"100"}}"#);
+        let url = mock_server(vec![status(100), included.clone(), status(102), included]).await;
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let storage = Arc::new(MemStorage::default());
+        let buy = Trade {
+            action: TradeAction::Buy,
+            token: "aaaa".to_string(),
+            address: "fra1buyer".to_string(),
+            amount: 10,
+            price: 5,
+            txid: "ab".to_string(),
+            create_time: 0,
+        };
+        storage.record_trade(&buy).await?;
+        let server = BotServer::new(config, storage.clone(), rpc, vec![], vec![])?;
+
+        // in a block, but only one deep
+        server.confirm_trades().await?;
+        assert_eq!(storage.pending_trades().await?, vec!["ab".to_string()]);
+
+        server.confirm_trades().await?;
+        assert!(storage.pending_trades().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queued_actions_resume_after_restart() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.action_queue = true;
+        let url = mock_server(vec![json_response(r#"{"data":null}"#)]).await;
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let storage = Arc::new(MemStorage::default());
+        // the previous run queued these, then died before submitting
+        let buy = QueuedAction::Buy {
+            item: ListItem::sample(7, 10, 5),
+            quantity: 10,
+            partial: false,
+            floor_price: 5,
+        };
+        storage.enqueue_action("aaaa", &buy).await?;
+        storage.enqueue_action("bbbb", &buy).await?;
+
+        let server = BotServer::new(config, storage.clone(), rpc, vec![], gen_accounts(1)?)?;
+        server.resume_queue().await?;
+
+        // rechecked, found sold meanwhile, and done with; other tokens wait
+        // for their own server
+        assert!(storage.queued_actions("aaaa").await?.is_empty());
+        assert_eq!(storage.queued_actions("bbbb").await?.len(), 1);
+        assert!(storage.trades.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_over_saved_book() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.list_sum_min = Some(1000);
+        config.buy_budget = Some(150);
+        config.partial_fills = true;
+        let server = offline_server(config, vec![], vec![])?;
+        let book = vec![
+            ListItem::sample(1, 100, 1),
+            ListItem::sample(2, 100, 5),
+            ListItem::sample(3, 100, 1),
+        ];
+
+        let plan = server.plan(&book, 4, 2)?;
+        assert_eq!(plan.supply, "List(700)");
+        assert_eq!(plan.list_price, Some(4));
+        // the budget runs out part way into the second cheap listing
+        assert_eq!(plan.buys, vec![(1, 1, 100), (3, 1, 50)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dust_listing_is_merged_up() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.list_sum_min = Some(1000);
+        config.min_list_amount = 100;
+        let server = offline_server(config, vec![], vec![])?;
+        let book = vec![ListItem::sample(1, 970, 5)];
+
+        let plan = server.plan(&book, 5, 1)?;
+        assert_eq!(plan.supply, "List(30)");
+        assert_eq!(plan.list_amount, Some(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_buy_pool() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        let mint = gen_accounts(1)?;
+        assert!(check_account_pools(&config, &mint, &[]).is_err());
+        config.no_buy = true;
+        assert!(check_account_pools(&config, &mint, &[]).is_ok());
+
+        // a pool emptied behind the startup check must not panic the cycle
+        config.no_buy = false;
+        let book = ListResponse::from_items(vec![ListItem::sample(1, 100, 1)]);
+        let url = mock_server(vec![json_response(&serde_json::to_string(&book)?)]).await;
+        let storage = Arc::new(MemStorage::default());
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let server = BotServer::new(config, storage.clone(), rpc, mint, vec![])?;
+
+        server.run_buy_cycle(1000).await?;
+
+        assert!(storage.actions.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vanished_listing_is_not_bought() -> Result<()> {
+        let book = ListResponse::from_items(vec![ListItem::sample(1, 100, 1)]);
+        // the listing is bought by someone else between the scan and the buy
+        let url = mock_server(vec![
+            json_response(&serde_json::to_string(&book)?),
+            json_response(r#"{"data":null}"#),
+        ])
+        .await;
+        let storage = Arc::new(MemStorage::default());
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let config = Config::for_tests(&["aaaa"]);
+        let server = BotServer::new(config, storage.clone(), rpc, vec![], gen_accounts(1)?)?;
+
+        server.run_buy_cycle(1000).await?;
+
+        assert!(storage.actions.lock().unwrap().is_empty());
+        assert!(storage.trades.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_drifted_listings_are_repriced() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.reprice_drift_bps = Some(500);
+        config.reprice_min_interval_secs = 600;
+        let mint = gen_accounts(1)?;
+        let own = |id, price, create_time| ListItem {
+            from: mint[0].address.clone(),
+            create_time,
+            ..ListItem::sample(id, 10, price)
+        };
+        let book = vec![
+            own(1, 200, 0),
+            // within 5% of the list price
+            own(2, 104, 0),
+            // drifted, but listed too recently
+            own(3, 200, 900),
+            // drifted, but not ours
+            ListItem::sample(4, 10, 200),
+        ];
+        let rpc = Arc::new(Rpc::new(&config.ex_rpc_url, &config.ex_rpc_url, &config.ex_rpc_url)?);
+        let server = BotServer::new(config, Arc::new(MemStorage::default()), rpc, mint, vec![])?;
+
+        let stale: Vec<i32> = server
+            .stale_listings(&book, 100, 1000)
+            .iter()
+            .map(|(item, _)| item.id)
+            .collect();
+        assert_eq!(stale, vec![1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_minting_halts_at_target() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.mint_target_total = Some(2500);
+        config.mint_amount = 1000;
+        let storage = Arc::new(MemStorage::default());
+        let server = offline_server_on(storage.clone(), config, vec![], vec![])?;
+
+        assert_eq!(server.next_mint_amount(), Some(1000));
+        server.record_mint(2000).await?;
+        assert_eq!(server.next_mint_amount(), Some(500));
+        server.record_mint(500).await?;
+        assert_eq!(server.next_mint_amount(), None);
+
+        // the total survives a restart
+        let restarted = offline_server_on(storage, server.config.clone(), vec![], vec![])?;
+        restarted.restore_progress().await?;
+        assert_eq!(restarted.next_mint_amount(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repriced_listing_is_reread() -> Result<()> {
+        let listing = |price: u64| -> Result<String> {
+            let item = ListItem::sample(1, 10, price);
+            Ok(json_response(&format!(r#"{{"data":{}}}"#, serde_json::to_string(&item)?)))
+        };
+        let scanned = ListItem::sample(1, 10, 100);
+        let server = |url: &str| -> Result<BotServer> {
+            let rpc = Arc::new(Rpc::new(url, url, url)?);
+            let config = Config::for_tests(&["aaaa"]);
+            BotServer::new(config, Arc::new(MemStorage::default()), rpc, vec![], vec![])
+        };
+
+        // repriced over the floor: skipped
+        let url = mock_server(vec![listing(2000)?]).await;
+        assert!(server(&url)?.live_listing(&scanned, 10, 1000).await?.is_none());
+
+        // repriced but still under it: bought at the new price once it holds
+        let url = mock_server(vec![listing(500)?, listing(500)?]).await;
+        let live = server(&url)?.live_listing(&scanned, 10, 1000).await?;
+        assert_eq!(live.map(|item| item.price), Some("500".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_generation_cap() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("accounts-cap-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.max_gen_accounts = 4;
+
+        assert!(load_accounts(path, 5, &config).is_err());
+        assert!(!std::path::Path::new(path).exists());
+
+        let accounts = load_accounts(path, 3, &config)?;
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(format!("{}.sha256", path))?;
+        let indices: Vec<Option<i32>> = accounts.iter().map(|a| a.index).collect();
+        assert_eq!(indices, vec![Some(0), Some(1), Some(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edited_accounts_file_fails_checksum() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("accounts-sum-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.accounts_checksum_strict = true;
+        load_accounts(path, 1, &config)?;
+
+        let edited = std::fs::read_to_string(path)?.replace("fra1", "fra2");
+        std::fs::write(path, &edited)?;
+
+        assert!(!verify_checksum(path, &edited, false)?);
+        assert!(load_accounts(path, 1, &config).is_err());
+
+        // deleting the sidecar doesn't get the edit accepted or re-signed
+        let sidecar = format!("{}.sha256", path);
+        std::fs::remove_file(&sidecar)?;
+        assert!(load_accounts(path, 1, &config).is_err());
+        assert!(!verify_checksum(path, &edited, false)?);
+        assert!(!std::path::Path::new(&sidecar).exists());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_labelled_by_token() -> Result<()> {
+        let config = Config::for_tests(&["aaaa", "bbbb"]);
+        let storage = Arc::new(MemStorage::default());
+        let metrics = Arc::new(Metrics::default());
+        let server = |token: &str| -> Result<BotServer> {
+            let config = config.for_token(token);
+            let server = offline_server_on(storage.clone(), config, vec![], vec![])?;
+            Ok(server.with_metrics(metrics.clone()))
+        };
+
+        server("aaaa")?.record_spend(600, 6).await?;
+        server("bbbb")?.record_spend(50, 1).await?;
+
+        let out = metrics.render();
+        assert!(out.contains("buy_spent{token=\"aaaa\"} 600\n"));
+        assert!(out.contains("buy_spent{token=\"bbbb\"} 50\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spend_total_does_not_wrap() -> Result<()> {
+        let storage = Arc::new(MemStorage::default());
+        let config = Config::for_tests(&["aaaa"]);
+        let server = offline_server_on(storage, config, vec![], vec![])?;
+
+        server.record_spend(u64::MAX - 10, 1).await?;
+        assert!(server.record_spend(11, 1).await.is_err());
+        assert_eq!(server.budget_left(), None);
+        assert_eq!(server.spent.load(Ordering::SeqCst), u64::MAX - 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_thin_book_is_not_bought() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.min_listings_to_buy = 2;
+        let server = offline_server(config, vec![], vec![])?;
+        let mut book = vec![ListItem::sample(1, 100, 1)];
+
+        assert!(server.decide_buys(&book, 10).is_empty());
+        book.push(ListItem::sample(2, 100, 50));
+        assert_eq!(server.decide_buys(&book, 10).len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listing_cap_defers_until_a_cancel() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.max_open_listings = Some(1);
+        let metrics = Arc::new(Metrics::default());
+        let accounts = gen_accounts(1)?;
+        let mut ours = ListItem::sample(1, 100, 5);
+        ours.from = accounts[0].address.clone();
+        let exchange = Arc::new(MockExchange::with_book(vec![ours.clone()]));
+        let storage = Arc::new(MemStorage::default());
+        let server = offline_server_on(storage, config, accounts.clone(), vec![])?
+            .with_metrics(metrics.clone())
+            .with_exchange(exchange);
+        let skips = |reason: &str| {
+            let series = format!(
+                "actions_skipped_total{{cycle=\"list\",reason=\"{}\",token=\"aaaa\"}} 1\n",
+                reason
+            );
+            metrics.render().contains(&series)
+        };
+
+        // nothing needs listing, but the scan counts the open listing
+        server.list_cycle(5).await?;
+        server.create_listing(100, 5, None).await?;
+        assert!(skips("listing_cap"));
+
+        server.cancel(&ours, &accounts[0]).await?;
+        // past the cap now; the unreachable node stops it at the fee check
+        server.create_listing(100, 5, None).await?;
+        assert!(skips("listing_cap"));
+        assert!(skips("balance_unknown"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bad_price_is_not_cancelled() -> Result<()> {
+        let accounts = gen_accounts(1)?;
+        let mut ours = ListItem::sample(1, 100, 5);
+        ours.from = accounts[0].address.clone();
+        ours.price = "99999999999999999999".to_string();
+        let exchange = Arc::new(MockExchange::with_book(vec![ours.clone()]));
+        let storage = Arc::new(MemStorage::default());
+        let config = Config::for_tests(&["aaaa"]);
+        let server = offline_server_on(storage.clone(), config, accounts.clone(), vec![])?
+            .with_exchange(exchange.clone());
+
+        assert!(server.cancel(&ours, &accounts[0]).await.is_err());
+        // still listed and unrecorded, rather than cancelled without a trade
+        assert!(exchange.calls.lock().unwrap().is_empty());
+        assert!(storage.trades.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mint_and_list_pause_under_economic_price() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.min_economic_price = Some(100);
+        config.mint_target_total = Some(1000);
+        config.list_sum_min = Some(1000);
+        let metrics = Arc::new(Metrics::default());
+        let book = vec![ListItem::sample(1, 100, 50)];
+        let exchange = Arc::new(MockExchange::with_book(book.clone()));
+        let storage = Arc::new(MemStorage::default());
+        let server = offline_server_on(storage, config, gen_accounts(1)?, vec![])?
+            .with_metrics(metrics.clone())
+            .with_exchange(exchange);
+
+        server.list_cycle(50).await?;
+        server.mint_cycle().await?;
+        let bought: Vec<i32> = server.decide_buys(&book, 60).iter().map(|o| o.item.id).collect();
+
+        let rendered = metrics.render();
+        for cycle in ["list", "mint"] {
+            assert!(rendered.contains(&format!(
+                "actions_skipped_total{{cycle=\"{}\",reason=\"uneconomic\",token=\"aaaa\"}} 1\n",
+                cycle
+            )));
+        }
+        assert_eq!(bought, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skips_are_counted_by_reason() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.min_listings_to_buy = 2;
+        let metrics = Arc::new(Metrics::default());
+        let server = offline_server(config, vec![], vec![])?
+            .with_metrics(metrics.clone());
+        let book = vec![ListItem::sample(1, 100, 1)];
+
+        server.decide_buys(&book, 10);
+        server.decide_buys(&book, 10);
+
+        assert!(metrics.render().contains(
+            "actions_skipped_total{cycle=\"buy\",reason=\"thin_book\",token=\"aaaa\"} 2\n"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mint_skips_are_counted_by_reason() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.mint_target_total = Some(100);
+        let metrics = Arc::new(Metrics::default());
+        let skips = |reason: &str| {
+            let series = format!(
+                "actions_skipped_total{{cycle=\"mint\",reason=\"{}\",token=\"aaaa\"}} 1\n",
+                reason
+            );
+            metrics.render().contains(&series)
+        };
+
+        let server = offline_server(config.clone(), vec![], vec![])?
+            .with_metrics(metrics.clone());
+        server.mint_cycle().await?;
+        assert!(skips("no_accounts"));
+
+        let server = offline_server(config.clone(), gen_accounts(1)?, vec![])?
+            .with_metrics(metrics.clone());
+        server.minted.store(100, Ordering::SeqCst);
+        server.mint_cycle().await?;
+        assert!(skips("target_reached"));
+
+        config.no_mint = true;
+        let server = offline_server(config, gen_accounts(1)?, vec![])?
+            .with_metrics(metrics.clone());
+        server.mint_cycle().await?;
+        assert!(skips("disabled"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_whitelisted_sellers_are_bought() -> Result<()> {
+        let sellers = gen_accounts(3)?;
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.seller_whitelist = Some(sellers[..2].iter().map(|s| s.address.clone()).collect());
+        config.seller_blacklist = Some([sellers[1].address.clone()].into_iter().collect());
+        let server = offline_server(config, vec![], vec![])?;
+        let book: Vec<ListItem> = sellers
+            .iter()
+            .enumerate()
+            .map(|(i, seller)| ListItem {
+                from: seller.address.clone(),
+                ..ListItem::sample(i as i32 + 1, 100, 1)
+            })
+            .collect();
+
+        let bought: Vec<i32> = server.decide_buys(&book, 10).iter().map(|o| o.item.id).collect();
+        assert_eq!(bought, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_thin_profit_under_floor_is_skipped() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        // 2 under the floor on 10k tokens makes 20k, not enough after the fee
+        config.min_abs_profit = Some(50_000);
+        let server = offline_server(config, vec![], vec![])?;
+        let book = vec![ListItem::sample(1, 10_000, 8), ListItem::sample(2, 10_000, 1)];
+
+        let bought: Vec<i32> = server.decide_buys(&book, 10).iter().map(|o| o.item.id).collect();
+        assert_eq!(bought, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buys_taper_past_soft_cap() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.buy_budget = Some(1000);
+        config.buy_soft_cap_pct = Some(80);
+        let server = offline_server(config, vec![], vec![])?;
+        let book: Vec<ListItem> = (1..=10).map(|id| ListItem::sample(id, 1, 1)).collect();
+
+        let bought = |spent| {
+            server.spent.store(spent, Ordering::SeqCst);
+            server.decide_buys(&book, 10).len()
+        };
+        assert_eq!(bought(800), 10);
+        assert_eq!(bought(900), 5);
+        assert_eq!(bought(950), 3);
+        assert_eq!(bought(999), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cycle_aborts_at_retry_budget() -> Result<()> {
+        let limited =
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        // every page fetch is rate limited; the third retry is over budget
+        let url = mock_server(vec![limited.to_string(); 3]).await;
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.cycle_retry_budget = Some(2);
+        let rpc = Arc::new(Rpc::new(&url, &url, &url)?);
+        let server = BotServer::new(config, Arc::new(MemStorage::default()), rpc, vec![], vec![])?;
+
+        let err = server.run_buy_cycle(1000).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("retry budget of 2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listings_deadline_truncates_slow_scan() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.listings_deadline_ms = Some(250);
+        let book: Vec<ListItem> = (1..=120).map(|id| ListItem::sample(id, 10, 100)).collect();
+        let exchange = Arc::new(MockExchange {
+            page_delay: Duration::from_millis(100),
+            ..MockExchange::with_book(book)
+        });
+        let server = offline_server(config, vec![], vec![])?
+            .with_exchange(exchange);
+
+        // pages 1 and 2 are back by 200ms; page 3 would take until 300ms
+        let listings = server.get_all_token_listings("aaaa", &ListQuery::default()).await?;
+        assert!(listings.truncated);
+        assert_eq!(listings.items.len(), 2 * PAGE_SIZE as usize);
+        Ok(())
+    }
+
+    #[test]
+    fn test_treasury_mnemonic_reads_from_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("treasury-{}.txt", std::process::id()));
+        std::fs::write(&path, "word word word\n")?;
+        let path = path.to_string_lossy().into_owned();
+
+        assert_eq!(treasury_mnemonic(None, Some(&path))?, "word word word");
+        std::fs::remove_file(&path)?;
+        assert!(treasury_mnemonic(None, Some(&path)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_list_and_buy_ticks_count_toward_warmup() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.warmup_ticks = 2;
+        let server = offline_server(config, vec![], vec![])?;
+
+        // mint and reprice ticks
+        for _ in 0..4 {
+            server.tick();
+        }
+        assert!(server.warming_up());
+        assert!(server.observe_tick());
+        assert!(server.observe_tick());
+        assert!(!server.warming_up());
+        assert!(!server.observe_tick());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_panic_sell_while_warming_up() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.warmup_ticks = 3;
+        config.enable_panic_sell = true;
+        let buyers = gen_accounts(1)?;
+        let storage = Arc::new(MemStorage::default());
+        storage
+            .record_trade(&Trade {
+                action: TradeAction::Buy,
+                token: "aaaa".to_string(),
+                address: buyers[0].address.clone(),
+                amount: 100,
+                price: 100,
+                txid: "bought".to_string(),
+                create_time: now_secs(),
+            })
+            .await?;
+        let exchange = Arc::new(MockExchange::with_book(vec![ListItem::sample(1, 10, 100)]));
+        let server = offline_server_on(storage.clone(), config, vec![], buyers)?
+            .with_exchange(exchange.clone());
+
+        // the floor falls 60% over the warm-up ticks
+        for price in [100, 50, 40] {
+            *exchange.book.lock().unwrap() = vec![ListItem::sample(1, 10, price)];
+            server.buy_cycle(10).await?;
+        }
+        assert!(!server.panicked.load(Ordering::SeqCst));
+        assert!(exchange.calls.lock().unwrap().is_empty());
+        assert_eq!(storage.trades.lock().unwrap().len(), 1);
+
+        // the window filled meanwhile, so the first tick after it panics
+        server.buy_cycle(10).await?;
+        assert!(server.panicked.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_low_buy_account_is_topped_up_once() -> Result<()> {
+        let mut config = Config::for_tests(&["aaaa"]);
+        config.topup_min_balance = 1_000_000;
+        config.topup_amount = 5_000_000;
+        let (pool, buyers) = (gen_accounts(1)?, gen_accounts(2)?);
+        let wallet = Arc::new(MockWallet::with_balances(&[
+            (pool[0].address.as_str(), 100_000_000),
+            (buyers[0].address.as_str(), 20_000),
+            (buyers[1].address.as_str(), 2_000_000),
+        ]));
+        // the first broadcast lands but its response is lost
+        wallet.timeouts.store(1, Ordering::SeqCst);
+        let storage = Arc::new(MemStorage::default());
+        let server = offline_server_on(storage.clone(), config, pool.clone(), buyers.clone())?
+            .with_wallet(wallet.clone());
+
+        assert!(server.top_up().await.is_err());
+        server.top_up().await?;
+
+        // paid once, from the earnings pool, to the low account only
+        assert_eq!(wallet.landed.lock().unwrap().len(), 1);
+        let trades = storage.trades.lock().unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].action, TradeAction::TopUp);
+        assert_eq!(trades[0].address, buyers[0].address);
+        assert_eq!(trades[0].amount, 5_000_000);
+        assert_eq!(trades[0].txid, wallet.landed.lock().unwrap()[0]);
+        Ok(())
+    }
 }
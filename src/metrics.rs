@@ -0,0 +1,113 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[derive(Debug)]
+struct Family {
+    kind: &'static str,
+    series: BTreeMap<String, f64>,
+}
+
+/// Minimal registry rendered in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Metrics {
+    pub fn set_gauge(&self, name: &'static str, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind: "gauge",
+            series: BTreeMap::new(),
+        });
+        family.series.insert(format_labels(labels), value);
+    }
+
+    pub fn inc_counter(&self, name: &'static str, labels: &[(&str, &str)]) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind: "counter",
+            series: BTreeMap::new(),
+        });
+        *family.series.entry(format_labels(labels)).or_insert(0.0) += 1.0;
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+            for (labels, value) in family.series.iter() {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Serves `render()` to any request on `addr`; the path is ignored.
+pub async fn serve(metrics: Arc<Metrics>, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics listening on {}", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            if let Err(e) = stream.read(&mut buf).await {
+                warn!("metrics read error: {}", e);
+                return;
+            }
+            let body = metrics.render();
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(resp.as_bytes()).await {
+                warn!("metrics write error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_render_gauge() {
+        let metrics = Metrics::default();
+        metrics.set_gauge(
+            "account_balance",
+            &[("address", "fra1abc"), ("role", "mint")],
+            42.0,
+        );
+        metrics.set_gauge(
+            "account_balance",
+            &[("address", "fra1abc"), ("role", "mint")],
+            7.0,
+        );
+
+        assert_eq!(
+            metrics.render(),
+            "# TYPE account_balance gauge\naccount_balance{address=\"fra1abc\",role=\"mint\"} 7\n"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use crate::types::{checked_cost, ListItem};
+use crate::utils::fill_quantity;
+use anyhow::{Error, Result};
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+/// What a buy strategy knows about the bot when it decides.
+#[derive(Debug, Clone, Copy)]
+pub struct BotState {
+    /// The rotated floor price for this tick.
+    pub floor_price: u64,
+    /// Unix seconds of the decision.
+    pub now: i64,
+    pub budget_left: Option<u64>,
+    pub target_left: Option<u64>,
+    pub partial_fills: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuyOrder {
+    pub item: ListItem,
+    pub price: u64,
+    pub quantity: u64,
+}
+
+impl BuyOrder {
+    /// Whether the order takes less than the whole listing.
+    pub fn is_partial(&self) -> bool {
+        self.item.amount_value().map_or(false, |amount| self.quantity < amount)
+    }
+}
+
+/// Which of several listings at the same price is bought first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Most tokens per transaction.
+    LargestFirst,
+    OldestFirst,
+    Random,
+}
+
+impl TieBreak {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "largest" => Ok(Self::LargestFirst),
+            "oldest" => Ok(Self::OldestFirst),
+            "random" => Ok(Self::Random),
+            other => Err(Error::msg(format!(
+                "BUY_TIE_BREAK must be largest, oldest or random, not {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Sorts `book` cheapest first, ordering equal prices by `tie_break`.
+/// Listings with unreadable values go last.
+pub fn order_book(book: &mut [ListItem], tie_break: TieBreak) {
+    let price = |item: &ListItem| item.price_value().unwrap_or(u64::MAX);
+    match tie_break {
+        TieBreak::LargestFirst => {
+            book.sort_by_key(|item| (price(item), Reverse(item.amount_value().unwrap_or(0))))
+        }
+        TieBreak::OldestFirst => book.sort_by_key(|item| (price(item), item.create_time)),
+        TieBreak::Random => {
+            // the sort is stable, so shuffled ties stay shuffled
+            book.shuffle(&mut rand::thread_rng());
+            book.sort_by_key(price);
+        }
+    }
+}
+
+/// Decides which listings of a book the buy cycle takes, and how much of each.
+/// The cycle still applies jitter, liveness checks and account selection to
+/// every order.
+pub trait BuyStrategy: Debug + Send {
+    fn decide(&mut self, book: &[ListItem], state: &BotState) -> Vec<BuyOrder>;
+}
+
+/// Takes every listing at or under the floor, in book order, until the budget
+/// or the buy target runs out.
+#[derive(Debug, Default)]
+pub struct FloorStrategy;
+
+impl BuyStrategy for FloorStrategy {
+    fn decide(&mut self, book: &[ListItem], state: &BotState) -> Vec<BuyOrder> {
+        let mut budget_left = state.budget_left;
+        let mut target_left = state.target_left;
+        let mut orders = vec![];
+        for item in book.iter() {
+            let (price, amount) = match (item.price_value(), item.amount_value()) {
+                (Ok(price), Ok(amount)) => (price, amount),
+                _ => continue,
+            };
+            if price > state.floor_price {
+                continue;
+            }
+            let quantity =
+                match fill_quantity(amount, price, budget_left, target_left, state.partial_fills) {
+                    Some(quantity) => quantity,
+                    None => continue,
+                };
+            let cost = match checked_cost(price, quantity) {
+                Ok(cost) => cost,
+                Err(_) => continue,
+            };
+            budget_left = budget_left.map(|left| left.saturating_sub(cost));
+            target_left = target_left.map(|left| left.saturating_sub(quantity));
+            orders.push(BuyOrder {
+                item: item.clone(),
+                price,
+                quantity,
+            });
+        }
+        orders
+    }
+}
+
+/// Buys at or under the average of the cheapest ask seen over the last
+/// `window` seconds. Until that much history exists it uses the rotating
+/// floor instead.
+#[derive(Debug)]
+pub struct MovingAverageFloor {
+    window: i64,
+    /// `(time, cheapest ask)` per decision, oldest first.
+    snapshots: VecDeque<(i64, u64)>,
+    first_seen: Option<i64>,
+}
+
+impl MovingAverageFloor {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window: window_secs as i64,
+            snapshots: VecDeque::new(),
+            first_seen: None,
+        }
+    }
+
+    fn observe(&mut self, now: i64, cheapest: u64) {
+        self.first_seen.get_or_insert(now);
+        self.snapshots.push_back((now, cheapest));
+        while let Some(&(at, _)) = self.snapshots.front() {
+            if at >= now - self.window {
+                break;
+            }
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Average of the snapshots within the window ending at `now`, once the
+    /// history spans a whole window.
+    fn reference(&self, now: i64) -> Option<u64> {
+        let first_seen = self.first_seen?;
+        if now - first_seen < self.window {
+            return None;
+        }
+        let prices: Vec<u64> = self
+            .snapshots
+            .iter()
+            .filter(|&&(at, _)| at >= now - self.window)
+            .map(|&(_, price)| price)
+            .collect();
+        if prices.is_empty() {
+            return None;
+        }
+        Some((prices.iter().map(|&p| p as u128).sum::<u128>() / prices.len() as u128) as u64)
+    }
+}
+
+impl BuyStrategy for MovingAverageFloor {
+    fn decide(&mut self, book: &[ListItem], state: &BotState) -> Vec<BuyOrder> {
+        if let Some(cheapest) = book.iter().filter_map(|item| item.price_value().ok()).min() {
+            self.observe(state.now, cheapest);
+        }
+        let floor_price = self.reference(state.now).unwrap_or(state.floor_price);
+        FloorStrategy.decide(book, &BotState { floor_price, ..*state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::strategy::{
+        order_book, BotState, BuyStrategy, FloorStrategy, MovingAverageFloor, TieBreak,
+    };
+    use crate::types::ListItem;
+
+    #[test]
+    fn test_floor_strategy_stops_at_target() {
+        let book = vec![
+            ListItem::sample(1, 10, 5),
+            ListItem::sample(2, 10, 50),
+            ListItem::sample(3, 10, 5),
+        ];
+        let state = BotState {
+            floor_price: 20,
+            now: 0,
+            budget_left: None,
+            target_left: Some(15),
+            partial_fills: true,
+        };
+
+        let orders = FloorStrategy.decide(&book, &state);
+
+        let taken: Vec<(i32, u64)> = orders.iter().map(|o| (o.item.id, o.quantity)).collect();
+        assert_eq!(taken, vec![(1, 10), (3, 5)]);
+        assert!(!orders[0].is_partial());
+        assert!(orders[1].is_partial());
+    }
+
+    #[test]
+    fn test_moving_average_window_boundary() {
+        let mut ma = MovingAverageFloor::new(100);
+        ma.observe(0, 100);
+        ma.observe(50, 200);
+        // cold start: the history doesn't span a window yet
+        assert_eq!(ma.reference(50), None);
+
+        ma.observe(120, 300);
+        // the snapshot at 0 fell out; 50 and 120 remain
+        assert_eq!(ma.reference(120), Some(250));
+        assert_eq!(ma.reference(150), Some(250));
+        assert_eq!(ma.reference(151), Some(300));
+    }
+
+    #[test]
+    fn test_tied_listings_follow_tie_break() {
+        let listing = |id, amount, price, create_time| ListItem {
+            create_time,
+            ..ListItem::sample(id, amount, price)
+        };
+        let book = vec![
+            listing(1, 5, 10, 300),
+            listing(2, 50, 20, 100),
+            listing(3, 20, 10, 100),
+            listing(4, 30, 10, 200),
+        ];
+        let order = |tie_break| {
+            let mut book = book.clone();
+            order_book(&mut book, tie_break);
+            book.iter().map(|item| item.id).collect::<Vec<i32>>()
+        };
+
+        assert_eq!(order(TieBreak::LargestFirst), vec![4, 3, 1, 2]);
+        assert_eq!(order(TieBreak::OldestFirst), vec![3, 4, 1, 2]);
+        let mut random = order(TieBreak::Random);
+        assert_eq!(random.pop(), Some(2));
+        random.sort();
+        assert_eq!(random, vec![1, 3, 4]);
+    }
+}
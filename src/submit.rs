@@ -0,0 +1,226 @@
+use crate::db::StorageBackend;
+use crate::types::TxRejected;
+use anyhow::{Context, Error, Result};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use tokio::sync::Semaphore;
+
+/// Key for an action built from its deterministic inputs, e.g.
+/// `["buy", address, nonce, amount, target]`.
+pub fn idempotency_key(parts: &[&str]) -> String {
+    hex::encode(Sha256::digest(parts.join("|").as_bytes()))
+}
+
+fn nonce_cursor(kind: &str, address: &str) -> String {
+    format!("nonce:{}:{}", kind, address)
+}
+
+/// Stored nonce of the next `kind` action by `address`, for its key. It
+/// outlives restarts and only moves on in `settle_nonce`, so a retry of an
+/// unsettled action gets the same key and a later action a new one.
+pub async fn next_nonce(storage: &dyn StorageBackend, kind: &str, address: &str) -> Result<i64> {
+    let nonce = storage.load_cursor(&nonce_cursor(kind, address)).await;
+    Ok(nonce.context("loading action nonce")?.unwrap_or(0))
+}
+
+/// Moves past `nonce` once its action was submitted.
+pub async fn settle_nonce(
+    storage: &dyn StorageBackend,
+    kind: &str,
+    address: &str,
+    nonce: i64,
+) -> Result<()> {
+    storage
+        .save_cursor(&nonce_cursor(kind, address), nonce + 1)
+        .await
+        .context("saving action nonce")
+}
+
+/// Runs `submit` at most once per `key`. The key and the expected `txid` are
+/// stored before submitting, so a retry after a timeout, even from a later
+/// tick, finds them and asks `committed` about the earlier broadcast instead
+/// of sending a second transaction. A transaction the node refuses outright
+/// never landed, so its key is dropped and the action can be tried again.
+pub async fn submit_once<S, SFut, C, CFut>(
+    storage: &dyn StorageBackend,
+    key: &str,
+    txid: &str,
+    submit: S,
+    committed: C,
+) -> Result<String>
+where
+    S: FnOnce() -> SFut,
+    SFut: Future<Output = Result<String>>,
+    C: FnOnce(String) -> CFut,
+    CFut: Future<Output = Result<bool>>,
+{
+    if let Some(prev) = storage.load_action(key).await? {
+        if committed(prev.clone()).await? {
+            return Ok(prev);
+        }
+        return Err(Error::msg(format!(
+            "action {} already submitted as {}, not yet committed",
+            key, prev
+        )));
+    }
+
+    storage.save_action(key, txid).await?;
+    match submit().await {
+        Err(e) if e.downcast_ref::<TxRejected>().is_some() => {
+            storage.delete_action(key).await?;
+            Err(e)
+        }
+        result => result,
+    }
+}
+
+/// Runs `action` holding a permit from `inflight`, so however many accounts
+/// are active only as many actions as it has permits are in flight.
+pub async fn with_permit<T, Fut>(inflight: &Semaphore, action: Fut) -> Result<T>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    let _permit = inflight.acquire().await?;
+    action.await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::mem::MemStorage;
+    use crate::db::StorageBackend;
+    use crate::submit::{idempotency_key, next_nonce, settle_nonce, submit_once, with_permit};
+    use crate::types::TxRejected;
+    use anyhow::{Error, Result};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn test_submit_once_timeout_then_retry() -> Result<()> {
+        let storage = MemStorage::default();
+        let key = idempotency_key(&["buy", "fra1abc", "0", "10", "7"]);
+        let submits = AtomicUsize::new(0);
+        let submits = &submits;
+
+        // the broadcast lands but the response times out
+        let first = submit_once(
+            &storage,
+            &key,
+            "aa",
+            move || async move {
+                submits.fetch_add(1, Ordering::SeqCst);
+                Err::<String, _>(Error::msg("timeout"))
+            },
+            |_| async { Ok(false) },
+        )
+        .await;
+        assert!(first.is_err());
+
+        let retry = submit_once(
+            &storage,
+            &key,
+            "bb",
+            move || async move {
+                submits.fetch_add(1, Ordering::SeqCst);
+                Ok("bb".to_string())
+            },
+            |txid| async move { Ok(txid == "aa") },
+        )
+        .await?;
+
+        assert_eq!(retry, "aa");
+        assert_eq!(submits.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_after_restart_is_new() -> Result<()> {
+        // the store outlives the process; the tick counter doesn't
+        let storage = MemStorage::default();
+        let key = |nonce: i64| idempotency_key(&["list", "aaaa", "fra1abc", &nonce.to_string()]);
+        let submits = AtomicUsize::new(0);
+        let submits = &submits;
+        let list = |key: String, txid: &'static str| {
+            let storage = &storage;
+            async move {
+                submit_once(
+                    storage,
+                    &key,
+                    txid,
+                    move || async move {
+                        submits.fetch_add(1, Ordering::SeqCst);
+                        Ok(txid.to_string())
+                    },
+                    |_| async { Ok(true) },
+                )
+                .await
+            }
+        };
+
+        let nonce = next_nonce(&storage, "list", "fra1abc").await?;
+        assert_eq!(list(key(nonce), "aa").await?, "aa");
+        settle_nonce(&storage, "list", "fra1abc", nonce).await?;
+
+        // a restarted process lists the same amount from the same account
+        let nonce = next_nonce(&storage, "list", "fra1abc").await?;
+        assert_eq!(list(key(nonce), "bb").await?, "bb");
+        assert_eq!(submits.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_submit_frees_its_key() -> Result<()> {
+        let storage = MemStorage::default();
+        let key = idempotency_key(&["buy", "7", "100", "10", "fra1abc"]);
+
+        let rejected = submit_once(
+            &storage,
+            &key,
+            "aa",
+            || async {
+                Err::<String, _>(Error::new(TxRejected {
+                    code: 1,
+                    log: "insufficient balance".to_string(),
+                }))
+            },
+            |_| async { Ok(false) },
+        )
+        .await;
+        assert!(rejected.is_err());
+        assert_eq!(storage.load_action(&key).await?, None);
+
+        let retry = submit_once(
+            &storage,
+            &key,
+            "bb",
+            || async { Ok("bb".to_string()) },
+            |_| async { Ok(false) },
+        )
+        .await?;
+        assert_eq!(retry, "bb");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inflight_never_exceeds_limit() -> Result<()> {
+        let inflight = Semaphore::new(3);
+        let current = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let actions = (0..20).map(|_| {
+            with_permit(&inflight, async {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                current.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        for result in futures::future::join_all(actions).await {
+            result?;
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
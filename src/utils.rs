@@ -1,14 +1,26 @@
-use crate::types::FraAccount;
+use crate::types::{FraAccount, ListItem};
 use anyhow::Error;
 use anyhow::{anyhow, Result};
 use base64::{engine, Engine};
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
 use globutils::wallet::{
     generate_mnemonic_default, public_key_to_base64, public_key_to_bech32,
     restore_keypair_from_mnemonic_default, restore_keypair_from_seckey_base64,
 };
 use ledger::data_model::{TxoSID, Utxo, ASSET_TYPE_FRA};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tokio::time::{self, Instant};
 use zei::serialization::ZeiFromToBytes;
 use zei::xfr::asset_record::open_blind_asset_record;
 use zei::xfr::sig::XfrSecretKey;
@@ -18,26 +30,1333 @@ fn private_key_to_base64(key: &XfrSecretKey) -> String {
     engine::general_purpose::URL_SAFE.encode(ZeiFromToBytes::zei_to_bytes(key))
 }
 
+/// Upper bound on the threads `gen_accounts` spreads key derivation over.
+const GEN_THREADS: usize = 8;
+
+/// Generates `amount` fresh accounts, indexed from 0, on up to GEN_THREADS
+/// threads.
 pub fn gen_accounts(amount: i32) -> Result<Vec<FraAccount>> {
-    let mut accounts = vec![];
-    for index in 0..amount {
-        let mnemonic = generate_mnemonic_default();
-        let key_pair = restore_keypair_from_mnemonic_default(&mnemonic).unwrap();
-        let xfr_public_key = key_pair.get_pk_ref();
-        let xfr_private_key = key_pair.get_sk_ref();
-
-        let private_key = hex::encode(ZeiFromToBytes::zei_to_bytes(xfr_private_key));
-        let public_key = public_key_to_base64(xfr_public_key);
-        let address = public_key_to_bech32(&xfr_public_key);
-
-        accounts.push(FraAccount {
-            index: Some(index),
-            mnemonic,
-            address,
-            public_key: Some(public_key),
-            private_key: Some(private_key),
+    let amount = amount.max(0) as usize;
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(GEN_THREADS)
+        .min(amount)
+        .max(1);
+    let chunk = (amount + threads - 1) / threads;
+
+    let mut accounts = Vec::with_capacity(amount);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|worker| {
+                let start = (worker * chunk).min(amount) as i32;
+                let end = ((worker + 1) * chunk).min(amount) as i32;
+                scope.spawn(move || (start..end).map(gen_account).collect::<Vec<_>>())
+            })
+            .collect();
+        for worker in workers {
+            accounts.extend(worker.join().expect("account generation panicked"));
+        }
+    });
+
+    Ok(accounts)
+}
+
+/// Reorders `accounts` the same way for the same seed, so a restart keeps
+/// rotating in the order it had.
+pub fn shuffle_accounts(accounts: &mut [FraAccount], seed: u64) {
+    accounts.shuffle(&mut StdRng::seed_from_u64(seed));
+}
+
+/// `count` start offsets of up to `spread_secs` each, the same ones for the
+/// same seed.
+pub fn start_offsets(count: usize, spread_secs: u64, seed: u64) -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count).map(|_| rng.gen_range(0..=spread_secs)).collect()
+}
+
+fn gen_account(index: i32) -> FraAccount {
+    let mnemonic = generate_mnemonic_default();
+    let key_pair = restore_keypair_from_mnemonic_default(&mnemonic).unwrap();
+    let xfr_public_key = key_pair.get_pk_ref();
+    let xfr_private_key = key_pair.get_sk_ref();
+
+    let private_key = hex::encode(ZeiFromToBytes::zei_to_bytes(xfr_private_key));
+    let public_key = public_key_to_base64(xfr_public_key);
+    let address = public_key_to_bech32(&xfr_public_key);
+
+    FraAccount {
+        index: Some(index),
+        mnemonic,
+        address,
+        public_key: Some(public_key),
+        private_key: Some(private_key),
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// `body` cut to at most `max` bytes, on a char boundary, noting its size.
+pub fn truncate_body(body: &str, max: usize) -> String {
+    if body.len() <= max {
+        return body.to_string();
+    }
+    let mut end = max;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes)", &body[..end], body.len())
+}
+
+/// `text` with every `fra1` address shortened to its last six characters,
+/// which still tells accounts apart in a log.
+pub fn mask_addresses(text: &str) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("fra1") {
+        masked.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let len = tail.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(tail.len());
+        if len >= 38 {
+            masked.push_str("fra1..");
+            masked.push_str(&tail[len - 6..len]);
+        } else {
+            masked.push_str(&tail[..len]);
+        }
+        rest = &tail[len..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+/// `price` lowered to one `tick` under the cheapest listing in `book`, so a new
+/// listing is immediately the best ask, but never below `sell_floor`.
+pub fn clamp_list_price(price: u64, book: &[ListItem], tick: u64, sell_floor: Option<u64>) -> u64 {
+    let best_ask = book
+        .iter()
+        .filter_map(|item| item.price_value().ok())
+        .min();
+    let price = match best_ask {
+        Some(ask) => price.min(ask.saturating_sub(tick)),
+        None => price,
+    };
+    price.max(sell_floor.unwrap_or(0))
+}
+
+/// Drops listings already seen on an earlier page of the same scan. With
+/// `strict` a repeated id is an exchange bug worth halting for, so it is an
+/// error instead.
+#[derive(Debug, Default)]
+pub struct PageDedup {
+    strict: bool,
+    seen: HashSet<i32>,
+}
+
+impl PageDedup {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn filter(&mut self, page: Vec<ListItem>) -> Result<Vec<ListItem>> {
+        let mut fresh = Vec::with_capacity(page.len());
+        let mut repeated = vec![];
+        for item in page {
+            if self.seen.insert(item.id) {
+                fresh.push(item);
+            } else {
+                repeated.push(item.id);
+            }
+        }
+        if repeated.is_empty() {
+            return Ok(fresh);
+        }
+        if self.strict {
+            let msg = format!("exchange returned listings {:?} on more than one page", repeated);
+            log::error!("[scan] {}, aborting cycle", msg);
+            return Err(Error::msg(msg));
+        }
+        log::warn!("[scan] dropped duplicate listings {:?}", repeated);
+        Ok(fresh)
+    }
+}
+
+/// Pages after the first to fetch from a book of `pages` pages. With `cap`
+/// below `pages` only `cap - 1` of them are scanned (the first page always
+/// is), starting further along each `rotation` so the whole book is covered
+/// over successive ticks.
+pub fn scan_pages(pages: i32, cap: Option<u32>, rotation: usize) -> Vec<i32> {
+    let rest: Vec<i32> = (2..=pages).collect();
+    let take = match cap {
+        Some(cap) if (cap as usize) < pages.max(0) as usize => (cap as usize).saturating_sub(1),
+        _ => return rest,
+    };
+    if take == 0 {
+        return vec![];
+    }
+    let start = rotation * take % rest.len();
+    rest.iter().cycle().skip(start).take(take).copied().collect()
+}
+
+/// How far a list scan got: pages `1..=page` summed to `sum` while the book
+/// held `total` listings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanCheckpoint {
+    pub page: i32,
+    pub sum: u64,
+    pub total: i32,
+}
+
+impl ScanCheckpoint {
+    /// Whether a scan of the book, now `total` listings, may pick up here:
+    /// only while the size is within `tolerance_pct` of what it was.
+    pub fn resumes(&self, total: i32, tolerance_pct: u32) -> bool {
+        let drift = total.abs_diff(self.total) as u64 * 100;
+        self.page > 0 && drift <= self.total.max(0) as u64 * tolerance_pct as u64
+    }
+}
+
+/// Median listed price of `book` raised by `spread_bps` basis points; 0 when
+/// no listing has a parseable price.
+pub fn compute_list_price(book: &[ListItem], spread_bps: u32) -> u64 {
+    let mut prices: Vec<u64> = book
+        .iter()
+        .filter_map(|item| item.price_value().ok())
+        .collect();
+    if prices.is_empty() {
+        return 0;
+    }
+    prices.sort_unstable();
+    let n = prices.len();
+    let mid = if n % 2 == 0 {
+        (prices[n / 2 - 1] + prices[n / 2]) / 2
+    } else {
+        prices[n / 2]
+    };
+    (mid as u128 * (10_000 + spread_bps as u128) / 10_000) as u64
+}
+
+/// Picks inputs to cover `target`, largest first so transactions carry as few
+/// inputs as possible, ignoring anything below `min_value`. Each entry pairs an
+/// input with its opened value. If the eligible inputs can't cover `target`,
+/// all of them are returned and the caller sees the shortfall.
+pub fn select_utxos<T>(mut utxos: Vec<(T, u64)>, target: u64, min_value: u64) -> Vec<(T, u64)> {
+    utxos.retain(|(_, value)| *value > 0 && *value >= min_value);
+    utxos.sort_by(|a, b| b.1.cmp(&a.1));
+    let mut total = 0;
+    let mut selected = vec![];
+    for (utxo, value) in utxos {
+        if total >= target {
+            break;
+        }
+        total += value;
+        selected.push((utxo, value));
+    }
+    selected
+}
+
+/// What to do with an account's inputs for one spend.
+#[derive(Debug, PartialEq)]
+pub enum InputSelection<T> {
+    /// Inputs for the spend itself; short of the target if the account is.
+    Spend(Vec<(T, u64)>),
+    /// The account holds enough, but only across more inputs than a
+    /// transaction may carry. These should be merged into one first.
+    Consolidate(Vec<(T, u64)>),
+}
+
+/// [`select_utxos`] with at most `max_inputs` inputs per transaction.
+pub fn select_inputs<T>(
+    utxos: Vec<(T, u64)>,
+    target: u64,
+    min_value: u64,
+    max_inputs: Option<usize>,
+) -> InputSelection<T> {
+    let mut selected = select_utxos(utxos, target, min_value);
+    let max = match max_inputs {
+        Some(max) if selected.len() > max => max,
+        _ => return InputSelection::Spend(selected),
+    };
+    let total: u64 = selected.iter().map(|(_, value)| value).sum();
+    // largest first, so merging the head gains the most per consolidation
+    selected.truncate(max);
+    if total >= target {
+        InputSelection::Consolidate(selected)
+    } else {
+        InputSelection::Spend(selected)
+    }
+}
+
+/// How many of a listing's `amount` tokens to buy at unit `price` given what is
+/// left of the budget and the buy target. `None` means skip the listing: either
+/// nothing is affordable, or only part is and `partial` fills are unsupported.
+pub fn fill_quantity(
+    amount: u64,
+    price: u64,
+    budget_left: Option<u64>,
+    target_left: Option<u64>,
+    partial: bool,
+) -> Option<u64> {
+    let mut quantity = amount;
+    if let Some(target) = target_left {
+        quantity = quantity.min(target);
+    }
+    if let Some(budget) = budget_left {
+        quantity = quantity.min(budget / price.max(1));
+    }
+
+    if quantity == 0 || (quantity < amount && !partial) {
+        return None;
+    }
+    Some(quantity)
+}
+
+/// What relisting `quantity` bought at `price` for `resale` each makes once
+/// the listing `fee` is paid; negative for a loss.
+pub fn resale_profit(resale: u64, price: u64, quantity: u64, fee: u64) -> i128 {
+    (resale as i128 - price as i128) * quantity as i128 - fee as i128
+}
+
+/// What of `balance` may be spent while `reserve` stays behind for gas.
+pub fn spendable(balance: u64, reserve: u64) -> u64 {
+    balance.saturating_sub(reserve)
+}
+
+/// The smaller of the node's and the exchange's view of a balance, or `None`
+/// when they are more than `tolerance` apart and neither can be trusted.
+pub fn reconcile_balance(node: u64, exchange: u64, tolerance: Option<u64>) -> Option<u64> {
+    match tolerance {
+        Some(tolerance) if node.abs_diff(exchange) > tolerance => None,
+        _ => Some(node.min(exchange)),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SupplyAction {
+    /// List this many more tokens.
+    List(u64),
+    /// Cancel own listings worth this many tokens.
+    Cancel(u64),
+    Hold,
+}
+
+/// Keeps the listed supply `sum` inside `[min, max]`; either bound may be unset.
+pub fn supply_action(sum: u64, min: Option<u64>, max: Option<u64>) -> SupplyAction {
+    match (min, max) {
+        (Some(min), _) if sum < min => SupplyAction::List(min - sum),
+        (_, Some(max)) if sum > max => SupplyAction::Cancel(sum - max),
+        _ => SupplyAction::Hold,
+    }
+}
+
+/// What a list scan does when a page still fails after its retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialFailure {
+    /// Fail the whole scan.
+    Abort,
+    /// Go on without the page and mark the scan partial.
+    Continue,
+}
+
+impl PartialFailure {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "continue" => Ok(Self::Continue),
+            other => Err(anyhow!(
+                "SCAN_ON_PARTIAL_FAILURE must be abort or continue, not {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// What the cycles do while trades can't be recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbFailurePolicy {
+    /// Keep trading; records wait in memory and are lost on a crash.
+    FailOpen,
+    /// Stop acting until the records are written.
+    FailClosed,
+}
+
+impl DbFailurePolicy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "fail-open" => Ok(Self::FailOpen),
+            "fail-closed" => Ok(Self::FailClosed),
+            other => Err(anyhow!(
+                "DB_FAILURE_POLICY must be fail-open or fail-closed, not {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// How a listing deficit is shared across mint accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitStrategy {
+    /// One account lists it all.
+    Single,
+    Even,
+    /// In proportion to each account's FRA balance.
+    BalanceWeighted,
+}
+
+impl SplitStrategy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "single" => Ok(Self::Single),
+            "even" => Ok(Self::Even),
+            "balance-weighted" => Ok(Self::BalanceWeighted),
+            other => Err(anyhow!(
+                "SPLIT_STRATEGY must be single, even or balance-weighted, not {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// Which of the `low` accounts, each paired with its balance, a pool that can
+/// spend `available` sends `amount` each in one transfer paying `fee`. The
+/// lowest balances go first.
+pub fn plan_topups<A: Clone>(low: &[(A, u64)], available: u64, amount: u64, fee: u64) -> Vec<A> {
+    let mut low = low.to_vec();
+    low.sort_by_key(|&(_, balance)| balance);
+    let fits = available.saturating_sub(fee) / amount.max(1);
+    low.into_iter().take(fits as usize).map(|(account, _)| account).collect()
+}
+
+/// Shares `deficit` across `accounts`, each paired with its balance. The
+/// parts always sum to `deficit`; an account whose share rounds down to
+/// nothing is left out. Weighting by balances that are all zero splits evenly.
+pub fn plan_listings<A: Clone>(
+    deficit: u64,
+    accounts: &[(A, u64)],
+    strategy: SplitStrategy,
+) -> Vec<(A, u64)> {
+    if accounts.is_empty() {
+        return vec![];
+    }
+    let weights: Vec<u128> = match strategy {
+        SplitStrategy::Single => return vec![(accounts[0].0.clone(), deficit)],
+        SplitStrategy::BalanceWeighted if accounts.iter().any(|(_, balance)| *balance > 0) => {
+            accounts.iter().map(|(_, balance)| *balance as u128).collect()
+        }
+        SplitStrategy::Even | SplitStrategy::BalanceWeighted => vec![1; accounts.len()],
+    };
+    let total: u128 = weights.iter().sum();
+    let mut parts: Vec<u64> = weights
+        .iter()
+        .map(|weight| (deficit as u128 * weight / total) as u64)
+        .collect();
+    // rounding down leaves less than one token per account; the heaviest
+    // accounts take it
+    let left = deficit - parts.iter().sum::<u64>();
+    let mut heaviest: Vec<usize> = (0..parts.len()).collect();
+    heaviest.sort_by_key(|&i| Reverse(weights[i]));
+    for &i in heaviest.iter().take(left as usize) {
+        parts[i] += 1;
+    }
+    accounts
+        .iter()
+        .zip(parts)
+        .filter(|(_, amount)| *amount > 0)
+        .map(|((account, _), amount)| (account.clone(), amount))
+        .collect()
+}
+
+/// How the floor schedule goes on past its last price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloorMode {
+    /// Start over from the first price.
+    Rotate,
+    /// Keep buying at the last price.
+    HoldLast,
+}
+
+impl FloorMode {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "rotate" => Ok(Self::Rotate),
+            "hold-last" => Ok(Self::HoldLast),
+            other => Err(anyhow!("FLOOR_MODE must be rotate or hold-last, not {:?}", other)),
+        }
+    }
+
+    /// Floor at step `index` of `prices`.
+    pub fn floor(self, prices: &[u64], index: usize) -> u64 {
+        match self {
+            Self::Rotate => prices[index % prices.len()],
+            Self::HoldLast => prices[index.min(prices.len() - 1)],
+        }
+    }
+
+    /// Step after `index` in a schedule of `len` prices.
+    pub fn next(self, index: usize, len: usize) -> usize {
+        match self {
+            Self::Rotate => index + 1,
+            Self::HoldLast => (index + 1).min(len - 1),
+        }
+    }
+}
+
+/// Daily window, in a fixed UTC offset, during which the cycles run. When
+/// `end` is before `start` the window wraps past midnight.
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+}
+
+impl ActiveWindow {
+    /// `start` and `end` are `HH:MM`, `offset` is like `+08:00`.
+    pub fn parse(start: &str, end: &str, offset: &str) -> Result<Self> {
+        Ok(Self {
+            start: NaiveTime::parse_from_str(start, "%H:%M")?,
+            end: NaiveTime::parse_from_str(end, "%H:%M")?,
+            offset: parse_offset(offset)?,
+        })
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.offset).time();
+        if self.start <= self.end {
+            self.start <= local && local < self.end
+        } else {
+            local >= self.start || local < self.end
+        }
+    }
+}
+
+fn parse_offset(s: &str) -> Result<FixedOffset> {
+    let bad = || anyhow!("bad UTC offset {:?}, expected like +08:00", s);
+    let (sign, rest) = match s.as_bytes().first() {
+        Some(b'+') => (1, &s[1..]),
+        Some(b'-') => (-1, &s[1..]),
+        _ => return Err(bad()),
+    };
+    let (h, m) = rest.split_once(':').ok_or_else(bad)?;
+    let secs = h.parse::<i32>()? * 3600 + m.parse::<i32>()? * 60;
+    FixedOffset::east_opt(sign * secs).ok_or_else(bad)
+}
+
+tokio::task_local! {
+    static CYCLE_RETRIES: RetryBudget;
+    static CYCLE_ACTIONS: ActionCap;
+}
+
+/// Retries left to one cycle, shared by every call it makes.
+#[derive(Debug)]
+struct RetryBudget {
+    limit: u32,
+    used: Cell<u32>,
+}
+
+/// Runs `cycle` with at most `limit` retries across all of its calls; the
+/// retry past the limit fails, aborting the cycle. `None` is unlimited.
+pub async fn with_retry_budget<Fut: Future>(limit: Option<u32>, cycle: Fut) -> Fut::Output {
+    match limit {
+        Some(limit) => {
+            let budget = RetryBudget {
+                limit,
+                used: Cell::new(0),
+            };
+            CYCLE_RETRIES.scope(budget, cycle).await
+        }
+        None => cycle.await,
+    }
+}
+
+/// Takes one retry from the running cycle's budget, if it has one.
+pub fn spend_retry() -> Result<()> {
+    CYCLE_RETRIES
+        .try_with(|budget| {
+            let used = budget.used.get() + 1;
+            budget.used.set(used);
+            if used > budget.limit {
+                return Err(anyhow!("retry budget of {} spent, aborting cycle", budget.limit));
+            }
+            Ok(())
+        })
+        .unwrap_or(Ok(()))
+}
+
+/// Mints, lists and buys left to one cycle.
+#[derive(Debug)]
+struct ActionCap {
+    limit: u32,
+    taken: Cell<u32>,
+    deferred: Cell<u32>,
+}
+
+/// Runs `cycle` with at most `limit` mints, lists and buys in total; the
+/// rest wait for a later cycle. `None` is unlimited.
+pub async fn with_action_cap<Fut: Future>(limit: Option<u32>, cycle: Fut) -> Fut::Output {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return cycle.await,
+    };
+    let cap = ActionCap {
+        limit,
+        taken: Cell::new(0),
+        deferred: Cell::new(0),
+    };
+    let counted = async {
+        let output = cycle.await;
+        let deferred = CYCLE_ACTIONS.with(|cap| cap.deferred.get());
+        if deferred > 0 {
+            log::info!("[tick] action cap of {} reached, deferred {} actions", limit, deferred);
+        }
+        output
+    };
+    CYCLE_ACTIONS.scope(cap, counted).await
+}
+
+/// Takes `count` actions from the running cycle's cap, if it has one; false
+/// means they are deferred.
+pub fn take_actions(count: u32) -> bool {
+    CYCLE_ACTIONS
+        .try_with(|cap| {
+            let taken = cap.taken.get() + count;
+            if taken > cap.limit {
+                cap.deferred.set(cap.deferred.get() + count);
+                return false;
+            }
+            cap.taken.set(taken);
+            true
+        })
+        .unwrap_or(true)
+}
+
+/// Polls `check` every `poll` until it reports true or `timeout` passes.
+/// Returns whether the condition was met.
+pub async fn wait_until<F, Fut>(poll: Duration, timeout: Duration, mut check: F) -> Result<bool>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if check().await? {
+            return Ok(true);
+        }
+        if Instant::now() + poll > deadline {
+            return Ok(false);
+        }
+        time::sleep(poll).await;
+    }
+}
+
+/// Runs the file operation `op` up to `attempts` times, doubling `backoff`
+/// between tries, for networked filesystems that fail transiently. NotFound
+/// and InvalidData are returned at once: retrying won't change them.
+pub fn retry_io<T>(
+    attempts: u32,
+    backoff: Duration,
+    op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    retry_io_with(attempts, backoff, |retry| log::warn!("{}", retry), op)
+}
+
+/// [`retry_io`] handing each retry's warning to `report` instead of the log,
+/// for callers that run before the logger is installed.
+pub fn retry_io_with<T>(
+    attempts: u32,
+    backoff: Duration,
+    mut report: impl FnMut(String),
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e)
+                if attempt < attempts
+                    && !matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::InvalidData) =>
+            {
+                report(format!("[io] attempt {} of {} failed, retrying: {}", attempt, attempts, e));
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Thins out a cycle's scans while the book stays empty: from the `after`th
+/// empty scan in a row each further one doubles the ticks skipped, up to
+/// `max_skip`, and any listing resets it to scanning every tick.
+#[derive(Debug)]
+pub struct EmptyBackoff {
+    after: Option<u32>,
+    max_skip: u32,
+    empty_scans: u32,
+    skipped: u32,
+}
+
+impl EmptyBackoff {
+    pub fn new(after: Option<u32>, max_skip: u32) -> Self {
+        Self {
+            after,
+            max_skip,
+            empty_scans: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Ticks skipped between scans for the current streak.
+    pub fn skip(&self) -> u32 {
+        let after = match self.after {
+            Some(after) if self.empty_scans >= after => after,
+            _ => return 0,
+        };
+        let doublings = (self.empty_scans - after + 1).min(31);
+        ((1u64 << doublings) - 1).min(self.max_skip as u64) as u32
+    }
+
+    /// Whether this tick scans; skipped ticks are counted off here.
+    pub fn should_scan(&mut self) -> bool {
+        if self.skipped < self.skip() {
+            self.skipped += 1;
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
+
+    /// Records a scan; returns the new skip when it changed.
+    pub fn record(&mut self, empty: bool) -> Option<u32> {
+        let before = self.skip();
+        self.empty_scans = if empty { self.empty_scans.saturating_add(1) } else { 0 };
+        let after = self.skip();
+        (after != before).then_some(after)
+    }
+}
+
+/// Holds a buy back until its listing has been under the floor for `scans`
+/// scans in a row, so a fat-fingered ask cancelled a moment later is never
+/// hit. Listings are tracked by id.
+#[derive(Debug, Default)]
+pub struct DipDampener {
+    scans: u32,
+    streaks: HashMap<i32, u32>,
+}
+
+impl DipDampener {
+    pub fn new(scans: u32) -> Self {
+        Self {
+            scans,
+            streaks: HashMap::new(),
+        }
+    }
+
+    /// Counts one more scan for each listing of `cheap`, forgetting those
+    /// that dropped out, and returns the ones cheap for long enough.
+    pub fn confirm(&mut self, cheap: impl IntoIterator<Item = i32>) -> HashSet<i32> {
+        let streaks = cheap
+            .into_iter()
+            .map(|id| (id, self.streaks.get(&id).copied().unwrap_or(0) + 1))
+            .collect();
+        self.streaks = streaks;
+        self.streaks
+            .iter()
+            .filter(|(_, &streak)| streak >= self.scans)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+}
+
+/// Floors observed over the last `window` seconds.
+#[derive(Debug)]
+pub struct FloorWindow {
+    window: i64,
+    samples: VecDeque<(i64, u64)>,
+}
+
+impl FloorWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.as_secs() as i64,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records `floor` at `now` and drops samples older than the window.
+    pub fn observe(&mut self, now: i64, floor: u64) {
+        self.samples.push_back((now, floor));
+        while let Some(&(at, _)) = self.samples.front() {
+            if now - at <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Percentage the latest floor sits below the window's highest.
+    pub fn drop_pct(&self) -> f64 {
+        let latest = match self.samples.back() {
+            Some(&(_, floor)) => floor,
+            None => return 0.0,
+        };
+        let high = self.samples.iter().map(|&(_, floor)| floor).max().unwrap_or(0);
+        if high == 0 {
+            return 0.0;
+        }
+        (high - latest) as f64 * 100.0 / high as f64
+    }
+
+    pub fn high(&self) -> u64 {
+        self.samples.iter().map(|&(_, floor)| floor).max().unwrap_or(0)
+    }
+}
+
+/// Spreads buys over time: each qualifying listing is bought with
+/// `probability` per tick, after a random delay of up to `max_delay`.
+///
+/// Lower probability and longer delays make the bot's buying look less
+/// mechanical, at the cost of losing cheap listings to faster buyers. With
+/// `probability` 1.0 and no delay every listing is bought immediately and the
+/// rng is never consulted.
+#[derive(Debug)]
+pub struct BuyJitter {
+    probability: f64,
+    max_delay: Duration,
+    rng: StdRng,
+}
+
+impl BuyJitter {
+    pub fn new(probability: f64, max_delay: Duration, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            probability: probability.clamp(0.0, 1.0),
+            max_delay,
+            rng,
+        }
+    }
+
+    /// `None` skips the listing this tick, otherwise the delay before buying.
+    pub fn gate(&mut self) -> Option<Duration> {
+        if self.probability < 1.0 && !self.rng.gen_bool(self.probability) {
+            return None;
+        }
+        if self.max_delay.is_zero() {
+            return Some(Duration::ZERO);
+        }
+        let millis = self.rng.gen_range(0..=self.max_delay.as_millis() as u64);
+        Some(Duration::from_millis(millis))
+    }
+}
+
+/// A token bucket per account address: each may submit `burst` times at
+/// once, then `per_minute` times a minute. Unlimited without `per_minute`.
+#[derive(Debug, Default)]
+pub struct AccountRateLimit {
+    per_minute: Option<u32>,
+    burst: u32,
+    /// Permits left and when they were counted, per address.
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl AccountRateLimit {
+    pub fn new(per_minute: Option<u32>, burst: u32) -> Self {
+        Self {
+            per_minute,
+            burst: burst.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Permits of `address` at `now`, or `None` when unlimited.
+    fn permits_at(&self, address: &str, now: Instant) -> Option<f64> {
+        let per_minute = self.per_minute? as f64;
+        let buckets = self.buckets.lock().unwrap();
+        Some(match buckets.get(address) {
+            Some(&(permits, at)) => {
+                let refilled = now.saturating_duration_since(at).as_secs_f64() * per_minute / 60.0;
+                (permits + refilled).min(self.burst as f64)
+            }
+            None => self.burst as f64,
+        })
+    }
+
+    /// How long until `address` has a permit at `now`; zero when it has one.
+    pub fn wait_at(&self, address: &str, now: Instant) -> Duration {
+        match (self.permits_at(address, now), self.per_minute) {
+            (Some(permits), Some(per_minute)) if permits < 1.0 => {
+                Duration::from_secs_f64((1.0 - permits) * 60.0 / per_minute as f64)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn available(&self, address: &str) -> bool {
+        self.wait_at(address, Instant::now()).is_zero()
+    }
+
+    /// Takes a permit of `address` at `now` if it has one.
+    pub fn try_take_at(&self, address: &str, now: Instant) -> bool {
+        let permits = match self.permits_at(address, now) {
+            Some(permits) => permits,
+            None => return true,
+        };
+        if permits < 1.0 {
+            return false;
+        }
+        self.buckets.lock().unwrap().insert(address.to_string(), (permits - 1.0, now));
+        true
+    }
+
+    /// Waits until `address` has a permit, then takes it.
+    pub async fn take(&self, address: &str) {
+        loop {
+            let now = Instant::now();
+            if self.try_take_at(address, now) {
+                return;
+            }
+            time::sleep(self.wait_at(address, now)).await;
+        }
+    }
+}
+
+/// One lock per account address, held while a transaction spending from the
+/// account is built and submitted, so concurrent cycles never pick the same
+/// UTXOs.
+#[derive(Debug, Default)]
+pub struct AccountLocks(Mutex<HashMap<String, Arc<AsyncMutex<()>>>>);
+
+impl AccountLocks {
+    pub async fn lock(&self, address: &str) -> OwnedMutexGuard<()> {
+        let lock = self.0.lock().unwrap().entry(address.to_string()).or_default().clone();
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::ListItem;
+    use crate::utils::{
+        clamp_list_price, compute_list_price, fill_quantity, gen_accounts, mask_addresses,
+        plan_listings, plan_topups, reconcile_balance, retry_io, retry_io_with, scan_pages,
+        select_inputs, select_utxos, shuffle_accounts, spendable, supply_action, take_actions,
+        truncate_body, wait_until, with_action_cap, AccountRateLimit, ActiveWindow, BuyJitter,
+        DipDampener, EmptyBackoff, FloorMode, FloorWindow, InputSelection, ScanCheckpoint,
+        SplitStrategy, SupplyAction,
+    };
+    use anyhow::Result;
+    use chrono::{TimeZone, Utc};
+    use std::io;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_fill_quantity_budget_covers_part() {
+        // budget buys 40 of the 100 listed tokens at 5 each
+        assert_eq!(fill_quantity(100, 5, Some(200), None, true), Some(40));
+        assert_eq!(fill_quantity(100, 5, Some(200), None, false), None);
+    }
+
+    #[test]
+    fn test_fill_quantity_target_and_budget() {
+        assert_eq!(fill_quantity(100, 5, Some(1000), Some(30), true), Some(30));
+        assert_eq!(fill_quantity(100, 5, Some(1000), None, false), Some(100));
+        assert_eq!(fill_quantity(100, 5, Some(4), None, true), None);
+    }
+
+    #[test]
+    fn test_supply_action_band() {
+        assert_eq!(supply_action(40, Some(50), Some(100)), SupplyAction::List(10));
+        assert_eq!(supply_action(50, Some(50), Some(100)), SupplyAction::Hold);
+        assert_eq!(supply_action(100, Some(50), Some(100)), SupplyAction::Hold);
+        assert_eq!(supply_action(130, Some(50), Some(100)), SupplyAction::Cancel(30));
+    }
+
+    #[test]
+    fn test_supply_action_single_threshold() {
+        assert_eq!(supply_action(40, Some(50), None), SupplyAction::List(10));
+        assert_eq!(supply_action(1000, Some(50), None), SupplyAction::Hold);
+        assert_eq!(supply_action(0, None, Some(100)), SupplyAction::Hold);
+    }
+
+    #[test]
+    fn test_buy_jitter_seeded_gating() {
+        let gates = |seed| {
+            let mut jitter = BuyJitter::new(0.3, Duration::from_millis(100), Some(seed));
+            (0..1000).map(|_| jitter.gate()).collect::<Vec<_>>()
+        };
+
+        let first = gates(7);
+        assert_eq!(first, gates(7));
+        let bought = first.iter().filter(|gate| gate.is_some()).count();
+        assert!((250..350).contains(&bought), "bought {}", bought);
+        assert!(first
+            .iter()
+            .flatten()
+            .all(|delay| *delay <= Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_buy_jitter_deterministic_mode() {
+        let mut jitter = BuyJitter::new(1.0, Duration::ZERO, None);
+        assert!((0..100).all(|_| jitter.gate() == Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn test_select_utxos_skips_dust() {
+        let utxos = vec![(1, 5), (2, 300), (3, 8), (4, 500), (5, 200)];
+        let selected: Vec<i32> = select_utxos(utxos.clone(), 600, 10)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(selected, vec![4, 2]);
+
+        // dust alone can't cover the target, so everything eligible is returned
+        let selected = select_utxos(utxos, 2000, 10);
+        let total: u64 = selected.iter().map(|(_, v)| v).sum();
+        assert_eq!(selected.len(), 3);
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn test_input_cap_suggests_consolidation() {
+        let utxos: Vec<(i32, u64)> = (0..50).map(|id| (id, 10)).collect();
+
+        let inputs = match select_inputs(utxos.clone(), 300, 0, Some(20)) {
+            InputSelection::Consolidate(inputs) => inputs,
+            other => panic!("expected a consolidation, got {:?}", other),
+        };
+        assert_eq!(inputs.len(), 20);
+
+        let selected = select_inputs(utxos.clone(), 150, 0, Some(20));
+        assert!(matches!(selected, InputSelection::Spend(ref inputs) if inputs.len() == 15));
+
+        // even all 50 can't cover it, so there is nothing to merge for
+        let selected = select_inputs(utxos, 600, 0, Some(20));
+        assert!(matches!(selected, InputSelection::Spend(ref inputs) if inputs.len() == 20));
+    }
+
+    #[test]
+    fn test_low_account_is_topped_up_from_earnings_pool() {
+        let low = [("a", 300), ("b", 10), ("c", 200)];
+
+        // the pool covers two top-ups and the fee: the two lowest get them
+        assert_eq!(plan_topups(&low, 2_590, 1_000, 10), vec!["b", "c"]);
+        assert_eq!(plan_topups(&low, 5_000, 1_000, 10), vec!["b", "c", "a"]);
+        assert!(plan_topups(&low, 1_009, 1_000, 10).is_empty());
+    }
+
+    #[test]
+    fn test_plan_listings_sums_to_deficit() {
+        let accounts = [("a", 100), ("b", 300), ("c", 0)];
+        let plan = |deficit, strategy| plan_listings(deficit, &accounts, strategy);
+
+        assert_eq!(plan(1000, SplitStrategy::Single), vec![("a", 1000)]);
+        assert_eq!(plan(1000, SplitStrategy::Even), vec![("a", 334), ("b", 333), ("c", 333)]);
+        assert_eq!(plan(1001, SplitStrategy::BalanceWeighted), vec![("a", 250), ("b", 751)]);
+        // too little to go round: the empty shares are dropped
+        assert_eq!(plan(2, SplitStrategy::Even), vec![("a", 1), ("b", 1)]);
+        for strategy in [
+            SplitStrategy::Single,
+            SplitStrategy::Even,
+            SplitStrategy::BalanceWeighted,
+        ] {
+            for deficit in [1, 7, 999, 1000] {
+                let total: u64 = plan(deficit, strategy).iter().map(|(_, amount)| amount).sum();
+                assert_eq!(total, deficit);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dip_must_last_before_buying() {
+        let mut dampener = DipDampener::new(3);
+        // listing 1 is cheap for a single scan, then gone
+        assert!(dampener.confirm([1, 2]).is_empty());
+        assert!(dampener.confirm([2]).is_empty());
+        assert_eq!(dampener.confirm([1, 2]).into_iter().collect::<Vec<i32>>(), vec![2]);
+        assert!(dampener.confirm([1]).is_empty());
+        assert_eq!(dampener.confirm([1]).into_iter().collect::<Vec<i32>>(), vec![1]);
+
+        assert!(DipDampener::new(1).confirm([7]).contains(&7));
+    }
+
+    #[test]
+    fn test_floor_window_drop() {
+        let mut window = FloorWindow::new(Duration::from_secs(60));
+        window.observe(0, 1000);
+        window.observe(30, 900);
+        window.observe(50, 700);
+        assert_eq!(window.drop_pct(), 30.0);
+
+        // the 1000 sample ages out
+        window.observe(100, 700);
+        assert_eq!(window.high(), 700);
+        assert_eq!(window.drop_pct(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_balance_appears() -> Result<()> {
+        let balance = AtomicU64::new(0);
+        let polls = AtomicU64::new(0);
+        let (balance, polls) = (&balance, &polls);
+        let poll = Duration::from_millis(10);
+
+        // the minted balance shows up on the third poll
+        let visible = wait_until(poll, Duration::from_secs(1), move || async move {
+            if polls.fetch_add(1, Ordering::SeqCst) == 2 {
+                balance.store(100, Ordering::SeqCst);
+            }
+            Ok(balance.load(Ordering::SeqCst) >= 100)
+        })
+        .await?;
+        assert!(visible);
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        let listed = if visible { balance.load(Ordering::SeqCst) } else { 0 };
+        assert_eq!(listed, 100);
+
+        let never = wait_until(poll, Duration::from_millis(30), || async { Ok(false) }).await?;
+        assert!(!never);
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_window() -> Result<()> {
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 1, 1, h, m, 0).unwrap();
+
+        // 09:00-17:00 at +08:00 is 01:00-09:00 UTC
+        let day = ActiveWindow::parse("09:00", "17:00", "+08:00")?;
+        assert!(day.contains(at(1, 0)));
+        assert!(day.contains(at(8, 59)));
+        assert!(!day.contains(at(9, 0)));
+        assert!(!day.contains(at(0, 30)));
+
+        let night = ActiveWindow::parse("22:00", "06:00", "+00:00")?;
+        assert!(night.contains(at(23, 0)));
+        assert!(night.contains(at(2, 0)));
+        assert!(!night.contains(at(12, 0)));
+
+        assert!(ActiveWindow::parse("09:00", "17:00", "8").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_list_price() {
+        let book = vec![
+            ListItem::sample(1, 10, 300),
+            ListItem::sample(2, 10, 100),
+            ListItem::sample(3, 10, 200),
+        ];
+        assert_eq!(compute_list_price(&book, 0), 200);
+        // 2.5% over the median
+        assert_eq!(compute_list_price(&book, 250), 205);
+        assert_eq!(compute_list_price(&book[..2], 0), 200);
+        assert_eq!(compute_list_price(&[], 250), 0);
+    }
+
+    #[test]
+    fn test_scan_pages_cap() {
+        assert_eq!(scan_pages(4, None, 0), vec![2, 3, 4]);
+        assert_eq!(scan_pages(4, Some(10), 3), vec![2, 3, 4]);
+        // 3 pages per tick out of 6: page 1 plus a rotating pair of the rest
+        assert_eq!(scan_pages(6, Some(3), 0), vec![2, 3]);
+        assert_eq!(scan_pages(6, Some(3), 1), vec![4, 5]);
+        assert_eq!(scan_pages(6, Some(3), 2), vec![6, 2]);
+        assert_eq!(scan_pages(6, Some(1), 0), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_clamp_list_price() {
+        let book = vec![ListItem::sample(1, 10, 300), ListItem::sample(2, 10, 250)];
+        assert_eq!(clamp_list_price(400, &book, 1, None), 249);
+        assert_eq!(clamp_list_price(200, &book, 1, None), 200);
+        assert_eq!(clamp_list_price(400, &book, 1, Some(260)), 260);
+        assert_eq!(clamp_list_price(400, &[], 1, Some(100)), 400);
+    }
+
+    #[test]
+    fn test_spends_keep_fee_reserve() {
+        let reserve = 30;
+        let mut balance = 100;
+        for cost in [40, 40, 20, 10, 5] {
+            if cost <= spendable(balance, reserve) {
+                balance -= cost;
+            }
+            assert!(balance >= reserve);
+        }
+        assert_eq!(balance, 40);
+        assert_eq!(spendable(10, reserve), 0);
+    }
+
+    #[test]
+    fn test_reconcile_balance_takes_smaller() {
+        // the exchange hasn't indexed a recent spend yet
+        assert_eq!(reconcile_balance(700, 1000, None), Some(700));
+        assert_eq!(reconcile_balance(1000, 700, Some(300)), Some(700));
+        assert_eq!(reconcile_balance(1000, 699, Some(300)), None);
+    }
+
+    #[tokio::test]
+    async fn test_action_cap_is_combined() -> Result<()> {
+        let taken = with_action_cap(Some(3), async {
+            // a mint and its listing, then buys
+            let mut taken = vec![take_actions(2)];
+            for _ in 0..3 {
+                taken.push(take_actions(1));
+            }
+            taken
+        })
+        .await;
+        assert_eq!(taken, vec![true, true, false, false]);
+        // outside a capped cycle nothing is deferred
+        assert!(take_actions(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_shuffle_is_deterministic() -> Result<()> {
+        let accounts = gen_accounts(8)?;
+        let order = |seed| {
+            let mut shuffled = accounts.clone();
+            shuffle_accounts(&mut shuffled, seed);
+            shuffled.into_iter().map(|a| a.address).collect::<Vec<_>>()
+        };
+        assert_eq!(order(7), order(7));
+        assert_ne!(order(7), order(8));
+        let mut sorted = order(7);
+        sorted.sort();
+        let mut original: Vec<String> = accounts.iter().map(|a| a.address.clone()).collect();
+        original.sort();
+        assert_eq!(sorted, original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_backoff_grows_and_resets() {
+        let mut backoff = EmptyBackoff::new(Some(2), 5);
+        let mut scans = vec![];
+        for tick in 0..20 {
+            if backoff.should_scan() {
+                scans.push(tick);
+                // listings come back on tick 15
+                backoff.record(tick < 15);
+            }
+        }
+        // every tick for two scans, then skipping 1 and 3, then capped at 5
+        assert_eq!(scans, vec![0, 1, 3, 7, 13, 19]);
+        assert_eq!(backoff.skip(), 0);
+
+        let mut off = EmptyBackoff::new(None, 5);
+        assert_eq!(off.record(true), None);
+        assert!(off.should_scan());
+    }
+
+    #[test]
+    fn test_logged_bodies_are_masked_and_cut() -> Result<()> {
+        let address = gen_accounts(1)?[0].address.clone();
+        let body = format!(r#"{{"address":"{}","amount":"10"}}"#, address);
+        let masked = mask_addresses(&body);
+        assert_eq!(
+            masked,
+            format!(r#"{{"address":"fra1..{}","amount":"10"}}"#, &address[address.len() - 6..])
+        );
+        assert_eq!(mask_addresses("fra1 is short"), "fra1 is short");
+
+        assert_eq!(truncate_body("héllo", 10), "héllo");
+        // never splits the two-byte é
+        assert_eq!(truncate_body("héllo", 2), "h... (6 bytes)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_burst_on_one_account_is_paced() {
+        // a permit every 10s, two at once
+        let limit = AccountRateLimit::new(Some(6), 2);
+        let start = Instant::now();
+        assert!(limit.try_take_at("a", start));
+        assert!(limit.try_take_at("a", start));
+        assert!(!limit.try_take_at("a", start));
+        assert_eq!(limit.wait_at("a", start), Duration::from_secs(10));
+
+        // other accounts have buckets of their own
+        assert!(limit.try_take_at("b", start));
+        assert!(limit.try_take_at("b", start));
+
+        assert!(!limit.try_take_at("a", start + Duration::from_secs(9)));
+        assert!(limit.try_take_at("a", start + Duration::from_secs(10)));
+        assert!(!limit.try_take_at("a", start + Duration::from_secs(10)));
+
+        let unlimited = AccountRateLimit::default();
+        assert!((0..100).all(|_| unlimited.try_take_at("a", start)));
+    }
+
+    #[test]
+    fn test_hold_last_stops_at_the_last_floor() {
+        let prices = [30, 20, 10];
+        let floors = |mode: FloorMode| {
+            let mut index = 0;
+            let mut floors = vec![];
+            for _ in 0..7 {
+                floors.push(mode.floor(&prices, index));
+                index = mode.next(index, prices.len());
+            }
+            (floors, index)
+        };
+
+        assert_eq!(floors(FloorMode::Rotate), (vec![30, 20, 10, 30, 20, 10, 30], 7));
+        assert_eq!(floors(FloorMode::HoldLast), (vec![30, 20, 10, 10, 10, 10, 10], 2));
+        // an index saved by a rotating run is held too
+        assert_eq!(FloorMode::HoldLast.floor(&prices, 40), 10);
+    }
+
+    #[test]
+    fn test_transient_io_error_succeeds_on_retry() {
+        let mut tries = 0;
+        let read = retry_io(3, Duration::ZERO, || {
+            tries += 1;
+            match tries {
+                1 => Err(io::Error::new(io::ErrorKind::TimedOut, "stale NFS handle")),
+                _ => Ok("fra1"),
+            }
+        });
+        assert_eq!(read.unwrap(), "fra1");
+        assert_eq!(tries, 2);
+
+        // a missing file is generated, not waited for
+        let mut tries = 0;
+        let missing: io::Result<()> = retry_io(3, Duration::ZERO, || {
+            tries += 1;
+            Err(io::ErrorKind::NotFound.into())
         });
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(tries, 1);
     }
 
-    Ok(accounts)
+    #[test]
+    fn test_io_retries_are_handed_to_the_reporter() {
+        let mut retries = vec![];
+        let mut tries = 0;
+        let read = retry_io_with(3, Duration::ZERO, |retry| retries.push(retry), || {
+            tries += 1;
+            match tries {
+                3 => Ok(()),
+                _ => Err(io::Error::new(io::ErrorKind::TimedOut, "stale NFS handle")),
+            }
+        });
+        assert!(read.is_ok());
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0], "[io] attempt 1 of 3 failed, retrying: stale NFS handle");
+    }
 }
@@ -0,0 +1,114 @@
+use crate::types::{FraAccount, Rpc};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use zei::xfr::sig::XfrPublicKey;
+
+/// The node side of moving FRA between our own accounts, as top-ups do.
+/// Transactions pass through it encoded, so `submit_once` can key them by
+/// hash before they are sent.
+#[async_trait]
+pub trait Wallet: Debug + Send + Sync {
+    async fn balance(&self, account: &FraAccount) -> Result<u64>;
+
+    /// Builds the transfer paying every `(to, amount)` from `account`,
+    /// encoded for `submit`.
+    async fn build_transfer(
+        &self,
+        account: &FraAccount,
+        outputs: &[(XfrPublicKey, u64)],
+    ) -> Result<Vec<u8>>;
+
+    /// Broadcasts a transaction from `build_transfer` and returns its hash.
+    async fn submit(&self, tx: &[u8]) -> Result<String>;
+
+    async fn is_committed(&self, txid: &str) -> Result<bool>;
+}
+
+#[async_trait]
+impl Wallet for Rpc {
+    async fn balance(&self, account: &FraAccount) -> Result<u64> {
+        self.get_balance(account).await
+    }
+
+    async fn build_transfer(
+        &self,
+        account: &FraAccount,
+        outputs: &[(XfrPublicKey, u64)],
+    ) -> Result<Vec<u8>> {
+        let tx = Rpc::build_transfer(self, account, outputs).await?;
+        Ok(serde_json::to_vec(&tx)?)
+    }
+
+    async fn submit(&self, tx: &[u8]) -> Result<String> {
+        self.submit_encoded(tx).await
+    }
+
+    async fn is_committed(&self, txid: &str) -> Result<bool> {
+        self.is_tx_committed(txid).await
+    }
+}
+
+/// An in-process wallet for tests.
+#[cfg(test)]
+pub mod mock {
+    use super::Wallet;
+    use crate::types::{FraAccount, Rpc};
+    use anyhow::{Error, Result};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use zei::xfr::sig::XfrPublicKey;
+
+    #[derive(Debug, Default)]
+    pub struct MockWallet {
+        /// Balance per address; unknown addresses hold nothing.
+        pub balances: Mutex<HashMap<String, u64>>,
+        /// Hashes of the transactions that reached the chain, in order.
+        pub landed: Mutex<Vec<String>>,
+        /// How many of the next submits land but time out on the way back.
+        pub timeouts: AtomicUsize,
+    }
+
+    impl MockWallet {
+        pub fn with_balances(balances: &[(&str, u64)]) -> Self {
+            let balances = balances.iter().map(|(a, b)| (a.to_string(), *b)).collect();
+            Self {
+                balances: Mutex::new(balances),
+                ..Self::default()
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Wallet for MockWallet {
+        async fn balance(&self, account: &FraAccount) -> Result<u64> {
+            Ok(self.balances.lock().unwrap().get(&account.address).copied().unwrap_or(0))
+        }
+
+        async fn build_transfer(
+            &self,
+            account: &FraAccount,
+            outputs: &[(XfrPublicKey, u64)],
+        ) -> Result<Vec<u8>> {
+            let amounts: Vec<u64> = outputs.iter().map(|(_, amount)| *amount).collect();
+            Ok(format!("{}:{:?}", account.address, amounts).into_bytes())
+        }
+
+        async fn submit(&self, tx: &[u8]) -> Result<String> {
+            let txid = Rpc::encoded_tx_hash(tx);
+            self.landed.lock().unwrap().push(txid.clone());
+            let timeouts = self.timeouts.load(Ordering::SeqCst);
+            if timeouts > 0 {
+                self.timeouts.store(timeouts - 1, Ordering::SeqCst);
+                return Err(Error::msg("broadcast timed out"));
+            }
+            Ok(txid)
+        }
+
+        async fn is_committed(&self, txid: &str) -> Result<bool> {
+            Ok(self.landed.lock().unwrap().iter().any(|landed| landed == txid))
+        }
+    }
+}